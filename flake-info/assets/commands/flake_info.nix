@@ -1,11 +1,13 @@
 {
   flake ? null,
   input-flake ? "input-flake",
+  # passed in by long-lived nix repl sessions to share one instance between flakes
+  pkgs ? import <nixpkgs> { },
 }:
 let
   resolved = builtins.getFlake input-flake;
 
-  nixpkgs = (import <nixpkgs> { });
+  nixpkgs = pkgs;
   lib = nixpkgs.lib;
 
   # filter = lib.filterAttrs (key: _ : key == "apps" || key == "packages");
@@ -1,13 +1,33 @@
 use anyhow::{Context, Result};
-use flake_info::commands::NixCheckError;
+use flake_info::cache::ExportCache;
+use flake_info::commands::{
+    EvalNixpkgs, Limits, NixCheckError, NixError, NixRunner, RecordingRunner, ReplRunner,
+    ReplayRunner, SystemRunner,
+};
 use flake_info::data::import::Kind;
-use flake_info::data::{self, Export, Source};
-use flake_info::elastic::{self, ElasticsearchError, ExistsStrategy};
+use flake_info::data::{self, Export, Ident, Provenance, Source, SourceRevision};
+use flake_info::elastic::{
+    self, Backend, BulkFileSink, ElasticSink, ElasticsearchError, ExistsStrategy,
+};
+#[cfg(feature = "local")]
+use flake_info::local::LocalIndexSink;
+#[cfg(feature = "opensearch")]
+use flake_info::opensearch::OpenSearch;
+use flake_info::sink::{self, JsonSink, NdjsonSink};
+use flake_info::sqlite::SqliteSink;
 use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 use sha2::Digest;
 use std::io;
 use std::path::PathBuf;
-use structopt::{StructOpt, clap::ArgGroup};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use structopt::{
+    StructOpt,
+    clap::{ArgGroup, arg_enum},
+};
 use thiserror::Error;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
@@ -32,15 +52,171 @@ struct Args {
     )]
     kind: data::import::Kind,
 
+    #[structopt(flatten)]
+    output: OutputOpts,
+
     #[structopt(flatten)]
     elastic: ElasticOpts,
 
+    #[structopt(
+        long,
+        env = "FI_NIX_TIMEOUT",
+        help = "Kill nix commands running longer than the given number of seconds"
+    )]
+    nix_timeout: Option<u64>,
+
+    #[structopt(
+        long,
+        env = "FI_NIX_MEMORY",
+        help = "Limit the address space of nix commands to the given number of MiB"
+    )]
+    nix_memory: Option<u64>,
+
+    #[structopt(
+        long,
+        env = "FI_NIX_REPL",
+        conflicts_with = "replay",
+        help = "Evaluate flakes in long-lived nix repl sessions sharing one nixpkgs instance"
+    )]
+    nix_repl: bool,
+
+    #[structopt(
+        long,
+        conflicts_with = "replay",
+        help = "Record the outputs of all nix commands and HTTP requests of the import into the given directory"
+    )]
+    record: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["record", "nix_repl"],
+        help = "Replay the outputs recorded with --record from the given directory instead of running nix or accessing the network"
+    )]
+    replay: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        env = "FI_EVAL_NIXPKGS",
+        help = "Nixpkgs to evaluate flakes with: `unstable` (default), `input` for the nixpkgs input \
+                of each flake, a local path, a flake reference or `rev:<revision>:<narHash>`"
+    )]
+    eval_nixpkgs: Option<EvalNixpkgs>,
+
     #[structopt(help = "Extra arguments that are passed to nix as it")]
     extra: Vec<String>,
 }
 
 #[derive(StructOpt, Debug)]
 enum Command {
+    #[structopt(flatten)]
+    Import(ImportCommand),
+
+    #[cfg(feature = "local")]
+    #[structopt(about = "Search a local index created with --local-index")]
+    Search {
+        #[structopt(help = "Search terms, an attribute name or an option name")]
+        query: String,
+
+        #[structopt(
+            long,
+            env = "FI_LOCAL_INDEX",
+            help = "Directory of the local index to search"
+        )]
+        index: PathBuf,
+
+        #[structopt(long, default_value = "20", help = "Maximum number of results")]
+        limit: usize,
+
+        #[structopt(long = "json", help = "Print matching documents as JSON")]
+        json: bool,
+    },
+
+    #[structopt(about = "Push a dump written with --json or --ndjson to Elasticsearch")]
+    Push {
+        #[structopt(help = "Dump to push")]
+        dump: PathBuf,
+    },
+
+    #[structopt(about = "Compare two JSON exports")]
+    Diff {
+        #[structopt(help = "Exports to compare against (written with --json)")]
+        old: PathBuf,
+
+        #[structopt(help = "Exports to compare (written with --json)")]
+        new: PathBuf,
+
+        #[structopt(long = "json", help = "Print the differences as JSON")]
+        json: bool,
+    },
+
+    #[structopt(about = "Inspect and clean up flake-info indices in Elasticsearch")]
+    Indices(IndicesCommand),
+
+    #[structopt(about = "Check the index mapping and export schema against a recorded fingerprint")]
+    Schema(SchemaCommand),
+}
+
+#[derive(StructOpt, Debug)]
+enum SchemaCommand {
+    #[structopt(
+        about = "Fail if the schema changed since it was recorded but the schema version did not"
+    )]
+    Check {
+        #[structopt(
+            long = "schema-version",
+            env = "FI_ES_VERSION",
+            help = "Declared schema version, i.e. the `import` version in version.nix"
+        )]
+        version: usize,
+
+        #[structopt(help = "JSON file recording a schema version and its fingerprint")]
+        record: PathBuf,
+
+        #[structopt(long, help = "Record the current fingerprint for the declared version")]
+        update: bool,
+    },
+}
+
+/// Fingerprint of the schema written for a schema version, see `flake-info schema check`
+#[derive(Debug, Serialize, Deserialize)]
+struct SchemaRecord {
+    version: usize,
+    fingerprint: String,
+}
+
+#[derive(StructOpt, Debug)]
+enum IndicesCommand {
+    #[structopt(about = "List indices with their document count and aliases")]
+    List {
+        #[structopt(default_value = "*", help = "Index pattern to list")]
+        pattern: String,
+    },
+
+    #[structopt(about = "Delete old indices, keeping the newest of each source")]
+    Prune {
+        #[structopt(
+            long,
+            default_value = "2",
+            help = "Number of indices to keep per kind, schema version and source"
+        )]
+        keep: usize,
+
+        #[structopt(long, help = "Only print the indices that would be deleted")]
+        dry_run: bool,
+
+        #[structopt(default_value = "*", help = "Index pattern to prune")]
+        pattern: String,
+    },
+
+    #[structopt(about = "Show details about a single index")]
+    Describe {
+        #[structopt(help = "Name of the index")]
+        index: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum ImportCommand {
     #[structopt(about = "Import a flake")]
     Flake {
         #[structopt(help = "Flake identifier passed to nix to gather information about")]
@@ -107,39 +283,176 @@ enum Command {
 
         #[structopt(
             long,
-            help = "Run nix garbage collection between every group member evaluation"
+            help = "Run nix garbage collection between every group member evaluation, or once after all members with --jobs"
         )]
         with_gc: bool,
+
+        #[structopt(
+            long,
+            short,
+            default_value = "1",
+            help = "Number of group members to evaluate concurrently"
+        )]
+        jobs: usize,
+
+        #[structopt(
+            long,
+            help = "Re-evaluate every flake instead of reusing exports cached for its locked revision"
+        )]
+        no_cache: bool,
+
+        #[structopt(
+            long,
+            env = "FI_CACHE_DIR",
+            help = "Where to cache flake exports. Defaults to $XDG_CACHE_HOME/flake-info"
+        )]
+        cache_dir: Option<PathBuf>,
     },
 }
 
+/// Where exports are written to, unless they are pushed to a search engine.
+///
+/// All outputs, including `--push`, are in the `output` group, of which only
+/// one can be selected.
 #[derive(StructOpt, Debug)]
-struct ElasticOpts {
-    #[structopt(long = "json", help = "Print ElasticSeach Compatible JSON output")]
+struct OutputOpts {
+    #[structopt(
+        long = "json",
+        group = "output",
+        help = "Print a JSON dump of the exports, readable with `Export::from_reader`"
+    )]
     json: bool,
 
+    #[structopt(
+        long = "json-array",
+        group = "output",
+        help = "Print the exports as a bare JSON array, the unversioned format --json printed before dumps had a schema version"
+    )]
+    json_array: bool,
+
+    #[structopt(
+        long = "ndjson",
+        group = "output",
+        help = "Print exports as newline delimited JSON, one export per line. Nixpkgs imports are streamed as they are converted, flake and group imports are printed once all members were evaluated, since the header line identifies the import by the revisions of all members"
+    )]
+    ndjson: bool,
+
+    #[structopt(
+        long,
+        env = "FI_ES_BULK_FILE",
+        group = "output",
+        help = "Write exports to a file in the Elasticsearch bulk format, for use with `_bulk` requests"
+    )]
+    bulk_file: Option<PathBuf>,
+
+    #[cfg(feature = "local")]
+    #[structopt(
+        long,
+        env = "FI_LOCAL_INDEX",
+        group = "output",
+        help = "Write to a local search index in the given directory, replacing its contents"
+    )]
+    local_index: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        env = "FI_SQLITE",
+        group = "output",
+        help = "Write to a SQLite database at the given path, replacing an existing file"
+    )]
+    sqlite: Option<PathBuf>,
+
+    #[cfg(feature = "meilisearch")]
+    #[structopt(
+        long,
+        env = "FI_MEILI_URL",
+        group = "output",
+        help = "Write to the Meilisearch server at the given url, replacing the contents of the index"
+    )]
+    meilisearch: Option<String>,
+
+    #[cfg(feature = "meilisearch")]
+    #[structopt(
+        long,
+        env = "FI_MEILI_KEY",
+        hide_env_values = true,
+        help = "Meilisearch API key",
+        requires("meilisearch")
+    )]
+    meilisearch_key: Option<String>,
+
+    #[cfg(feature = "meilisearch")]
+    #[structopt(
+        long,
+        env = "FI_MEILI_INDEX",
+        help = "Meilisearch index to write to. Defaults to <kind>-<name> of the imported channel, flake or group",
+        requires("meilisearch")
+    )]
+    meilisearch_index: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+struct ElasticOpts {
     #[structopt(
         long = "push",
+        group = "output",
         help = "Push to Elasticsearch (Configure using FI_ES_* environment variables)",
         requires("elastic-schema-version")
     )]
     enable: bool,
 
-    // #[structopt(
-    //     long,
-    //     short = "u",
-    //     env = "FI_ES_USER",
-    //     help = "Elasticsearch username (unimplemented)"
-    // )]
-    // elastic_user: Option<String>,
-
-    // #[structopt(
-    //     long,
-    //     short = "p",
-    //     env = "FI_ES_PASSWORD",
-    //     help = "Elasticsearch password (unimplemented)"
-    // )]
-    // elastic_pw: Option<String>,
+    #[structopt(
+        long,
+        short = "u",
+        env = "FI_ES_USER",
+        help = "Elasticsearch username",
+        requires("elastic-pw")
+    )]
+    elastic_user: Option<String>,
+
+    #[structopt(
+        long,
+        short = "p",
+        env = "FI_ES_PASSWORD",
+        hide_env_values = true,
+        help = "Elasticsearch password",
+        requires("elastic-user")
+    )]
+    elastic_pw: Option<String>,
+
+    #[structopt(
+        long,
+        env = "FI_ES_API_KEY",
+        hide_env_values = true,
+        help = "Elasticsearch API key, given as <id>:<api_key>",
+        conflicts_with_all(&["elastic-user", "elastic-token"])
+    )]
+    elastic_api_key: Option<String>,
+
+    #[structopt(
+        long,
+        env = "FI_ES_TOKEN",
+        hide_env_values = true,
+        help = "Elasticsearch bearer token",
+        conflicts_with("elastic-user")
+    )]
+    elastic_token: Option<String>,
+
+    #[structopt(
+        long,
+        env = "FI_ES_CLIENT_CERT",
+        help = "PEM file with a client certificate and its key to authenticate with",
+        conflicts_with_all(&["elastic-user", "elastic-api-key", "elastic-token"])
+    )]
+    elastic_client_cert: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        env = "FI_ES_CA_CERT",
+        help = "PEM bundle of CA certificates to trust when connecting to Elasticsearch"
+    )]
+    elastic_ca_cert: Option<PathBuf>,
+
     #[structopt(
         long,
         env = "FI_ES_URL",
@@ -148,6 +461,16 @@ struct ElasticOpts {
     )]
     elastic_url: String,
 
+    #[structopt(
+        long,
+        help = "Search engine running at --elastic-url",
+        possible_values = &SearchBackend::variants(),
+        case_insensitive = true,
+        default_value = "elasticsearch",
+        env = "FI_BACKEND"
+    )]
+    backend: SearchBackend,
+
     #[structopt(
         long,
         help = "Name of the index to store results to",
@@ -166,6 +489,22 @@ struct ElasticOpts {
     )]
     elastic_exists: ExistsStrategy,
 
+    #[structopt(
+        long,
+        help = "Maximum size of a single bulk request in bytes",
+        env = "FI_ES_CHUNK_BYTES",
+        default_value = "10485760"
+    )]
+    elastic_chunk_bytes: usize,
+
+    #[structopt(
+        long,
+        help = "How often to retry documents rejected by an overloaded cluster",
+        env = "FI_ES_RETRIES",
+        default_value = "5"
+    )]
+    elastic_retries: u32,
+
     #[structopt(
         long,
         help = "Which schema version to associate with the operation",
@@ -179,9 +518,54 @@ struct ElasticOpts {
         env = "FI_ES_NO_ALIAS"
     )]
     no_alias: bool,
+
+    #[structopt(
+        long,
+        help = "Minimum number of documents of each type before the `latest` alias is switched",
+        env = "FI_ES_MIN_DOCS",
+        default_value = "1"
+    )]
+    elastic_min_docs: u64,
+
+    #[structopt(
+        long,
+        help = "Minimum ratio of documents compared to the index currently aliased as `latest`",
+        env = "FI_ES_MIN_RATIO",
+        default_value = "0.5"
+    )]
+    elastic_min_ratio: f64,
+
+    #[structopt(
+        long,
+        help = "After switching the `latest` alias, delete all but this many indices of the same source",
+        env = "FI_ES_KEEP_INDICES"
+    )]
+    elastic_keep_indices: Option<usize>,
+}
+
+arg_enum! {
+    /// Search engines exports can be pushed to
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum SearchBackend {
+        Elasticsearch,
+        Opensearch,
+    }
+}
+
+/// Number of exports handed to a [Sink] at once
+const BATCH_SIZE: usize = 10_000;
+
+type ExportStream = Box<dyn Iterator<Item = Result<Export, FlakeInfoError>>>;
+type LazyExports = Box<dyn FnOnce() -> Result<ExportStream, FlakeInfoError>>;
+
+/// Wraps exports that have already been computed
+fn ready(exports: Vec<Export>) -> LazyExports {
+    Box::new(move || Ok(Box::new(exports.into_iter().map(Ok)) as ExportStream))
 }
 
-type LazyExports = Box<dyn FnOnce() -> Result<Vec<Export>, FlakeInfoError>>;
+fn nixpkgs_stream(exports: impl Iterator<Item = anyhow::Result<Export>> + 'static) -> ExportStream {
+    Box::new(exports.map(|export| export.map_err(FlakeInfoError::Nixpkgs)))
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -189,17 +573,107 @@ async fn main() -> Result<()> {
 
     let args = Args::from_args();
 
+    let command = match args.command {
+        Command::Import(command) => command,
+        #[cfg(feature = "local")]
+        Command::Search {
+            query,
+            index,
+            limit,
+            json,
+        } => return search_local(&index, &query, args.kind, limit, json),
+        Command::Diff { old, new, json } => return diff_exports(&old, &new, json),
+        Command::Push { dump } => return push_dump(&args.elastic, &dump).await,
+        Command::Indices(command) => return manage_indices(&args.elastic, command).await,
+        Command::Schema(SchemaCommand::Check {
+            version,
+            record,
+            update,
+        }) => return check_schema(version, &record, update),
+    };
+
+    #[cfg(feature = "local")]
+    let local_index = args.output.local_index.is_some();
+    #[cfg(not(feature = "local"))]
+    let local_index = false;
+
+    #[cfg(feature = "meilisearch")]
+    let meilisearch = args.output.meilisearch.is_some();
+    #[cfg(not(feature = "meilisearch"))]
+    let meilisearch = false;
+
     anyhow::ensure!(
-        args.elastic.enable || args.elastic.json,
-        "at least one of --push or --json must be specified"
+        args.elastic.enable
+            || args.output.json
+            || args.output.json_array
+            || args.output.ndjson
+            || args.output.bulk_file.is_some()
+            || local_index
+            || args.output.sqlite.is_some()
+            || meilisearch,
+        "one of --push, --json, --json-array, --ndjson, --bulk-file, --local-index, --sqlite or --meilisearch must be specified"
     );
 
-    let (exports, ident, partial_error) = run_command(args.command, args.kind, &args.extra).await?;
+    flake_info::commands::set_limits(Limits {
+        timeout: args.nix_timeout.map(Duration::from_secs),
+        memory: args.nix_memory.map(|mib| mib * 1024 * 1024),
+    });
+
+    let mut command = command;
+    let runner: Box<dyn NixRunner> = if args.nix_repl {
+        let jobs = match &command {
+            ImportCommand::Group { jobs, .. } => *jobs,
+            _ => 1,
+        };
+        Box::new(ReplRunner::new(jobs))
+    } else {
+        Box::new(SystemRunner)
+    };
+    if let Some(dir) = &args.replay {
+        flake_info::commands::set_runner(ReplayRunner::open(dir)?);
+    } else if let Some(dir) = &args.record {
+        flake_info::commands::set_runner(RecordingRunner::create(dir, runner)?);
+    } else {
+        flake_info::commands::set_runner(runner);
+    }
+    if args.record.is_some() || args.replay.is_some() {
+        // cached exports would skip the evaluation that is to be recorded or replayed
+        if let ImportCommand::Group { no_cache, .. } = &mut command {
+            *no_cache = true;
+        }
+    }
+
+    let eval_nixpkgs = args.eval_nixpkgs.unwrap_or_default();
+    let (exports, provenance, partial_error) =
+        run_command(command, args.kind, &args.extra, &eval_nixpkgs).await?;
+    let ident = provenance.ident.clone();
 
     if args.elastic.enable {
-        push_to_elastic(&args.elastic, exports, ident).await?;
-    } else if args.elastic.json {
-        println!("{}", serde_json::to_string(&exports()?)?);
+        push_to_elastic(&args.elastic, exports, &provenance).await?;
+    } else if args.output.json {
+        let mut sink = JsonSink::new(io::BufWriter::new(io::stdout().lock()), ident);
+        sink::write_exports(&mut sink, exports()?, BATCH_SIZE).await?;
+    } else if args.output.json_array {
+        let mut sink = JsonSink::array(io::BufWriter::new(io::stdout().lock()));
+        sink::write_exports(&mut sink, exports()?, BATCH_SIZE).await?;
+    } else if args.output.ndjson {
+        let mut sink = NdjsonSink::new(io::BufWriter::new(io::stdout().lock()), ident);
+        sink::write_exports(&mut sink, exports()?, BATCH_SIZE).await?;
+    } else if let Some(path) = &args.output.bulk_file {
+        let mut sink = BulkFileSink::new(path);
+        sink::write_exports(&mut sink, exports()?, BATCH_SIZE).await?;
+    } else if let Some(path) = &args.output.sqlite {
+        let mut sink = SqliteSink::new(path);
+        sink::write_exports(&mut sink, exports()?, BATCH_SIZE).await?;
+    } else if local_index {
+        #[cfg(feature = "local")]
+        {
+            let mut sink = LocalIndexSink::new(args.output.local_index.as_deref().unwrap());
+            sink::write_exports(&mut sink, exports()?, BATCH_SIZE).await?;
+        }
+    } else if meilisearch {
+        #[cfg(feature = "meilisearch")]
+        write_meilisearch(&args.output, exports, provenance.ident.as_ref()).await?;
     }
 
     // Surface partial failures (e.g. some group members failed to evaluate) as a
@@ -219,28 +693,44 @@ enum FlakeInfoError {
     Flake(anyhow::Error),
     #[error("Getting nixpkgs info caused an error: {0:?}")]
     Nixpkgs(anyhow::Error),
-    #[error("Some members of the group '{0}' could not be processed: \n {}", .1.iter().enumerate().map(|(n, e)| format!("{}: {:?}", n+1, e)).collect::<Vec<String>>().join("\n\n"))]
-    Group(String, Vec<anyhow::Error>),
+    #[error("Some members of the group '{0}' could not be processed ({} timed out): \n {}", .1.iter().filter(|e| matches!(e, MemberError::Timeout(_))).count(), .1.iter().enumerate().map(|(n, e)| format!("{}: {}", n+1, e)).collect::<Vec<String>>().join("\n\n"))]
+    Group(String, Vec<MemberError>),
     #[error("Couldn't perform IO: {0}")]
     IO(#[from] io::Error),
 }
 
+/// Why a member of a group could not be processed
+#[derive(Debug, Error)]
+enum MemberError {
+    #[error("Timed out: {0:?}")]
+    Timeout(anyhow::Error),
+    #[error("{0:?}")]
+    Failed(anyhow::Error),
+}
+
+impl From<anyhow::Error> for MemberError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast_ref::<NixError>() {
+            Some(NixError::Timeout { .. }) => MemberError::Timeout(error),
+            _ => MemberError::Failed(error),
+        }
+    }
+}
+
 async fn run_command(
-    command: Command,
+    command: ImportCommand,
     kind: Kind,
     extra: &[String],
-) -> Result<
-    (
-        LazyExports,
-        (String, String, String),
-        Option<FlakeInfoError>,
-    ),
-    FlakeInfoError,
-> {
-    flake_info::commands::check_nix_version(env!("MIN_NIX_VERSION"))?;
+    eval_nixpkgs: &EvalNixpkgs,
+) -> Result<(LazyExports, Provenance, Option<FlakeInfoError>), FlakeInfoError> {
+    let nix_version = flake_info::commands::check_nix_version(env!("MIN_NIX_VERSION"))?;
+    let provenance = |ident: Ident, sources: Vec<SourceRevision>| Provenance {
+        sources,
+        ..Provenance::new(Some(ident), Some(nix_version.clone()))
+    };
 
     match command {
-        Command::Flake { flake, temp_store } => {
+        ImportCommand::Flake { flake, temp_store } => {
             let source = if flake.starts_with("github:") {
                 let mut s = flake.split(":").skip(1).next().unwrap().split("/");
                 Source::Github {
@@ -252,19 +742,31 @@ async fn run_command(
             } else {
                 Source::Git { url: flake }
             };
-            let (info, exports) =
-                flake_info::process_flake(&source, &kind, temp_store, extra, false)
-                    .map_err(FlakeInfoError::Flake)?;
-
-            let ident = (
-                "flake".to_owned(),
-                info.name,
-                info.revision.unwrap_or("latest".into()),
-            );
+            let eval_nixpkgs = eval_nixpkgs.lock().map_err(FlakeInfoError::Flake)?;
+            let (info, exports) = flake_info::process_flake(
+                &source,
+                &kind,
+                temp_store,
+                extra,
+                false,
+                None,
+                &eval_nixpkgs,
+            )
+            .map_err(FlakeInfoError::Flake)?;
 
-            Ok((Box::new(|| Ok(exports)), ident, None))
+            let revision = SourceRevision {
+                nixpkgs: info.nixpkgs.clone().map(Box::new),
+                ..SourceRevision::new(&source, info.revision.clone())
+            };
+            let ident = Ident {
+                kind: "flake".to_owned(),
+                name: info.name,
+                hash: info.revision.unwrap_or("latest".into()),
+            };
+
+            Ok((ready(exports), provenance(ident, vec![revision]), None))
         }
-        Command::Nixpkgs {
+        ImportCommand::Nixpkgs {
             channel,
             attribute,
             packages_json_url,
@@ -272,10 +774,14 @@ async fn run_command(
             let nixpkgs = Source::nixpkgs(channel)
                 .await
                 .map_err(FlakeInfoError::Nixpkgs)?;
-            let ident = (
-                "nixos".to_owned(),
-                nixpkgs.channel.to_owned(),
-                nixpkgs.git_ref.to_owned(),
+            let ident = Ident {
+                kind: "nixos".to_owned(),
+                name: nixpkgs.channel.to_owned(),
+                hash: nixpkgs.git_ref.to_owned(),
+            };
+            let revision = SourceRevision::new(
+                &Source::Nixpkgs(nixpkgs.clone()),
+                Some(nixpkgs.git_ref.to_owned()),
             );
             let kind = if attribute.is_some() {
                 if !matches!(kind, Kind::All | Kind::Package) {
@@ -288,27 +794,34 @@ async fn run_command(
 
             Ok((
                 Box::new(move || {
-                    flake_info::process_nixpkgs(
+                    let exports = flake_info::nixpkgs_exports(
                         &Source::Nixpkgs(nixpkgs),
                         &kind,
                         &attribute,
                         &packages_json_url,
                     )
-                    .map_err(FlakeInfoError::Nixpkgs)
+                    .map_err(FlakeInfoError::Nixpkgs)?;
+                    Ok(nixpkgs_stream(exports))
                 }),
-                ident,
+                provenance(ident, vec![revision]),
                 None,
             ))
         }
-        Command::NixpkgsArchive {
+        ImportCommand::NixpkgsArchive {
             source,
             channel,
             attribute,
         } => {
-            let ident = (
-                "nixos".to_string(),
-                channel.to_owned(),
-                "latest".to_string(),
+            let ident = Ident {
+                kind: "nixos".to_string(),
+                name: channel.to_owned(),
+                hash: "latest".to_string(),
+            };
+            let revision = SourceRevision::new(
+                &Source::Git {
+                    url: source.to_owned(),
+                },
+                None,
             );
             let kind = if attribute.is_some() {
                 if !matches!(kind, Kind::All | Kind::Package) {
@@ -321,58 +834,106 @@ async fn run_command(
 
             Ok((
                 Box::new(move || {
-                    flake_info::process_nixpkgs(
+                    let exports = flake_info::nixpkgs_exports(
                         &Source::Git { url: source },
                         &kind,
                         &attribute,
                         &None,
                     )
-                    .map_err(FlakeInfoError::Nixpkgs)
+                    .map_err(FlakeInfoError::Nixpkgs)?;
+                    Ok(nixpkgs_stream(exports))
                 }),
-                ident,
+                provenance(ident, vec![revision]),
                 None,
             ))
         }
-        Command::Group {
+        ImportCommand::Group {
             targets,
             temp_store,
             name,
             report,
             with_gc,
+            jobs,
+            no_cache,
+            cache_dir,
         } => {
             // if reporting is enabled delete old report
             if report && tokio::fs::metadata("report.txt").await.is_ok() {
                 tokio::fs::remove_file("report.txt").await?;
             }
 
+            let cache = if no_cache {
+                None
+            } else {
+                let dir = cache_dir.unwrap_or_else(ExportCache::default_dir);
+                Some(ExportCache::open(&dir).map_err(FlakeInfoError::Flake)?)
+            };
+
             let sources = Source::read_sources_file(&targets)?;
-            let (exports_and_hashes, errors) = sources
-                .iter()
-                .map(|source| match source {
-                    Source::Nixpkgs(nixpkgs) => {
-                        flake_info::process_nixpkgs(source, &kind, &None, &None)
-                            .with_context(|| {
-                                format!(
-                                    "While processing nixpkgs archive {}",
-                                    source.to_flake_ref()
-                                )
-                            })
-                            .map(|result| (result, nixpkgs.git_ref.to_owned()))
-                    }
-                    _ => flake_info::process_flake(source, &kind, temp_store, &extra, with_gc)
+            // all members are evaluated with the nixpkgs the reference points to now
+            let eval_nixpkgs = eval_nixpkgs.lock().map_err(FlakeInfoError::Flake)?;
+            // collecting garbage while other members are evaluated would delete
+            // the store paths they are using, so concurrent imports only collect
+            // once all members are done
+            let gc_between_members = with_gc && jobs <= 1;
+            let results = process_parallel(&sources, jobs, |source| match source {
+                Source::Nixpkgs(nixpkgs) => {
+                    flake_info::process_nixpkgs(source, &kind, &None, &None)
                         .with_context(|| {
-                            format!("While processing flake {}", source.to_flake_ref())
+                            format!("While processing nixpkgs archive {}", source.to_flake_ref())
                         })
-                        .map(|(info, result)| (result, info.revision.unwrap_or("latest".into()))),
+                        .map(|result| (result, nixpkgs.git_ref.to_owned(), None))
+                }
+                _ => flake_info::process_flake(
+                    source,
+                    &kind,
+                    temp_store,
+                    &extra,
+                    gc_between_members,
+                    cache.as_ref(),
+                    &eval_nixpkgs,
+                )
+                .with_context(|| format!("While processing flake {}", source.to_flake_ref()))
+                .map(|(info, result)| {
+                    (
+                        result,
+                        info.revision.unwrap_or("latest".into()),
+                        info.nixpkgs,
+                    )
+                }),
+            });
+            if with_gc && !gc_between_members {
+                flake_info::commands::run_garbage_collection().map_err(FlakeInfoError::Flake)?;
+            }
+
+            let revisions: Vec<SourceRevision> = sources
+                .iter()
+                .zip(&results)
+                .filter_map(|(source, result)| {
+                    let (_, hash, nixpkgs) = result.as_ref().ok()?;
+                    Some(SourceRevision {
+                        nixpkgs: nixpkgs.clone().map(Box::new),
+                        ..SourceRevision::new(source, Some(hash.to_owned()))
+                    })
                 })
-                .partition::<Vec<_>, _>(Result::is_ok);
+                .collect();
+            let (exports_and_hashes, errors) =
+                results.into_iter().partition::<Vec<_>, _>(Result::is_ok);
+
+            if let Some(cache) = &cache {
+                info!(
+                    "{} of {} group members were reused from the cache",
+                    cache.hits(),
+                    sources.len()
+                );
+            }
 
             let (exports, hashes) = exports_and_hashes
                 .into_iter()
                 .map(|result| result.unwrap()) // each result is_ok
                 .fold(
                     (Vec::new(), Vec::new()),
-                    |(mut exports, mut hashes), (export, hash)| {
+                    |(mut exports, mut hashes), (export, hash, _)| {
                         exports.extend(export);
                         hashes.push(hash);
                         (exports, hashes)
@@ -381,8 +942,9 @@ async fn run_command(
 
             let errors = errors
                 .into_iter()
-                .map(Result::unwrap_err) // each result is_err
+                .map(|result| MemberError::from(result.unwrap_err())) // each result is_err
                 .collect::<Vec<_>>();
+            let failed_members = errors.len();
 
             let partial_error = if !errors.is_empty() {
                 let error = FlakeInfoError::Group(name.clone(), errors);
@@ -412,17 +974,347 @@ async fn run_command(
                 format!("{:08x}", sha.finalize())
             };
 
-            let ident = ("group".to_owned(), name, hash);
+            let ident = Ident {
+                kind: "group".to_owned(),
+                name,
+                hash,
+            };
+
+            let provenance = Provenance {
+                failed_members,
+                ..provenance(ident, revisions)
+            };
+
+            Ok((ready(exports), provenance, partial_error))
+        }
+    }
+}
+
+/// Replaces the contents of the Meilisearch index given by `--meilisearch-index`,
+/// or named after the ident of the import
+#[cfg(feature = "meilisearch")]
+async fn write_meilisearch(
+    output: &OutputOpts,
+    exports: LazyExports,
+    ident: Option<&Ident>,
+) -> Result<()> {
+    use flake_info::meilisearch::{Meilisearch, MeilisearchOptions, MeilisearchSink};
+
+    let index = output
+        .meilisearch_index
+        .clone()
+        .or_else(|| {
+            let Ident { kind, name, .. } = ident?;
+            // index names are restricted to alphanumeric characters, `-` and `_`
+            Some(
+                format!("{}-{}", kind, name)
+                    .replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_"),
+            )
+        })
+        .context("The import does not identify its source, specify --meilisearch-index")?;
+    info!("Writing to Meilisearch index {}", index);
+
+    let client = Meilisearch::new(
+        output.meilisearch.as_deref().unwrap(),
+        MeilisearchOptions {
+            api_key: output.meilisearch_key.clone(),
+            ..Default::default()
+        },
+    )?;
+    let mut sink = MeilisearchSink::new(&client, &index);
+    sink::write_exports(&mut sink, exports()?, BATCH_SIZE).await
+}
+
+/// Pushes a dump without evaluating its sources again
+async fn push_dump(elastic: &ElasticOpts, dump: &std::path::Path) -> Result<()> {
+    anyhow::ensure!(
+        elastic.elastic_schema_version.is_some(),
+        "--elastic-schema-version is required to push a dump"
+    );
+
+    let file = std::fs::File::open(dump)
+        .with_context(|| format!("Couldn't open dump {}", dump.display()))?;
+    let (ident, exports) = Export::read_dump(io::BufReader::new(file))
+        .with_context(|| format!("Couldn't read dump {}", dump.display()))?;
+    info!("Read {} exports from {}", exports.len(), dump.display());
+
+    push_to_elastic(elastic, ready(exports), &Provenance::new(ident, None)).await
+}
+
+fn check_schema(version: usize, record: &std::path::Path, update: bool) -> Result<()> {
+    let fingerprint = elastic::schema_fingerprint()?;
+
+    if update {
+        let file = std::fs::File::create(record)
+            .with_context(|| format!("Couldn't create {}", record.display()))?;
+        serde_json::to_writer_pretty(
+            file,
+            &SchemaRecord {
+                version,
+                fingerprint,
+            },
+        )?;
+        println!("Recorded schema version {}", version);
+        return Ok(());
+    }
+
+    let file = std::fs::File::open(record).with_context(|| {
+        format!(
+            "Couldn't open {}, create it with `flake-info schema check --update`",
+            record.display()
+        )
+    })?;
+    let recorded: SchemaRecord = serde_json::from_reader(io::BufReader::new(file))
+        .with_context(|| format!("Couldn't read {}", record.display()))?;
+
+    if recorded.fingerprint == fingerprint {
+        if recorded.version != version {
+            warn!(
+                "Schema version changed from {} to {} without a schema change",
+                recorded.version, version
+            );
+        }
+        println!("Schema {} is up to date", fingerprint);
+        return Ok(());
+    }
+
+    if recorded.version == version {
+        anyhow::bail!(
+            "The index mapping or export schema changed but the schema version is still {}, \
+             bump the `import` version in version.nix and run `flake-info schema check --update`",
+            version
+        );
+    }
+    anyhow::bail!(
+        "The schema changed for version {} but {} still records version {}, \
+         run `flake-info schema check --update`",
+        version,
+        record.display(),
+        recorded.version
+    )
+}
+
+fn diff_exports(old: &std::path::Path, new: &std::path::Path, json: bool) -> Result<()> {
+    let diff = flake_info::diff::diff(
+        &flake_info::diff::read_exports(old)?,
+        &flake_info::diff::read_exports(new)?,
+    )?;
+
+    if json {
+        println!("{}", serde_json::to_string(&diff)?);
+    } else {
+        println!("{}", diff);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "local")]
+fn search_local(
+    index: &std::path::Path,
+    query: &str,
+    kind: Kind,
+    limit: usize,
+    json: bool,
+) -> Result<()> {
+    let hits = flake_info::local::search(index, query, kind, limit)?;
+
+    if json {
+        let documents: Vec<_> = hits.into_iter().map(|hit| hit.document).collect();
+        println!("{}", serde_json::to_string(&documents)?);
+        return Ok(());
+    }
+
+    for hit in hits {
+        let field = |name: &str| hit.document[name].as_str().unwrap_or_default().to_owned();
+        match field("type").as_str() {
+            "package" => println!(
+                "{} ({})\n    {}",
+                field("package_attr_name"),
+                field("package_pversion"),
+                field("package_description")
+            ),
+            "app" => println!("{} (app)", field("app_attr_name")),
+            other => println!(
+                "{} ({})\n    {}",
+                field("option_name"),
+                other,
+                field("option_type")
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Applies `process` to every item using at most `jobs` worker threads.
+///
+/// Results are returned in the order of `items`, regardless of the order in
+/// which the workers finish, so that anything derived from them (e.g. the
+/// group hash) stays deterministic.
+fn process_parallel<T, R, F>(items: &[T], jobs: usize, process: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let jobs = jobs.clamp(1, items.len().max(1));
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..items.len()).map(|_| None).collect::<Vec<Option<R>>>());
 
-            Ok((Box::new(|| Ok(exports)), ident, partial_error))
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let item = match items.get(index) {
+                        Some(item) => item,
+                        None => break,
+                    };
+                    let result = process(item);
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            });
         }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("every item is processed by a worker"))
+        .collect()
+}
+
+fn connection_options(elastic: &ElasticOpts) -> Result<elastic::ConnectionOptions> {
+    let auth = if let (Some(user), Some(password)) = (&elastic.elastic_user, &elastic.elastic_pw) {
+        Some(elastic::Auth::Basic {
+            user: user.to_owned(),
+            password: password.to_owned(),
+        })
+    } else if let Some(api_key) = &elastic.elastic_api_key {
+        let (id, key) = api_key
+            .split_once(':')
+            .context("The API key needs to be given as <id>:<api_key>")?;
+        Some(elastic::Auth::ApiKey {
+            id: id.to_owned(),
+            key: key.to_owned(),
+        })
+    } else if let Some(token) = &elastic.elastic_token {
+        Some(elastic::Auth::Bearer(token.to_owned()))
+    } else {
+        elastic
+            .elastic_client_cert
+            .clone()
+            .map(elastic::Auth::ClientCertificate)
+    };
+
+    Ok(elastic::ConnectionOptions {
+        auth,
+        ca_certificate: elastic.elastic_ca_cert.clone(),
+    })
+}
+
+fn connect(elastic: &ElasticOpts) -> Result<elastic::Elasticsearch> {
+    Ok(elastic::Elasticsearch::connect(
+        elastic.elastic_url.as_str(),
+        &connection_options(elastic)?,
+    )?)
+}
+
+fn print_index(index: &elastic::IndexInfo) {
+    let aliases = if index.aliases.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", index.aliases.join(", "))
+    };
+    println!("{} {} documents{}", index.name, index.documents, aliases);
+}
+
+#[cfg(feature = "opensearch")]
+fn connect_opensearch(elastic: &ElasticOpts) -> Result<OpenSearch> {
+    Ok(OpenSearch::connect(
+        elastic.elastic_url.as_str(),
+        &connection_options(elastic)?,
+    )?)
+}
+
+#[cfg(not(feature = "opensearch"))]
+const NO_OPENSEARCH: &str =
+    "flake-info was built without OpenSearch support, enable the `opensearch` feature";
+
+async fn manage_indices(elastic: &ElasticOpts, command: IndicesCommand) -> Result<()> {
+    match elastic.backend {
+        SearchBackend::Elasticsearch => indices_command(&connect(elastic)?, command).await,
+        #[cfg(feature = "opensearch")]
+        SearchBackend::Opensearch => indices_command(&connect_opensearch(elastic)?, command).await,
+        #[cfg(not(feature = "opensearch"))]
+        SearchBackend::Opensearch => anyhow::bail!(NO_OPENSEARCH),
     }
 }
 
+async fn indices_command(es: &impl Backend, command: IndicesCommand) -> Result<()> {
+    match command {
+        IndicesCommand::List { pattern } => {
+            for index in es.list_indices(&pattern).await? {
+                print_index(&index);
+            }
+        }
+        IndicesCommand::Prune {
+            keep,
+            dry_run,
+            pattern,
+        } => {
+            let deleted = es
+                .prune_indices(&pattern, keep, dry_run)
+                .await
+                .with_context(|| "Failed to delete old indices".to_string())?;
+            for index in &deleted {
+                println!("{}", index);
+            }
+            if dry_run {
+                info!("Would delete {} indices", deleted.len());
+            } else {
+                info!("Deleted {} indices", deleted.len());
+            }
+        }
+        IndicesCommand::Describe { index } => {
+            let info = es
+                .list_indices(&index)
+                .await?
+                .into_iter()
+                .find(|info| info.name == index)
+                .with_context(|| format!("No index named {}", index))?;
+
+            print_index(&info);
+            if let Some(parsed) = &info.parsed {
+                println!("kind: {}", parsed.kind);
+                println!("schema: {}", parsed.schema);
+                println!("source: {}", parsed.name);
+                println!("hash: {}", parsed.hash);
+            }
+
+            let mut counts: Vec<_> = es.count_types(&index).await?.into_iter().collect();
+            counts.sort();
+            for (document_type, count) in counts {
+                println!("{}: {}", document_type, count);
+            }
+
+            match es.provenance(&index).await? {
+                Some(provenance) => {
+                    println!("provenance:");
+                    println!("{}", serde_json::to_string_pretty(&provenance)?);
+                }
+                None => println!("no provenance recorded"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
 async fn push_to_elastic(
     elastic: &ElasticOpts,
     exports: LazyExports,
-    ident: (String, String, String),
+    provenance: &Provenance,
 ) -> Result<()> {
     let (index, alias) = elastic
         .elastic_index_name
@@ -434,14 +1326,14 @@ async fn push_to_elastic(
             )
         })
         .or_else(|| {
-            let (kind, name, hash) = ident;
-            let ident = format!(
-                "{}-{}-{}-{}",
+            let Ident { kind, name, hash } = provenance.ident.as_ref()?;
+            let prefix = format!(
+                "{}-{}-{}-",
                 kind,
                 elastic.elastic_schema_version.unwrap(),
-                &name,
-                hash
+                &name
             );
+            let ident = format!("{}{}", prefix, hash);
             let alias = format!(
                 "latest-{}-{}-{}",
                 elastic.elastic_schema_version.unwrap(),
@@ -450,42 +1342,124 @@ async fn push_to_elastic(
             );
 
             warn!("Using automatic index identifier: {}", ident);
-            Some((ident, Some(alias)))
+            Some((ident, Some((alias, format!("{}*", prefix)))))
         })
-        .unwrap();
+        .context("The dump does not identify its source, specify --elastic-index-name")?;
 
-    let es = elastic::Elasticsearch::new(elastic.elastic_url.as_str())?;
     let config = elastic::Config {
         index: &index,
         exists_strategy: elastic.elastic_exists,
+        push: elastic::PushOptions {
+            max_chunk_bytes: elastic.elastic_chunk_bytes,
+            max_retries: elastic.elastic_retries,
+            ..Default::default()
+        },
+        provenance: Some(provenance),
     };
 
-    // catch error variant if abort strategy was triggered
-    let ensure = es.ensure_index(&config).await;
-    if let Err(ElasticsearchError::IndexExistsError(_)) = ensure {
-        // abort on abort
-        return Ok(());
-    } else {
-        // throw error if present
-        ensure?;
+    match elastic.backend {
+        SearchBackend::Elasticsearch => {
+            push_exports(&connect(elastic)?, elastic, exports, &config, alias).await
+        }
+        #[cfg(feature = "opensearch")]
+        SearchBackend::Opensearch => {
+            push_exports(
+                &connect_opensearch(elastic)?,
+                elastic,
+                exports,
+                &config,
+                alias,
+            )
+            .await
+        }
+        #[cfg(not(feature = "opensearch"))]
+        SearchBackend::Opensearch => anyhow::bail!(NO_OPENSEARCH),
     }
+}
 
-    let successes = exports()?;
+async fn push_exports(
+    es: &impl Backend,
+    elastic: &ElasticOpts,
+    exports: LazyExports,
+    config: &elastic::Config<'_>,
+    alias: Option<(String, String)>,
+) -> Result<()> {
+    let alias = alias.and_then(|(alias, pattern)| {
+        if elastic.no_alias {
+            warn!("Creating alias disabled");
+            return None;
+        }
+        Some(elastic::AliasOptions {
+            alias,
+            validation: elastic::Validation {
+                min_documents: elastic.elastic_min_docs,
+                min_ratio: elastic.elastic_min_ratio,
+            },
+            prune: elastic.elastic_keep_indices.map(|keep| (pattern, keep)),
+        })
+    });
 
     info!("Pushing to elastic");
-    es.push_exports(&config, &successes)
-        .await
-        .with_context(|| "Failed to push results to elasticsearch".to_string())?;
-
-    if let Some(alias) = alias {
-        if !elastic.no_alias {
-            es.write_alias(&config, &index, &alias)
-                .await
-                .with_context(|| "Failed to create alias".to_string())?;
-        } else {
-            warn!("Creating alias disabled")
+    let mut sink = ElasticSink::new(es, config, alias);
+    match sink::write_exports(&mut sink, exports()?, BATCH_SIZE).await {
+        // abort on abort
+        Err(error)
+            if matches!(
+                error.downcast_ref(),
+                Some(ElasticsearchError::IndexExistsError(_))
+            ) =>
+        {
+            Ok(())
         }
+        result => result,
     }
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_output() {
+        let parse = |args: &[&str]| {
+            Args::from_iter_safe(
+                ["flake-info"]
+                    .iter()
+                    .chain(args)
+                    .chain(&["nixpkgs", "unstable"]),
+            )
+        };
+
+        assert!(parse(&["--json"]).is_ok());
+        assert!(parse(&["--json", "--sqlite", "db"]).is_err());
+        assert!(parse(&["--ndjson", "--bulk-file", "nixpkgs.bulk"]).is_err());
+        assert!(parse(&["--push", "--elastic-schema-version", "48", "--json"]).is_err());
+    }
+
+    #[test]
+    fn test_process_parallel() {
+        let items: Vec<u64> = (0..20).collect();
+        let results = process_parallel(&items, 4, |item| {
+            // later items finish first
+            thread::sleep(Duration::from_millis(20 - item));
+            if item % 3 == 0 {
+                Err(format!("member {} failed", item))
+            } else {
+                Ok(item * 2)
+            }
+        });
+
+        assert_eq!(results.len(), items.len());
+        for (item, result) in items.iter().zip(&results) {
+            match result {
+                Ok(double) => assert_eq!(*double, item * 2),
+                Err(e) => assert_eq!(*e, format!("member {} failed", item)),
+            }
+        }
+        let errors = results.iter().filter(|result| result.is_err()).count();
+        assert_eq!(errors, 7);
+
+        assert!(process_parallel(&[] as &[u64], 4, |item| *item).is_empty());
+        assert_eq!(process_parallel(&items, 0, |item| *item), items);
+    }
 }
@@ -0,0 +1,216 @@
+/// A persistent cache of flake exports.
+///
+/// Evaluating a flake is by far the most expensive part of an import. As long as
+/// `nix flake metadata` reports the same locked revision for a flake, its
+/// exports will not change, so they are stored on disk keyed by the flake
+/// reference, the locked revision and the evaluation parameters.
+use std::{
+    env,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use sha2::Digest;
+
+use crate::data::{Export, SCHEMA_VERSION, import::Kind};
+
+/// Distinguishes the temporary files of concurrent [ExportCache::put]s
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+pub struct ExportCache {
+    dir: PathBuf,
+    hits: AtomicUsize,
+}
+
+impl ExportCache {
+    /// Opens (and creates if necessary) a cache located at `dir`
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Couldn't create cache directory {}", dir.display()))?;
+        Ok(ExportCache {
+            dir: dir.to_owned(),
+            hits: AtomicUsize::new(0),
+        })
+    }
+
+    /// `$XDG_CACHE_HOME/flake-info`, falling back to `~/.cache/flake-info`
+    pub fn default_dir() -> PathBuf {
+        env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .unwrap_or_else(env::temp_dir)
+            .join("flake-info")
+    }
+
+    /// Looks up the exports of `flake_ref` at the locked `revision`.
+    ///
+    /// Unreadable entries are treated as a cache miss.
+    pub fn get(
+        &self,
+        flake_ref: &str,
+        revision: &str,
+        kind: Kind,
+        extra: &[String],
+    ) -> Option<Vec<Export>> {
+        let path = self.entry_path(flake_ref, revision, kind, extra);
+        let file = File::open(&path).ok()?;
+
+        match serde_json::from_reader(BufReader::new(file)) {
+            Ok(exports) => {
+                debug!("Cache hit for {} at {}", flake_ref, revision);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(exports)
+            }
+            Err(e) => {
+                warn!("Ignoring unreadable cache entry {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Stores the exports of `flake_ref` at the locked `revision`
+    pub fn put(
+        &self,
+        flake_ref: &str,
+        revision: &str,
+        kind: Kind,
+        extra: &[String],
+        exports: &[Export],
+    ) -> Result<()> {
+        let path = self.entry_path(flake_ref, revision, kind, extra);
+
+        // Write to a temporary file first so that concurrent readers never
+        // observe a partially written entry, and concurrent writers, in this
+        // or another process, never write to the same file
+        let temp_path = path.with_extension(format!(
+            "{}-{}.tmp",
+            std::process::id(),
+            TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::create(&temp_path)
+            .with_context(|| format!("Couldn't create cache entry {}", temp_path.display()))?;
+        serde_json::to_writer(BufWriter::new(file), exports)?;
+        fs::rename(&temp_path, &path)
+            .with_context(|| format!("Couldn't write cache entry {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// Number of successful lookups since the cache was opened
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    fn entry_path(&self, flake_ref: &str, revision: &str, kind: Kind, extra: &[String]) -> PathBuf {
+        let mut sha = sha2::Sha256::new();
+        // exports produced by a different version may not be compatible
+        sha.update(env!("CARGO_PKG_VERSION"));
+        sha.update(SCHEMA_VERSION.to_le_bytes());
+        for part in [flake_ref, revision, kind.as_ref()]
+            .iter()
+            .copied()
+            .chain(extra.iter().map(String::as_str))
+        {
+            sha.update(part);
+            sha.update(b"\0");
+        }
+        self.dir.join(format!("{:x}.json", sha.finalize()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::import::{NixOption, NixpkgsEntry};
+
+    fn exports() -> Vec<Export> {
+        let option: NixOption = serde_json::from_str(
+            r#"{"declarations": [], "description": null, "name": "services.foo.enable", "type": "boolean"}"#,
+        )
+        .unwrap();
+        vec![Export::nixpkgs(NixpkgsEntry::Option(option)).unwrap()]
+    }
+
+    fn json(exports: &[Export]) -> serde_json::Value {
+        serde_json::to_value(exports).unwrap()
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = env::temp_dir().join(format!("flake-info-cache-{}", std::process::id()));
+        let cache = ExportCache::open(&dir).unwrap();
+        let extra = vec!["nixpkgs=abc".to_owned()];
+
+        assert!(cache.get("github:a/b", "rev", Kind::All, &extra).is_none());
+        cache
+            .put("github:a/b", "rev", Kind::All, &extra, &exports())
+            .unwrap();
+        let hit = cache.get("github:a/b", "rev", Kind::All, &extra).unwrap();
+        assert_eq!(json(&hit), json(&exports()));
+        assert_eq!(cache.hits(), 1);
+
+        // every part of the key selects a different entry
+        assert!(cache.get("github:a/c", "rev", Kind::All, &extra).is_none());
+        assert!(
+            cache
+                .get("github:a/b", "other", Kind::All, &extra)
+                .is_none()
+        );
+        assert!(
+            cache
+                .get("github:a/b", "rev", Kind::Package, &extra)
+                .is_none()
+        );
+        assert!(cache.get("github:a/b", "rev", Kind::All, &[]).is_none());
+        assert_eq!(cache.hits(), 1);
+
+        // no temporary files are left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_entry() {
+        let dir = env::temp_dir().join(format!("flake-info-cache-corrupt-{}", std::process::id()));
+        let cache = ExportCache::open(&dir).unwrap();
+
+        let path = cache.entry_path("github:a/b", "rev", Kind::All, &[]);
+        fs::write(&path, "[{\"type\": ").unwrap();
+        assert!(cache.get("github:a/b", "rev", Kind::All, &[]).is_none());
+        assert_eq!(cache.hits(), 0);
+
+        // a later put replaces the corrupt entry
+        cache
+            .put("github:a/b", "rev", Kind::All, &[], &exports())
+            .unwrap();
+        assert!(cache.get("github:a/b", "rev", Kind::All, &[]).is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_puts() {
+        let dir = env::temp_dir().join(format!("flake-info-cache-puts-{}", std::process::id()));
+        let cache = ExportCache::open(&dir).unwrap();
+
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    cache
+                        .put("github:a/b", "rev", Kind::All, &[], &exports())
+                        .unwrap()
+                });
+            }
+        });
+        let hit = cache.get("github:a/b", "rev", Kind::All, &[]).unwrap();
+        assert_eq!(json(&hit), json(&exports()));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,414 @@
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde_json::Value;
+
+use crate::data::SourceRevision;
+
+/// Tarball of the nixpkgs-unstable branch, fetched anew whenever it expired from the cache of nix
+pub const UNSTABLE_TARBALL: &str =
+    "https://github.com/NixOS/nixpkgs/archive/refs/heads/nixpkgs-unstable.tar.gz";
+
+/// The nixpkgs flakes are evaluated with, `<nixpkgs>` in the extraction script.
+///
+/// Parsed from `unstable`, `input`, a path starting with `/`, `./` or `../`,
+/// `rev:<revision>:<narHash>` or any other flake reference.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum EvalNixpkgs {
+    /// The latest nixpkgs-unstable, see [UNSTABLE_TARBALL]
+    #[default]
+    Unstable,
+    /// A local nixpkgs checkout or channel
+    Path(PathBuf),
+    /// A flake reference, e.g. `github:NixOS/nixpkgs/nixos-24.11`, see [EvalNixpkgs::lock]
+    Flake(String),
+    /// The `nixpkgs` input in the lock file of each flake, or [EvalNixpkgs::Unstable]
+    /// for flakes without one
+    FlakeInput,
+    /// A revision of NixOS/nixpkgs with the narHash of its source
+    Pinned { rev: String, hash: String },
+    /// A nixpkgs resolved by [EvalNixpkgs::lock]
+    Locked(SourceRevision),
+}
+
+impl FromStr for EvalNixpkgs {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "unstable" => EvalNixpkgs::Unstable,
+            "input" => EvalNixpkgs::FlakeInput,
+            _ if s.starts_with('/') || s.starts_with("./") || s.starts_with("../") => {
+                EvalNixpkgs::Path(s.into())
+            }
+            _ => match s.strip_prefix("rev:") {
+                Some(pin) => {
+                    let (rev, hash) = pin
+                        .split_once(':')
+                        .context("Expected a pinned nixpkgs as rev:<revision>:<narHash>")?;
+                    EvalNixpkgs::Pinned {
+                        rev: rev.to_owned(),
+                        hash: hash.to_owned(),
+                    }
+                }
+                None => EvalNixpkgs::Flake(s.to_owned()),
+            },
+        })
+    }
+}
+
+impl EvalNixpkgs {
+    /// Resolves a flake reference or [UNSTABLE_TARBALL] to the revision it
+    /// currently points to, so that all flakes of an import are evaluated
+    /// with the same nixpkgs
+    pub fn lock(&self) -> Result<EvalNixpkgs> {
+        let flake_ref = match self {
+            EvalNixpkgs::Flake(flake_ref) => flake_ref.to_owned(),
+            EvalNixpkgs::Unstable => format!("tarball+{}", UNSTABLE_TARBALL),
+            other => return Ok(other.clone()),
+        };
+        let metadata = super::nix_flake_info::get_flake_metadata(&flake_ref, false, &[])
+            .with_context(|| format!("Failed to lock nixpkgs {}", flake_ref))?;
+        Ok(EvalNixpkgs::Locked(locked(&metadata).with_context(
+            || format!("Can't lock nixpkgs {}", flake_ref),
+        )?))
+    }
+
+    /// The nixpkgs to evaluate the flake with the given `nix flake metadata`
+    /// with. Its `source` is a URL, an absolute path or a flake reference.
+    pub fn resolve(&self, metadata: &Value) -> Result<SourceRevision> {
+        let nixpkgs = |source: String, revision: Option<&str>| SourceRevision {
+            source,
+            revision: revision.map(str::to_owned),
+            nixpkgs: None,
+        };
+        Ok(match self {
+            EvalNixpkgs::Unstable => nixpkgs(UNSTABLE_TARBALL.to_owned(), None),
+            EvalNixpkgs::Path(path) => {
+                let path = path
+                    .canonicalize()
+                    .with_context(|| format!("Can't find nixpkgs at {}", path.display()))?;
+                // written into nixpkgs channels and release tarballs
+                let revision = fs::read_to_string(path.join(".git-revision")).ok();
+                nixpkgs(
+                    path.display().to_string(),
+                    revision.as_deref().map(str::trim),
+                )
+            }
+            EvalNixpkgs::Flake(flake_ref) => nixpkgs(flake_ref.to_owned(), None),
+            EvalNixpkgs::FlakeInput => match locked_input(metadata, "nixpkgs") {
+                Some(locked) => nixpkgs(
+                    locked_ref(locked).context("Can't lock the nixpkgs input of the flake")?,
+                    locked["rev"].as_str(),
+                ),
+                None => {
+                    warn!("Flake has no nixpkgs input, evaluating with nixpkgs-unstable");
+                    EvalNixpkgs::Unstable.resolve(metadata)?
+                }
+            },
+            EvalNixpkgs::Pinned { rev, hash } => nixpkgs(
+                format!(
+                    "github:NixOS/nixpkgs/{}?narHash={}",
+                    rev,
+                    encode_query_value(hash)
+                ),
+                Some(rev),
+            ),
+            EvalNixpkgs::Locked(nixpkgs) => nixpkgs.clone(),
+        })
+    }
+}
+
+/// The path `<nixpkgs>` is looked up at for the resolved `nixpkgs`, flake
+/// references are fetched into the store
+pub(crate) fn lookup_path(
+    nixpkgs: &SourceRevision,
+    temp_store: bool,
+    extra: &[String],
+) -> Result<String> {
+    let source = &nixpkgs.source;
+    if source.starts_with('/') || source.starts_with("https://") || source.starts_with("http://") {
+        return Ok(source.to_owned());
+    }
+    let metadata = super::nix_flake_info::get_flake_metadata(source, temp_store, extra)
+        .with_context(|| format!("Failed to fetch nixpkgs {}", source))?;
+    metadata["path"]
+        .as_str()
+        .map(str::to_owned)
+        .with_context(|| format!("nix did not report the store path of nixpkgs {}", source))
+}
+
+/// The locked attributes of `input` of the flake with the given `nix flake
+/// metadata`, following `follows` declarations
+fn locked_input<'a>(metadata: &'a Value, input: &str) -> Option<&'a Value> {
+    let locks = &metadata["locks"];
+    let root = locks["root"].as_str().unwrap_or("root");
+    let node = lock_node(&locks["nodes"], root, &[input.to_owned()])?;
+    Some(&locks["nodes"][node.as_str()]["locked"]).filter(|locked| locked.is_object())
+}
+
+/// Name of the lock file node at the input path `path`, starting at `root`
+fn lock_node(nodes: &Value, root: &str, path: &[String]) -> Option<String> {
+    let mut node = root.to_owned();
+    for input in path {
+        node = match &nodes[node.as_str()]["inputs"][input.as_str()] {
+            Value::String(name) => name.to_owned(),
+            Value::Array(follows) => {
+                let follows: Option<Vec<String>> = follows
+                    .iter()
+                    .map(|input| input.as_str().map(str::to_owned))
+                    .collect();
+                lock_node(nodes, root, &follows?)?
+            }
+            _ => return None,
+        };
+    }
+    Some(node)
+}
+
+/// The nixpkgs described by the `nix flake metadata` of a nixpkgs flake reference.
+///
+/// Tarballs have no revision unless the server announced one, the narHash
+/// still pins their contents.
+fn locked(metadata: &Value) -> Option<SourceRevision> {
+    let revision = metadata["revision"]
+        .as_str()
+        .or(metadata["locked"]["rev"].as_str());
+    Some(SourceRevision {
+        source: locked_ref(&metadata["locked"])?,
+        revision: revision.map(str::to_owned),
+        nixpkgs: None,
+    })
+}
+
+/// Identifies the contents of a resolved nixpkgs in the export cache, `None`
+/// if they may differ between runs.
+///
+/// Locked flake references, such as a locked tarball, pin their contents by
+/// a narHash in the reference, other sources only by their revision.
+pub(crate) fn cache_key(nixpkgs: &SourceRevision) -> Option<String> {
+    if nixpkgs.source.contains("narHash=") {
+        Some(nixpkgs.source.clone())
+    } else {
+        nixpkgs.revision.clone()
+    }
+}
+
+/// Flake reference of the locked attributes of a lock file node or of `nix flake metadata`
+fn locked_ref(locked: &Value) -> Option<String> {
+    let attr = |name: &str| locked[name].as_str();
+    let nar_hash = encode_query_value(attr("narHash")?);
+    Some(match attr("type")? {
+        kind @ ("github" | "gitlab" | "sourcehut") => format!(
+            "{}:{}/{}/{}?narHash={}",
+            kind,
+            attr("owner")?,
+            attr("repo")?,
+            attr("rev")?,
+            nar_hash
+        ),
+        "git" => format!(
+            "git+{}?rev={}&narHash={}",
+            attr("url")?,
+            attr("rev")?,
+            nar_hash
+        ),
+        "tarball" => format!("tarball+{}?narHash={}", attr("url")?, nar_hash),
+        "path" => format!("path:{}?narHash={}", attr("path")?, nar_hash),
+        _ => return None,
+    })
+}
+
+/// Percent-encodes the characters of a base64 hash that are not safe in a query
+fn encode_query_value(value: &str) -> String {
+    value
+        .replace('+', "%2B")
+        .replace('/', "%2F")
+        .replace('=', "%3D")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const NAR_HASH: &str = "sha256-Uq6V0kSYrrvEkAQlUBm2Ql1wGgt6/iJTkBD2F9bBH/0=";
+    const NAR_HASH_QUERY: &str = "sha256-Uq6V0kSYrrvEkAQlUBm2Ql1wGgt6%2FiJTkBD2F9bBH%2F0%3D";
+
+    fn metadata() -> Value {
+        json!({
+            "locks": {
+                "nodes": {
+                    "root": {"inputs": {"nixpkgs": "nixpkgs_2", "utils": "utils"}},
+                    "utils": {"inputs": {"nixpkgs": ["nixpkgs"]}},
+                    "nixpkgs_2": {
+                        "locked": {
+                            "narHash": NAR_HASH,
+                            "owner": "NixOS",
+                            "repo": "nixpkgs",
+                            "rev": "0123456789abcdef0123456789abcdef01234567",
+                            "type": "github",
+                        },
+                    },
+                },
+                "root": "root",
+            },
+        })
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "unstable".parse::<EvalNixpkgs>().unwrap(),
+            EvalNixpkgs::Unstable
+        );
+        assert_eq!(
+            "input".parse::<EvalNixpkgs>().unwrap(),
+            EvalNixpkgs::FlakeInput
+        );
+        assert_eq!(
+            "./nixpkgs".parse::<EvalNixpkgs>().unwrap(),
+            EvalNixpkgs::Path("./nixpkgs".into())
+        );
+        assert_eq!(
+            "github:NixOS/nixpkgs/nixos-24.11"
+                .parse::<EvalNixpkgs>()
+                .unwrap(),
+            EvalNixpkgs::Flake("github:NixOS/nixpkgs/nixos-24.11".to_owned())
+        );
+        assert_eq!(
+            format!("rev:0123abcd:{}", NAR_HASH)
+                .parse::<EvalNixpkgs>()
+                .unwrap(),
+            EvalNixpkgs::Pinned {
+                rev: "0123abcd".to_owned(),
+                hash: NAR_HASH.to_owned()
+            }
+        );
+        assert!("rev:0123abcd".parse::<EvalNixpkgs>().is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            EvalNixpkgs::Unstable.resolve(&metadata()).unwrap(),
+            SourceRevision {
+                source: UNSTABLE_TARBALL.to_owned(),
+                revision: None,
+                nixpkgs: None,
+            }
+        );
+        assert_eq!(
+            EvalNixpkgs::FlakeInput.resolve(&metadata()).unwrap(),
+            SourceRevision {
+                source: format!(
+                    "github:NixOS/nixpkgs/0123456789abcdef0123456789abcdef01234567?narHash={}",
+                    NAR_HASH_QUERY
+                ),
+                revision: Some("0123456789abcdef0123456789abcdef01234567".to_owned()),
+                nixpkgs: None,
+            }
+        );
+        let pinned = EvalNixpkgs::Pinned {
+            rev: "0123456789abcdef0123456789abcdef01234567".to_owned(),
+            hash: NAR_HASH.to_owned(),
+        };
+        assert_eq!(
+            pinned.resolve(&json!({})).unwrap(),
+            EvalNixpkgs::FlakeInput.resolve(&metadata()).unwrap()
+        );
+        // flakes without nixpkgs input
+        assert_eq!(
+            EvalNixpkgs::FlakeInput.resolve(&json!({})).unwrap(),
+            EvalNixpkgs::Unstable.resolve(&json!({})).unwrap()
+        );
+
+        let dir = std::env::temp_dir().join(format!("flake-info-nixpkgs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".git-revision"), "0123abcd\n").unwrap();
+        let resolved = EvalNixpkgs::Path(dir.clone()).resolve(&metadata()).unwrap();
+        assert_eq!(resolved.revision.as_deref(), Some("0123abcd"));
+        assert_eq!(
+            lookup_path(&resolved, false, &[]).unwrap(),
+            dir.canonicalize().unwrap().display().to_string()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_locked() {
+        let tarball = json!({
+            "locked": {
+                "narHash": NAR_HASH,
+                "type": "tarball",
+                "url": "https://github.com/NixOS/nixpkgs/archive/0123abcd.tar.gz",
+            },
+        });
+        assert_eq!(
+            locked(&tarball).unwrap(),
+            SourceRevision {
+                source: format!(
+                    "tarball+https://github.com/NixOS/nixpkgs/archive/0123abcd.tar.gz?narHash={}",
+                    NAR_HASH_QUERY
+                ),
+                revision: None,
+                nixpkgs: None,
+            }
+        );
+
+        let github = json!({
+            "locked": metadata()["locks"]["nodes"]["nixpkgs_2"]["locked"],
+            "revision": "0123456789abcdef0123456789abcdef01234567",
+        });
+        assert_eq!(
+            locked(&github).unwrap(),
+            EvalNixpkgs::FlakeInput.resolve(&metadata()).unwrap()
+        );
+        assert_eq!(locked(&json!({"locked": {"type": "indirect"}})), None);
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let tarball = locked(&json!({
+            "locked": {
+                "narHash": NAR_HASH,
+                "type": "tarball",
+                "url": "https://github.com/NixOS/nixpkgs/archive/0123abcd.tar.gz",
+            },
+        }))
+        .unwrap();
+        assert_eq!(cache_key(&tarball), Some(tarball.source.clone()));
+
+        let unlocked = EvalNixpkgs::Unstable.resolve(&json!({})).unwrap();
+        assert_eq!(cache_key(&unlocked), None);
+
+        let checkout = SourceRevision {
+            source: "/home/user/nixpkgs".to_owned(),
+            revision: Some("0123abcd".to_owned()),
+            nixpkgs: None,
+        };
+        assert_eq!(cache_key(&checkout).as_deref(), Some("0123abcd"));
+        assert_eq!(
+            cache_key(&SourceRevision {
+                revision: None,
+                ..checkout
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn test_follows() {
+        let mut metadata = metadata();
+        metadata["locks"]["nodes"]["root"]["inputs"]["nixpkgs"] = json!(["utils", "nixpkgs"]);
+        metadata["locks"]["nodes"]["utils"]["inputs"]["nixpkgs"] = json!("nixpkgs_2");
+        assert_eq!(
+            locked_input(&metadata, "nixpkgs").unwrap()["rev"],
+            "0123456789abcdef0123456789abcdef01234567"
+        );
+        assert_eq!(locked_input(&metadata, "home-manager"), None);
+    }
+}
@@ -0,0 +1,224 @@
+use std::ffi::OsStr;
+use std::io::{self, Read};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{self, Child, ExitStatus, Output, Stdio};
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use command_run::Command;
+use lazy_static::lazy_static;
+use log::{error, info};
+use thiserror::Error;
+
+/// Bounds applied to every nix subprocess, see [set_limits]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// Wall-clock time after which a command and all of its children are killed
+    pub timeout: Option<Duration>,
+    /// Maximum address space of a command in bytes (`RLIMIT_AS`), inherited by its children
+    pub memory: Option<u64>,
+}
+
+lazy_static! {
+    static ref LIMITS: RwLock<Limits> = RwLock::new(Limits::default());
+}
+
+/// Sets the limits of all nix commands started from now on, in any thread
+pub fn set_limits(limits: Limits) {
+    *LIMITS.write().unwrap() = limits;
+}
+
+pub fn limits() -> Limits {
+    *LIMITS.read().unwrap()
+}
+
+#[derive(Debug, Error)]
+pub enum NixError {
+    #[error("`{command}` timed out after {}s and was killed", .timeout.as_secs())]
+    Timeout { command: String, timeout: Duration },
+
+    #[error("`{command}` failed with {status}:\n{stderr}")]
+    Failed {
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+
+    #[error("Couldn't run `{command}`: {source}")]
+    Io { command: String, source: io::Error },
+
+    #[error("Evaluating `{command}` failed:\n{stderr}")]
+    Evaluation { command: String, stderr: String },
+
+    #[error("`{command}` was not recorded in {}", .dir.display())]
+    NotRecorded { command: String, dir: PathBuf },
+}
+
+/// Delay between two checks whether a command exited
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub(super) fn command_line(command: &Command) -> String {
+    std::iter::once(command.program.as_os_str())
+        .chain(command.args.iter().map(|arg| arg.as_os_str()))
+        .map(OsStr::to_string_lossy)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn read_all(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = pipe.read_to_end(&mut buffer);
+        buffer
+    })
+}
+
+/// Runs `command` within the limits set with [set_limits], capturing its output
+pub(crate) fn run(command: &Command) -> Result<Output, NixError> {
+    run_limited(command, limits())
+}
+
+/// Starts `command` with the memory limit of `limits` in a process group of
+/// its own, see [kill_group]. Stdout and stderr are piped.
+pub(super) fn spawn(command: &Command, limits: Limits, stdin: Stdio) -> io::Result<Child> {
+    let mut std_command = process::Command::new(&command.program);
+    if command.clear_env {
+        std_command.env_clear();
+    }
+    std_command
+        .args(&command.args)
+        .envs(&command.env)
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+    if let Some(dir) = &command.dir {
+        std_command.current_dir(dir);
+    }
+    if let Some(memory) = limits.memory {
+        let limit = libc::rlimit {
+            rlim_cur: memory as libc::rlim_t,
+            rlim_max: memory as libc::rlim_t,
+        };
+        // SAFETY: setrlimit is async-signal-safe and only affects the child
+        unsafe {
+            std_command.pre_exec(move || {
+                if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    std_command.spawn()
+}
+
+/// Kills `child` and all processes it started
+pub(super) fn kill_group(child: &mut Child) {
+    // the group id is the pid of its leader, a negative pid signals the whole group
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.wait();
+}
+
+/// Runs `command` within `limits`, capturing its output.
+///
+/// The command runs in a process group of its own, which is killed as a
+/// whole on timeout. Fails with [NixError::Timeout] if the timeout expired
+/// and with [NixError::Failed] if the command exited unsuccessfully.
+fn run_limited(command: &Command, limits: Limits) -> Result<Output, NixError> {
+    let command_line = command_line(command);
+    let io_error = |source| NixError::Io {
+        command: command_line.clone(),
+        source,
+    };
+
+    if command.log_command {
+        info!("Running {}", command_line);
+    }
+    let mut child = spawn(command, limits, Stdio::null()).map_err(io_error)?;
+    let stdout = read_all(child.stdout.take().unwrap());
+    let stderr = read_all(child.stderr.take().unwrap());
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(io_error)? {
+            break status;
+        }
+        if let Some(timeout) = limits.timeout.filter(|timeout| start.elapsed() > *timeout) {
+            kill_group(&mut child);
+            return Err(NixError::Timeout {
+                command: command_line,
+                timeout,
+            });
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    let output = Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        if command.log_output_on_error {
+            error!("{} failed:\n{}", command_line, stderr);
+        }
+        return Err(NixError::Failed {
+            command: command_line,
+            status: output.status,
+            stderr,
+        });
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let output = run(&Command::with_args("sh", &["-c", "echo out; echo err >&2"])).unwrap();
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+
+        assert!(matches!(
+            run(&Command::with_args("sh", &["-c", "echo failed >&2; exit 3"])),
+            Err(NixError::Failed { stderr, .. }) if stderr == "failed\n"
+        ));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(200)),
+            memory: None,
+        };
+        let start = Instant::now();
+        let result = run_limited(
+            &Command::with_args("sh", &["-c", "sleep 30 & sleep 30"]),
+            limits,
+        );
+        assert!(matches!(result, Err(NixError::Timeout { .. })));
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let output = run_limited(&Command::with_args("echo", &["fast"]), limits).unwrap();
+        assert_eq!(output.stdout, b"fast\n");
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let limits = Limits {
+            timeout: None,
+            memory: Some(64 * 1024 * 1024),
+        };
+        let output = run_limited(&Command::with_args("sh", &["-c", "ulimit -v"]), limits).unwrap();
+        assert_eq!(output.stdout, b"65536\n");
+    }
+}
@@ -1,14 +1,28 @@
+mod eval_nixpkgs;
+mod limits;
 mod nix_check_version;
 mod nix_flake_attrs;
 mod nix_flake_info;
 mod nixpkgs_info;
+mod repl;
+mod runner;
+pub(crate) use eval_nixpkgs::cache_key;
+pub(crate) use eval_nixpkgs::lookup_path;
+pub use eval_nixpkgs::{EvalNixpkgs, UNSTABLE_TARBALL};
+pub use limits::{Limits, NixError, limits, set_limits};
 pub use nix_check_version::{NixCheckError, check_nix_version};
 pub use nix_flake_attrs::get_derivation_info;
 pub use nix_flake_info::get_flake_info;
+pub(crate) use nix_flake_info::{flake_info, get_flake_metadata};
 pub use nixpkgs_info::{
     get_home_manager_options, get_nixpkgs_info, get_nixpkgs_options, get_nixpkgs_package_services,
     get_nixpkgs_services,
 };
+pub use repl::ReplRunner;
+#[cfg(test)]
+pub(crate) use runner::test_runner;
+pub use runner::{NixRunner, RecordingRunner, ReplayRunner, SystemRunner, set_runner};
+pub(crate) use runner::{fetch, fetch_blocking, run};
 
 use anyhow::{Context, Result};
 use command_run::{Command, LogTo};
@@ -26,9 +40,7 @@ pub fn run_garbage_collection() -> Result<()> {
     command.log_to = LogTo::Log;
     command.log_output_on_error = true;
 
-    command
-        .run()
-        .with_context(|| "Failed to run garbage collection")?;
+    run(&command).with_context(|| "Failed to run garbage collection")?;
 
     Ok(())
 }
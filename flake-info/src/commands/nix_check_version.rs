@@ -12,7 +12,7 @@ pub enum NixCheckError {
     CheckError(#[from] semver::Error),
 
     #[error("Failed to run nix command: {0}")]
-    CommandError(#[from] command_run::Error),
+    CommandError(#[from] super::NixError),
 }
 
 fn compare_nix_versions(min_version: &str, actual_version: &str) -> Result<(), NixCheckError> {
@@ -35,18 +35,18 @@ fn compare_nix_versions(min_version: &str, actual_version: &str) -> Result<(), N
     Ok(())
 }
 
-pub fn check_nix_version(min_version: &str) -> Result<(), NixCheckError> {
+/// Fails if the installed nix is older than `min_version`, returns the installed version otherwise
+pub fn check_nix_version(min_version: &str) -> Result<String, NixCheckError> {
     info!("Checking nix version");
 
     let mut command =
         Command::with_args("nix", &["eval", "--raw", "--expr", "builtins.nixVersion"]);
     command.log_command = false;
     command.enable_capture();
-    let output = command.run()?;
-    return compare_nix_versions(
-        min_version,
-        output.stdout_string_lossy().into_owned().as_str(),
-    );
+    let output = super::run(&command)?;
+    let version = String::from_utf8_lossy(&output).into_owned();
+    compare_nix_versions(min_version, &version)?;
+    Ok(version)
 }
 
 #[cfg(test)]
@@ -14,23 +14,44 @@ const ARGS: [&str; 4] = [
 
 /// Uses `nix` to fetch the provided flake and read general information
 /// about it using `nix flake info`
+///
+/// `nixpkgs` is the path `<nixpkgs>` is looked up at, see [super::EvalNixpkgs]
 pub fn get_derivation_info<T: AsRef<str> + Display>(
     flake_ref: T,
     kind: Kind,
     temp_store: bool,
     extra: &[String],
+    nixpkgs: &str,
 ) -> Result<Vec<FlakeEntry>> {
+    let command = derivation_info_command(flake_ref.as_ref(), kind, temp_store, extra, nixpkgs)?;
+
+    let parsed: Result<Vec<FlakeEntry>> = super::run(&command)
+        .with_context(|| format!("Failed to gather information about {}", flake_ref))
+        .and_then(|o| {
+            let output = &*String::from_utf8_lossy(&o);
+            let de = &mut Deserializer::from_str(output);
+            serde_path_to_error::deserialize(de)
+                .with_context(|| format!("Failed to analyze flake {}", flake_ref))
+        });
+    parsed
+}
+
+/// The `nix eval` of the extraction script run by [get_derivation_info]
+pub(super) fn derivation_info_command(
+    flake_ref: &str,
+    kind: Kind,
+    temp_store: bool,
+    extra: &[String],
+    nixpkgs: &str,
+) -> Result<Command> {
     let mut command = Command::with_args("nix", ARGS.iter());
     command
         .env
         .insert("NIXPKGS_ALLOW_UNSUPPORTED_SYSTEM".into(), "1".into());
     command.add_arg_pair("-f", super::EXTRACT_SCRIPT.clone());
-    command.add_arg_pair(
-        "-I",
-        "nixpkgs=https://github.com/NixOS/nixpkgs/archive/refs/heads/nixpkgs-unstable.tar.gz",
-    );
-    command.add_args(["--override-flake", "input-flake", flake_ref.as_ref()].iter());
-    command.add_args(["--argstr", "flake", flake_ref.as_ref()].iter());
+    command.add_arg_pair("-I", format!("nixpkgs={}", nixpkgs));
+    command.add_args(["--override-flake", "input-flake", flake_ref].iter());
+    command.add_args(["--argstr", "flake", flake_ref].iter());
     command.add_arg(kind.as_ref());
     if temp_store {
         let temp_store_path = PathBuf::from("/tmp/flake-info-store");
@@ -44,15 +65,5 @@ pub fn get_derivation_info<T: AsRef<str> + Display>(
     command.enable_capture();
     command.log_to = LogTo::Log;
     command.log_output_on_error = true;
-
-    let parsed: Result<Vec<FlakeEntry>> = command
-        .run()
-        .with_context(|| format!("Failed to gather information about {}", flake_ref))
-        .and_then(|o| {
-            let output = &*o.stdout_string_lossy();
-            let de = &mut Deserializer::from_str(output);
-            serde_path_to_error::deserialize(de)
-                .with_context(|| format!("Failed to analyze flake {}", flake_ref))
-        });
-    parsed
+    Ok(command)
 }
@@ -12,9 +12,26 @@ pub fn get_flake_info<T: AsRef<str> + Display>(
     temp_store: bool,
     extra: &[String],
 ) -> Result<Flake> {
+    let metadata = get_flake_metadata(flake_ref.as_ref(), temp_store, extra)
+        .with_context(|| format!("Failed to gather information about {}", flake_ref))?;
+    flake_info(metadata)
+}
+
+/// Reads the [Flake] from the output of `nix flake metadata`
+pub(crate) fn flake_info(metadata: serde_json::Value) -> Result<Flake> {
+    let deserialized: Flake = serde_json::from_value(metadata)?;
+    Ok(deserialized.resolve_name())
+}
+
+/// The output of `nix flake metadata` for `flake_ref`
+pub(crate) fn get_flake_metadata(
+    flake_ref: &str,
+    temp_store: bool,
+    extra: &[String],
+) -> Result<serde_json::Value> {
     let args = ["flake", "metadata", "--json", "--no-write-lock-file"];
     let mut command = Command::with_args("nix", args);
-    command.add_arg(flake_ref.as_ref());
+    command.add_arg(flake_ref);
     if temp_store {
         let temp_store_path = PathBuf::from("/tmp/flake-info-store");
         if !temp_store_path.exists() {
@@ -28,12 +45,8 @@ pub fn get_flake_info<T: AsRef<str> + Display>(
     command.log_to = LogTo::Log;
     command.log_output_on_error = true;
 
-    command
-        .run()
-        .with_context(|| format!("Failed to gather information about {}", flake_ref))
-        .and_then(|o| {
-            let deserialized: Result<Flake, _> =
-                serde_json::de::from_str(o.stdout_string_lossy().to_string().as_str());
-            Ok(deserialized?.resolve_name())
-        })
+    let output = super::run(&command)?;
+    Ok(serde_json::de::from_str(
+        String::from_utf8_lossy(&output).as_ref(),
+    )?)
 }
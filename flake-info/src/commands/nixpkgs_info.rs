@@ -35,14 +35,15 @@ pub fn get_nixpkgs_info(
     });
     log::info!("Fetching packages from {}", url);
 
-    let response = reqwest::blocking::Client::new()
-        .get(&url)
-        .send()
-        .with_context(|| format!("Failed to download {}", url))?
-        .error_for_status()
-        .with_context(|| format!("HTTP error fetching {}", url))?;
-
-    let body = response.bytes()?;
+    let body = super::fetch_blocking(&url, || {
+        let response = reqwest::blocking::Client::new()
+            .get(&url)
+            .send()
+            .with_context(|| format!("Failed to download {}", url))?
+            .error_for_status()
+            .with_context(|| format!("HTTP error fetching {}", url))?;
+        Ok(response.bytes()?.to_vec())
+    })?;
     let info: PackagesInfo =
         serde_json::from_slice(&body).with_context(|| "Could not parse channel packages.json")?;
 
@@ -88,11 +89,10 @@ pub fn get_nixpkgs_package_services(nixpkgs: &Source) -> Result<HashMap<String,
     command.log_to = LogTo::Log;
     command.log_output_on_error = true;
 
-    let cow = command
-        .run()
+    let output = super::run(&command)
         .with_context(|| "Failed to gather modular service mapping for packages")?;
 
-    let output = &*cow.stdout_string_lossy();
+    let output = &*String::from_utf8_lossy(&output);
     let map: HashMap<String, Vec<String>> =
         serde_json::from_str(output).with_context(|| "Could not parse package-services map")?;
     Ok(map)
@@ -113,11 +113,10 @@ pub fn get_nixpkgs_programs(nixpkgs: &Nixpkgs) -> Result<HashMap<String, HashSet
     command.log_to = LogTo::Log;
     command.log_output_on_error = true;
 
-    let cow = command
-        .run()
+    let output = super::run(&command)
         .with_context(|| "Failed to gather information about nixpkgs programs")?;
 
-    let output = &*cow.stdout_string_lossy();
+    let output = &*String::from_utf8_lossy(&output);
     let programs_db: &str = serde_json::from_str(output)?;
     let conn = sqlite::open(programs_db)?;
     let cur = conn
@@ -147,11 +146,10 @@ pub fn get_nixpkgs_options(nixpkgs: &Source) -> Result<Vec<NixpkgsEntry>> {
     command.log_to = LogTo::Log;
     command.log_output_on_error = true;
 
-    let cow = command
-        .run()
+    let output = super::run(&command)
         .with_context(|| "Failed to gather information about nixpkgs options")?;
 
-    let output = &*cow.stdout_string_lossy();
+    let output = &*String::from_utf8_lossy(&output);
     let de = &mut serde_json::Deserializer::from_str(output);
     let attr_set: Vec<NixOption> =
         serde_path_to_error::deserialize(de).with_context(|| "Could not parse options")?;
@@ -169,11 +167,10 @@ pub fn get_nixpkgs_services(nixpkgs: &Source) -> Result<Vec<NixpkgsEntry>> {
     command.log_to = LogTo::Log;
     command.log_output_on_error = true;
 
-    let cow = command
-        .run()
+    let output = super::run(&command)
         .with_context(|| "Failed to gather information about nixpkgs modular services")?;
 
-    let output = &*cow.stdout_string_lossy();
+    let output = &*String::from_utf8_lossy(&output);
     let de = &mut serde_json::Deserializer::from_str(output);
     let attr_set: Vec<NixOption> =
         serde_path_to_error::deserialize(de).with_context(|| "Could not parse services")?;
@@ -206,11 +203,10 @@ pub fn get_home_manager_options(nixpkgs: &Source) -> Result<Vec<NixpkgsEntry>> {
     command.log_to = LogTo::Log;
     command.log_output_on_error = true;
 
-    let cow = command
-        .run()
+    let output = super::run(&command)
         .with_context(|| "Failed to gather information about home-manager options")?;
 
-    let output = &*cow.stdout_string_lossy();
+    let output = &*String::from_utf8_lossy(&output);
     let de = &mut serde_json::Deserializer::from_str(output);
     let attr_set: Vec<NixOption> = serde_path_to_error::deserialize(de)
         .with_context(|| "Could not parse home-manager options")?;
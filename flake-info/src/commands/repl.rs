@@ -0,0 +1,552 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Stdio};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Instant;
+
+use command_run::Command;
+use log::info;
+
+use super::limits::{self, command_line, kill_group};
+use super::{NixError, NixRunner, SystemRunner};
+
+/// Name the nixpkgs instance shared by all evaluations of a session is bound to
+const PKGS: &str = "flakeInfoPkgs";
+
+/// Prompt nix repl may print in front of results
+const PROMPT: &str = "nix-repl> ";
+
+/// Evaluations after which a session is replaced, so that memory it holds on
+/// to for earlier flakes is freed
+const MAX_EVALUATIONS: usize = 50;
+
+/// Evaluates the extraction script in long-lived `nix repl` sessions.
+///
+/// Every `nix eval` of the extraction script spawns a new nix process, which
+/// parses the script and instantiates nixpkgs again. This runner serves these
+/// evaluations from `nix repl` sessions instead, which import nixpkgs once and
+/// stay around for later evaluations with the same nixpkgs, store and
+/// environment, for example of the next member of a group.
+///
+/// At most `sessions` sessions run at once, the least recently used idle
+/// session is killed to make room for one with a different nixpkgs, store or
+/// environment. A session is replaced after [MAX_EVALUATIONS] evaluations,
+/// or once its address space exceeds three quarters of the memory limit.
+///
+/// Results are identical to those of `nix eval`. All other commands, and
+/// evaluations with extra arguments to nix, are run by [SystemRunner].
+pub struct ReplRunner {
+    runner: SystemRunner,
+    sessions: Mutex<Pool<Session>>,
+}
+
+impl ReplRunner {
+    /// Runs up to `sessions` sessions, usually one per thread evaluating in parallel
+    pub fn new(sessions: usize) -> Self {
+        ReplRunner {
+            runner: SystemRunner,
+            sessions: Mutex::new(Pool::new(sessions)),
+        }
+    }
+}
+
+impl NixRunner for ReplRunner {
+    fn run(&self, command: &Command) -> Result<Vec<u8>, NixError> {
+        let evaluation = match Evaluation::parse(command) {
+            Some(evaluation) => evaluation,
+            None => return self.runner.run(command),
+        };
+        let command_line = command_line(command);
+        if command.log_command {
+            info!("Evaluating {} in nix repl", command_line);
+        }
+
+        let idle = self.sessions.lock().unwrap().take(&evaluation.session);
+        let mut session = match idle {
+            Some(session) => session,
+            None => {
+                let evicted = self.sessions.lock().unwrap().reserve();
+                // killed outside of the lock
+                drop(evicted);
+                Session::start(&evaluation.session).map_err(|source| {
+                    self.sessions
+                        .lock()
+                        .unwrap()
+                        .release(&evaluation.session, None);
+                    NixError::Io {
+                        command: command_line.clone(),
+                        source,
+                    }
+                })?
+            }
+        };
+
+        let result = session.evaluate(&evaluation.expression(), &command_line);
+        // sessions that timed out, broke down or are worn out are killed when dropped
+        let reusable =
+            matches!(result, Ok(_) | Err(NixError::Evaluation { .. })) && !session.worn_out();
+        let session = reusable.then_some(session);
+        self.sessions
+            .lock()
+            .unwrap()
+            .release(&evaluation.session, session);
+        result
+    }
+}
+
+/// Sessions of a [ReplRunner], bounded in number
+struct Pool<S> {
+    max: usize,
+    /// Idle sessions, least recently used first
+    idle: Vec<(SessionKey, S)>,
+    /// Number of idle sessions and sessions evaluating
+    running: usize,
+}
+
+impl<S> Pool<S> {
+    fn new(max: usize) -> Self {
+        Pool {
+            max: max.max(1),
+            idle: Vec::new(),
+            running: 0,
+        }
+    }
+
+    /// Removes an idle session started with `key`
+    fn take(&mut self, key: &SessionKey) -> Option<S> {
+        let index = self.idle.iter().position(|(idle, _)| idle == key)?;
+        Some(self.idle.remove(index).1)
+    }
+
+    /// Counts a session that is about to be started, returns the least
+    /// recently used idle session if it has to be killed to make room
+    fn reserve(&mut self) -> Option<S> {
+        let evicted = if self.running >= self.max && !self.idle.is_empty() {
+            self.running -= 1;
+            Some(self.idle.remove(0).1)
+        } else {
+            None
+        };
+        self.running += 1;
+        evicted
+    }
+
+    /// Returns a session taken or reserved before, `None` if it is gone
+    fn release(&mut self, key: &SessionKey, session: Option<S>) {
+        match session {
+            Some(session) => self.idle.push((key.clone(), session)),
+            None => self.running -= 1,
+        }
+    }
+}
+
+/// What a session is started with, evaluations sharing it can use the same session
+#[derive(Debug, Clone, PartialEq)]
+struct SessionKey {
+    /// Arguments to `nix repl`
+    args: Vec<String>,
+    /// Sorted environment variables set for nix
+    env: Vec<(String, String)>,
+}
+
+/// An evaluation of the extraction script, as built by
+/// [derivation_info_command](super::nix_flake_attrs::derivation_info_command)
+#[derive(Debug, PartialEq)]
+struct Evaluation {
+    session: SessionKey,
+    /// Flake the script evaluates, passed to `--override-flake input-flake`
+    input_flake: String,
+    /// `--argstr` arguments to the script
+    args: Vec<(String, String)>,
+    /// Attribute of the script to evaluate
+    attribute: String,
+}
+
+impl Evaluation {
+    /// Reads the evaluation from `command`, `None` if it is no evaluation of
+    /// the extraction script that can be run in a session
+    fn parse(command: &Command) -> Option<Evaluation> {
+        let argv = super::runner::argv(command);
+        let script = super::runner::argv(&Command::new(&*super::EXTRACT_SCRIPT)).remove(0);
+        let mut argv = argv.into_iter();
+        if argv.next()? != "nix" || argv.next()? != "eval" {
+            return None;
+        }
+
+        let mut session_args = Vec::new();
+        let mut input_flake = None;
+        let mut args = Vec::new();
+        let mut attribute = None;
+        let mut json = false;
+        let mut file = None;
+        while let Some(arg) = argv.next() {
+            match arg.as_str() {
+                "--json" => json = true,
+                // has no effect on getFlake
+                "--no-write-lock-file" => {}
+                "--no-allow-import-from-derivation" => session_args.push(arg),
+                "-f" | "--file" => file = Some(argv.next()?),
+                "-I" | "--store" => {
+                    session_args.push(arg);
+                    session_args.push(argv.next()?);
+                }
+                "--override-flake" => {
+                    if argv.next()? != "input-flake" {
+                        return None;
+                    }
+                    input_flake = Some(argv.next()?);
+                }
+                "--argstr" => args.push((argv.next()?, argv.next()?)),
+                _ if !arg.starts_with('-') && attribute.is_none() => attribute = Some(arg),
+                _ => return None,
+            }
+        }
+        if !json || file? != script {
+            return None;
+        }
+
+        let mut env: Vec<(String, String)> = command
+            .env
+            .iter()
+            .map(|(key, value)| {
+                (
+                    key.to_string_lossy().into_owned(),
+                    value.to_string_lossy().into_owned(),
+                )
+            })
+            .collect();
+        env.sort();
+        Some(Evaluation {
+            session: SessionKey {
+                args: session_args,
+                env,
+            },
+            input_flake: input_flake?,
+            args,
+            attribute: attribute?,
+        })
+    }
+
+    /// Expression evaluating to the JSON `nix eval --json` would print
+    fn expression(&self) -> String {
+        let script = super::EXTRACT_SCRIPT.to_string_lossy();
+        let args: String = self
+            .args
+            .iter()
+            .map(|(name, value)| format!(" {} = {};", quote(name), quote(value)))
+            .collect();
+        format!(
+            "builtins.toJSON (import {} {{ pkgs = {}; input-flake = {};{} }}).{}",
+            quote(&script),
+            PKGS,
+            quote(&self.input_flake),
+            args,
+            quote(&self.attribute),
+        )
+    }
+}
+
+/// Nix string literal of `value`
+fn quote(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t");
+    format!("\"{}\"", escaped)
+}
+
+/// Value of the nix string literal `literal`, as printed by nix repl
+fn unquote(literal: &str) -> Option<String> {
+    let inner = literal.strip_prefix('"')?.strip_suffix('"')?;
+    let mut value = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                'r' => value.push('\r'),
+                't' => value.push('\t'),
+                escaped => value.push(escaped),
+            },
+            '"' => return None,
+            c => value.push(c),
+        }
+    }
+    Some(value)
+}
+
+enum Line {
+    Stdout(String),
+    Stderr(String),
+}
+
+fn read_lines(pipe: impl Read + Send + 'static, sender: Sender<Line>, line: fn(String) -> Line) {
+    thread::spawn(move || {
+        for text in BufReader::new(pipe).lines() {
+            match text {
+                Ok(text) if sender.send(line(text)).is_ok() => {}
+                _ => break,
+            }
+        }
+    });
+}
+
+/// A running `nix repl`
+struct Session {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<Line>,
+    evaluations: usize,
+}
+
+impl Session {
+    fn start(key: &SessionKey) -> io::Result<Session> {
+        let mut command = Command::with_args("nix", &["repl"]);
+        command.add_args(&key.args);
+        for (name, value) in &key.env {
+            command.env.insert(name.into(), value.into());
+        }
+        command.env.insert("NO_COLOR".into(), "1".into());
+
+        let mut child = limits::spawn(&command, limits::limits(), Stdio::piped())?;
+        let (sender, lines) = mpsc::channel();
+        read_lines(child.stdout.take().unwrap(), sender.clone(), Line::Stdout);
+        read_lines(child.stderr.take().unwrap(), sender, Line::Stderr);
+        let mut stdin = child.stdin.take().unwrap();
+        writeln!(stdin, "{} = import <nixpkgs> {{ }}", PKGS)?;
+
+        Ok(Session {
+            child,
+            stdin,
+            lines,
+            evaluations: 0,
+        })
+    }
+
+    /// Evaluates `expression`, which must evaluate to a string, and returns its value.
+    ///
+    /// The value is traced behind a prefix, as nix prints traced strings as
+    /// they are, while the way nix repl prints values changes between
+    /// versions. The expression is followed by a marker that nix repl prints
+    /// both to stdout and, as a trace, to stderr once it is done, everything
+    /// printed until then belongs to the evaluation.
+    fn evaluate(&mut self, expression: &str, command_line: &str) -> Result<Vec<u8>, NixError> {
+        let timeout = limits::limits().timeout;
+        let start = Instant::now();
+        self.evaluations += 1;
+        let marker = format!("flake-info-{}", self.evaluations);
+        let prefix = format!("{}-result ", marker);
+        writeln!(
+            self.stdin,
+            "builtins.trace ({} + {}) null",
+            quote(&prefix),
+            expression
+        )
+        .and_then(|_| writeln!(self.stdin, "builtins.trace {0} {0}", quote(&marker)))
+        .and_then(|_| self.stdin.flush())
+        .map_err(|source| NixError::Io {
+            command: command_line.to_owned(),
+            source,
+        })?;
+
+        let trace = format!("trace: {}", marker);
+        let result = format!("trace: {}", prefix);
+        let mut value = None;
+        let mut stderr = Vec::new();
+        let (mut stdout_done, mut stderr_done) = (false, false);
+        while !(stdout_done && stderr_done) {
+            let line = match timeout {
+                Some(timeout) => self
+                    .lines
+                    .recv_timeout(timeout.saturating_sub(start.elapsed())),
+                None => self.lines.recv().map_err(RecvTimeoutError::from),
+            };
+            match line {
+                Ok(Line::Stdout(line)) => {
+                    let line = line.trim_start_matches(PROMPT).trim_end();
+                    if unquote(line).is_some_and(|text| text == marker) {
+                        stdout_done = true;
+                    }
+                }
+                Ok(Line::Stderr(line)) if line.trim_end() == trace => stderr_done = true,
+                Ok(Line::Stderr(line)) if line.starts_with(&result) => {
+                    value = Some(line[result.len()..].to_owned());
+                }
+                Ok(Line::Stderr(line)) => stderr.push(line),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(NixError::Timeout {
+                        command: command_line.to_owned(),
+                        timeout: timeout.unwrap(),
+                    });
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    let status = self.child.wait().map_err(|source| NixError::Io {
+                        command: command_line.to_owned(),
+                        source,
+                    })?;
+                    return Err(NixError::Failed {
+                        command: command_line.to_owned(),
+                        status,
+                        stderr: stderr.join("\n"),
+                    });
+                }
+            }
+        }
+
+        value.map(String::into_bytes).ok_or(NixError::Evaluation {
+            command: command_line.to_owned(),
+            stderr: stderr.join("\n"),
+        })
+    }
+}
+
+impl Session {
+    /// Whether the session should be replaced instead of being reused
+    fn worn_out(&self) -> bool {
+        if self.evaluations >= MAX_EVALUATIONS {
+            return true;
+        }
+        match (limits::limits().memory, address_space(self.child.id())) {
+            (Some(limit), Some(size)) => size > limit / 4 * 3,
+            _ => false,
+        }
+    }
+}
+
+/// Size of the address space of process `pid` in bytes, which `RLIMIT_AS` bounds
+fn address_space(pid: u32) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmSize:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        kill_group(&mut self.child);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::import::Kind;
+
+    fn command(extra: &[String]) -> Command {
+        super::super::nix_flake_attrs::derivation_info_command(
+            "github:serokell/deploy-rs",
+            Kind::All,
+            false,
+            extra,
+            super::super::UNSTABLE_TARBALL,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let evaluation = Evaluation::parse(&command(&[])).unwrap();
+        assert_eq!(
+            evaluation.session,
+            SessionKey {
+                args: vec![
+                    "--no-allow-import-from-derivation".to_owned(),
+                    "-I".to_owned(),
+                    "nixpkgs=https://github.com/NixOS/nixpkgs/archive/refs/heads/nixpkgs-unstable.tar.gz".to_owned(),
+                ],
+                env: vec![("NIXPKGS_ALLOW_UNSUPPORTED_SYSTEM".to_owned(), "1".to_owned())],
+            }
+        );
+        assert_eq!(evaluation.input_flake, "github:serokell/deploy-rs");
+        assert_eq!(
+            evaluation.args,
+            [("flake".to_owned(), "github:serokell/deploy-rs".to_owned())]
+        );
+        assert_eq!(evaluation.attribute, "all");
+        assert_eq!(
+            evaluation.expression(),
+            format!(
+                "builtins.toJSON (import \"{}\" {{ pkgs = flakeInfoPkgs; input-flake = \"github:serokell/deploy-rs\"; \"flake\" = \"github:serokell/deploy-rs\"; }}).\"all\"",
+                super::super::EXTRACT_SCRIPT.display()
+            )
+        );
+
+        // extra arguments may change the evaluation in ways a session can't reproduce
+        assert!(Evaluation::parse(&command(&["--impure".to_owned()])).is_none());
+        assert!(
+            Evaluation::parse(&Command::with_args(
+                "nix",
+                &["eval", "--json", "nixpkgs#hello"]
+            ))
+            .is_none()
+        );
+        assert!(
+            Evaluation::parse(&Command::with_args("nix", &["flake", "metadata", "--json"]))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_pool() {
+        let key = |name: &str| SessionKey {
+            args: vec![name.to_owned()],
+            env: Vec::new(),
+        };
+        let mut pool = Pool::new(2);
+        assert_eq!(pool.reserve(), None);
+        assert_eq!(pool.reserve(), None);
+        pool.release(&key("a"), Some(1));
+        pool.release(&key("b"), Some(2));
+        assert_eq!(pool.take(&key("c")), None);
+
+        // the least recently used session makes room for a new one
+        assert_eq!(pool.reserve(), Some(1));
+        pool.release(&key("c"), Some(3));
+        assert_eq!(pool.take(&key("a")), None);
+        assert_eq!(pool.take(&key("b")), Some(2));
+
+        // sessions that are gone free their place
+        pool.release(&key("b"), None);
+        assert_eq!(pool.reserve(), None);
+        assert_eq!(pool.running, 2);
+    }
+
+    /// Evaluates a flake through [SystemRunner] and [ReplRunner]
+    #[test]
+    #[ignore = "needs nix and network access to fetch <nixpkgs>"]
+    fn test_same_as_nix_eval() {
+        let command = command(&[]);
+        let json = |output: Vec<u8>| serde_json::from_slice::<serde_json::Value>(&output).unwrap();
+        let expected = json(SystemRunner.run(&command).unwrap());
+
+        let runner = ReplRunner::new(1);
+        // the second evaluation reuses the session of the first
+        for _ in 0..2 {
+            assert_eq!(json(runner.run(&command).unwrap()), expected);
+        }
+        assert_eq!(runner.sessions.lock().unwrap().idle.len(), 1);
+    }
+
+    #[test]
+    fn test_quote() {
+        for value in [
+            "",
+            "plain",
+            "\"quoted\" \\ ${interpolated} $ {",
+            "lines\n\r\t",
+        ] {
+            assert_eq!(unquote(&quote(value)).as_deref(), Some(value));
+        }
+        assert_eq!(quote("${x}"), "\"\\${x}\"");
+        assert_eq!(
+            unquote(r#""{\"name\":\"\${x}\"}""#).as_deref(),
+            Some(r#"{"name":"${x}"}"#)
+        );
+        assert_eq!(unquote("nix-repl> "), None);
+        assert_eq!(unquote(r#""a" + "b""#), None);
+    }
+}
@@ -0,0 +1,509 @@
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+use anyhow::Context;
+use command_run::Command;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use super::NixError;
+
+/// Runs the nix commands built by [crate::commands].
+///
+/// [SystemRunner] runs them, [RecordingRunner] records their outputs and
+/// [ReplayRunner] serves outputs recorded earlier so that imports can run
+/// without nix or network access. All commands, as well as the HTTP requests
+/// made during an import, go through the runner set with [set_runner].
+pub trait NixRunner: Send + Sync {
+    /// Runs `command`, returning its stdout
+    fn run(&self, command: &Command) -> Result<Vec<u8>, NixError>;
+
+    /// Body to use for a GET request to `url` instead of fetching it
+    fn replay_fetch(&self, _url: &str) -> Option<Result<Vec<u8>, NixError>> {
+        None
+    }
+
+    /// Called with the body fetched from `url`
+    fn fetched(&self, _url: &str, _body: &[u8]) -> Result<(), NixError> {
+        Ok(())
+    }
+}
+
+impl<R: NixRunner + ?Sized> NixRunner for Box<R> {
+    fn run(&self, command: &Command) -> Result<Vec<u8>, NixError> {
+        (**self).run(command)
+    }
+
+    fn replay_fetch(&self, url: &str) -> Option<Result<Vec<u8>, NixError>> {
+        (**self).replay_fetch(url)
+    }
+
+    fn fetched(&self, url: &str, body: &[u8]) -> Result<(), NixError> {
+        (**self).fetched(url, body)
+    }
+}
+
+/// Runs commands as subprocesses, within the [Limits](super::Limits) set with [set_limits](super::set_limits)
+pub struct SystemRunner;
+
+impl NixRunner for SystemRunner {
+    fn run(&self, command: &Command) -> Result<Vec<u8>, NixError> {
+        Ok(super::limits::run(command)?.stdout)
+    }
+}
+
+/// File in a fixtures directory listing the recorded commands
+pub(crate) const RECORDINGS_FILE: &str = "commands.json";
+
+/// What an output was recorded for
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Key {
+    /// A command, see [argv]
+    Argv(Vec<String>),
+    /// A GET request
+    Url(String),
+}
+
+impl Key {
+    fn describe(&self) -> String {
+        match self {
+            Key::Argv(argv) => argv.join(" "),
+            Key::Url(url) => format!("GET {}", url),
+        }
+    }
+}
+
+/// A command or request and the file its output was recorded in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Recording {
+    #[serde(flatten)]
+    pub key: Key,
+    /// Stdout of the command or body of the response, relative to the fixtures directory
+    pub stdout: PathBuf,
+}
+
+fn read_recordings(dir: &Path) -> anyhow::Result<Vec<Recording>> {
+    let path = dir.join(RECORDINGS_FILE);
+    let file = fs::File::open(&path)
+        .with_context(|| format!("Couldn't open recordings {}", path.display()))?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("Invalid recordings {}", path.display()))
+}
+
+/// Runs commands with another runner and records their outputs in a fixtures
+/// directory that [ReplayRunner] can serve them from.
+///
+/// Only successful commands and requests are recorded, each once.
+pub struct RecordingRunner {
+    dir: PathBuf,
+    runner: Box<dyn NixRunner>,
+    recordings: Mutex<Vec<Recording>>,
+}
+
+impl RecordingRunner {
+    /// Records into `dir`, which is created if needed. Recordings already in
+    /// `dir` are kept.
+    pub fn create(dir: &Path, runner: impl NixRunner + 'static) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Couldn't create recordings directory {}", dir.display()))?;
+        let recordings = if dir.join(RECORDINGS_FILE).exists() {
+            read_recordings(dir)?
+        } else {
+            Vec::new()
+        };
+        Ok(RecordingRunner {
+            dir: dir.to_owned(),
+            runner: Box::new(runner),
+            recordings: Mutex::new(recordings),
+        })
+    }
+
+    fn record(&self, key: Key, output: &[u8]) -> Result<(), NixError> {
+        let io_error = |source| NixError::Io {
+            command: key.describe(),
+            source,
+        };
+        let mut recordings = self.recordings.lock().unwrap();
+        if recordings.iter().any(|recording| recording.key == key) {
+            return Ok(());
+        }
+        let stdout = PathBuf::from(format!("{:04}.out", recordings.len()));
+        fs::write(self.dir.join(&stdout), output).map_err(io_error)?;
+        recordings.push(Recording {
+            key: key.clone(),
+            stdout,
+        });
+        // rewritten after every command so that aborted imports can be replayed as far as they got
+        let json = serde_json::to_vec_pretty(&*recordings).map_err(|e| io_error(e.into()))?;
+        fs::write(self.dir.join(RECORDINGS_FILE), json).map_err(io_error)
+    }
+}
+
+impl NixRunner for RecordingRunner {
+    fn run(&self, command: &Command) -> Result<Vec<u8>, NixError> {
+        let stdout = self.runner.run(command)?;
+        self.record(Key::Argv(argv(command)), &stdout)?;
+        Ok(stdout)
+    }
+
+    fn fetched(&self, url: &str, body: &[u8]) -> Result<(), NixError> {
+        self.record(Key::Url(url.to_owned()), body)
+    }
+}
+
+/// Serves the outputs of commands and requests from a fixtures directory.
+///
+/// The directory holds a `commands.json` file with a list of
+/// `{"argv": [...], "stdout": "<file>"}` and `{"url": "...", "stdout": "<file>"}`
+/// entries, the output of a command is the content of the file recorded for
+/// the same [argv], the body of a request the one recorded for its URL.
+pub struct ReplayRunner {
+    dir: PathBuf,
+    recordings: Vec<Recording>,
+}
+
+impl ReplayRunner {
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        Ok(ReplayRunner {
+            dir: dir.to_owned(),
+            recordings: read_recordings(dir)?,
+        })
+    }
+
+    fn recorded(&self, key: Key) -> Result<Vec<u8>, NixError> {
+        let recording = self
+            .recordings
+            .iter()
+            .find(|recording| recording.key == key)
+            .ok_or_else(|| NixError::NotRecorded {
+                command: key.describe(),
+                dir: self.dir.clone(),
+            })?;
+        fs::read(self.dir.join(&recording.stdout)).map_err(|source| NixError::Io {
+            command: key.describe(),
+            source,
+        })
+    }
+}
+
+impl NixRunner for ReplayRunner {
+    fn run(&self, command: &Command) -> Result<Vec<u8>, NixError> {
+        self.recorded(Key::Argv(argv(command)))
+    }
+
+    fn replay_fetch(&self, url: &str) -> Option<Result<Vec<u8>, NixError>> {
+        Some(self.recorded(Key::Url(url.to_owned())))
+    }
+}
+
+/// Program and arguments of `command`, identifying its recording.
+///
+/// Paths into the data directory of flake-info start with `$DATADIR` instead,
+/// so that recordings can be replayed by any installation.
+pub(crate) fn argv(command: &Command) -> Vec<String> {
+    let datadir = crate::DATADIR.to_string_lossy();
+    std::iter::once(command.program.as_os_str())
+        .chain(command.args.iter().map(|arg| arg.as_os_str()))
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            match arg.strip_prefix(datadir.as_ref()) {
+                Some(path) => format!("$DATADIR{}", path),
+                None => arg.into_owned(),
+            }
+        })
+        .collect()
+}
+
+lazy_static! {
+    static ref RUNNER: RwLock<Arc<dyn NixRunner>> = RwLock::new(Arc::new(SystemRunner));
+}
+
+/// Runs all nix commands started from now on, in any thread, with `runner`
+pub fn set_runner(runner: impl NixRunner + 'static) {
+    *RUNNER.write().unwrap() = Arc::new(runner);
+}
+
+fn runner() -> Arc<dyn NixRunner> {
+    RUNNER.read().unwrap().clone()
+}
+
+#[cfg(test)]
+lazy_static! {
+    /// Held by tests while they replace the global runner
+    static ref TEST_RUNNER: Mutex<()> = Mutex::new(());
+}
+
+/// Runner of a test, set by [test_runner] until it is dropped
+#[cfg(test)]
+pub(crate) struct RunnerGuard {
+    previous: Arc<dyn NixRunner>,
+    _lock: std::sync::MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+impl Drop for RunnerGuard {
+    fn drop(&mut self) {
+        *RUNNER.write().unwrap_or_else(PoisonError::into_inner) = self.previous.clone();
+    }
+}
+
+/// Sets `runner` until the returned guard is dropped, also if the test panics.
+///
+/// Tests running imports through the global runner take the guard, so that
+/// they are serialized instead of running with each other's runners.
+#[cfg(test)]
+pub(crate) fn test_runner(runner: impl NixRunner + 'static) -> RunnerGuard {
+    let lock = TEST_RUNNER.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut current = RUNNER.write().unwrap_or_else(PoisonError::into_inner);
+    let previous = std::mem::replace(&mut *current, Arc::new(runner));
+    RunnerGuard {
+        previous,
+        _lock: lock,
+    }
+}
+
+/// Runs `command` with the current [NixRunner], returning its stdout
+pub(crate) fn run(command: &Command) -> Result<Vec<u8>, NixError> {
+    runner().run(command)
+}
+
+/// Body of a GET request to `url`, as replayed by the current [NixRunner] or
+/// fetched with `fetch`
+pub(crate) fn fetch_blocking(
+    url: &str,
+    fetch: impl FnOnce() -> anyhow::Result<Vec<u8>>,
+) -> anyhow::Result<Vec<u8>> {
+    let runner = runner();
+    if let Some(body) = runner.replay_fetch(url) {
+        return Ok(body?);
+    }
+    let body = fetch()?;
+    runner.fetched(url, &body)?;
+    Ok(body)
+}
+
+/// Like [fetch_blocking], `fetch` is only awaited if the body is not replayed
+pub(crate) async fn fetch(
+    url: &str,
+    fetch: impl Future<Output = anyhow::Result<Vec<u8>>>,
+) -> anyhow::Result<Vec<u8>> {
+    let runner = runner();
+    if let Some(body) = runner.replay_fetch(url) {
+        return Ok(body?);
+    }
+    let body = fetch.await?;
+    runner.fetched(url, &body)?;
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{Nixpkgs, Source, import::Kind};
+
+    #[test]
+    fn test_argv() {
+        let mut command = Command::with_args("nix", &["eval", "--json"]);
+        command.add_arg_pair("-f", crate::DATADIR.join("commands/flake_info.nix"));
+        assert_eq!(
+            argv(&command),
+            [
+                "nix",
+                "eval",
+                "--json",
+                "-f",
+                "$DATADIR/commands/flake_info.nix"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        let dir = std::env::temp_dir().join(format!("flake-info-record-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let url = "https://channels.nixos.org/nixos-unstable/packages.json.br";
+
+        let runner = RecordingRunner::create(&dir, SystemRunner).unwrap();
+        let command = Command::with_args("echo", &["recorded"]);
+        assert_eq!(runner.run(&command).unwrap(), b"recorded\n");
+        assert_eq!(runner.run(&command).unwrap(), b"recorded\n");
+        assert!(
+            runner
+                .run(&Command::with_args("false", &[] as &[&str]))
+                .is_err()
+        );
+        runner.fetched(url, b"{}").unwrap();
+        assert!(runner.replay_fetch(url).is_none());
+
+        let runner = ReplayRunner::open(&dir).unwrap();
+        assert_eq!(runner.recordings.len(), 2);
+        assert_eq!(runner.run(&command).unwrap(), b"recorded\n");
+        assert_eq!(runner.replay_fetch(url).unwrap().unwrap(), b"{}");
+        assert!(matches!(
+            runner.replay_fetch("https://example.org"),
+            Some(Err(NixError::NotRecorded { .. }))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/fixtures");
+        let runner = ReplayRunner::open(&dir).unwrap();
+
+        let command = Command::with_args("nix", &["eval", "--expr", "not recorded"]);
+        assert!(matches!(
+            runner.run(&command),
+            Err(NixError::NotRecorded { .. })
+        ));
+
+        let _runner = test_runner(runner);
+        let source = Source::Github {
+            owner: "serokell".to_owned(),
+            repo: "deploy-rs".to_owned(),
+            git_ref: None,
+            description: None,
+        };
+        let (flake, exports) = crate::process_flake(
+            &source,
+            &Kind::All,
+            false,
+            &[],
+            false,
+            None,
+            &Default::default(),
+        )
+        .unwrap();
+
+        assert_eq!(flake.name, "deploy-rs");
+        assert_eq!(
+            flake.nixpkgs.map(|nixpkgs| nixpkgs.source).as_deref(),
+            Some(crate::commands::UNSTABLE_TARBALL)
+        );
+        assert_eq!(
+            flake.revision.as_deref(),
+            Some("9c870f63e28ec1e83305f7f6cb73c941e699f74f")
+        );
+        let documents: Vec<serde_json::Value> = exports
+            .iter()
+            .map(|export| serde_json::to_value(export).unwrap())
+            .collect();
+        let mut names: Vec<(&str, &str)> = documents
+            .iter()
+            .map(|document| {
+                let name = document
+                    .get("package_attr_name")
+                    .or_else(|| document.get("app_attr_name"))
+                    .and_then(|name| name.as_str())
+                    .unwrap();
+                (document["type"].as_str().unwrap(), name)
+            })
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                ("app", "default"),
+                ("app", "deploy-rs"),
+                ("package", "default"),
+                ("package", "deploy-rs")
+            ]
+        );
+        assert!(documents.iter().all(|document| {
+            document["flake_description"] == "A Simple multi-profile Nix-flake deploy tool."
+        }));
+    }
+
+    #[test]
+    fn test_replay_cached() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/fixtures");
+        let _runner = test_runner(ReplayRunner::open(&dir).unwrap());
+        let cache_dir =
+            std::env::temp_dir().join(format!("flake-info-replay-cache-{}", std::process::id()));
+        let cache = crate::cache::ExportCache::open(&cache_dir).unwrap();
+
+        // the evaluation nixpkgs of a group import without --eval-nixpkgs
+        let nixpkgs = crate::commands::EvalNixpkgs::default().lock().unwrap();
+        let source = Source::Github {
+            owner: "serokell".to_owned(),
+            repo: "deploy-rs".to_owned(),
+            git_ref: None,
+            description: None,
+        };
+        let mut runs = (0..2).map(|_| {
+            crate::process_flake(
+                &source,
+                &Kind::All,
+                false,
+                &[],
+                false,
+                Some(&cache),
+                &nixpkgs,
+            )
+            .unwrap()
+        });
+        let (_, evaluated) = runs.next().unwrap();
+        assert_eq!(cache.hits(), 0);
+        let (_, cached) = runs.next().unwrap();
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(
+            serde_json::to_value(&cached).unwrap(),
+            serde_json::to_value(&evaluated).unwrap()
+        );
+
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_nixpkgs() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/fixtures/nixpkgs");
+        let _runner = test_runner(ReplayRunner::open(&dir).unwrap());
+
+        let nixpkgs = Source::Nixpkgs(Nixpkgs {
+            channel: "unstable".to_owned(),
+            git_ref: "a3b6f2a5c1d4e7f8091a2b3c4d5e6f708192a3b4".to_owned(),
+        });
+        let exports = crate::process_nixpkgs(&nixpkgs, &Kind::All, &None, &None).unwrap();
+
+        let documents: Vec<serde_json::Value> = exports
+            .iter()
+            .map(|export| serde_json::to_value(export).unwrap())
+            .collect();
+        let mut names: Vec<(&str, &str)> = documents
+            .iter()
+            .map(|document| {
+                let name = document
+                    .get("package_attr_name")
+                    .or_else(|| document.get("option_name"))
+                    .and_then(|name| name.as_str())
+                    .unwrap();
+                (document["type"].as_str().unwrap(), name)
+            })
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                ("home-manager-option", "programs.git.enable"),
+                ("option", "services.nginx.enable"),
+                ("package", "hello"),
+                ("package", "nginx"),
+                ("service", "nginx.package")
+            ]
+        );
+
+        let nginx = documents
+            .iter()
+            .find(|document| document["package_attr_name"] == "nginx")
+            .unwrap();
+        assert_eq!(nginx["package_programs"], serde_json::json!(["nginx"]));
+        assert_eq!(
+            nginx["package_modular_services"],
+            serde_json::json!(["default"])
+        );
+    }
+}
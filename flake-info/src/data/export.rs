@@ -8,16 +8,23 @@
 ///
 /// When merging a PR that changes the schema, also update the
 /// version.nix `import` version in the root of the repo,
-/// so a fresh index will be created.
+/// so a fresh index will be created, and bump [SCHEMA_VERSION].
+/// `flake-info schema check` fails if the schema changed but the
+/// version did not, see [Export::schema_fields].
 use std::{
-    collections::HashSet,
+    collections::{BTreeSet, HashSet},
     convert::{TryFrom, TryInto},
+    io::{Read, Write},
     path::PathBuf,
 };
 
-use serde::{Deserialize, Serialize};
+use anyhow::Context;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
 
 use super::{
+    Repo, Source,
     import::{self, DocString, DocValue, ModulePath, NixOption},
     pandoc::PandocExt,
     utility::{Flatten, OneOrMany},
@@ -202,12 +209,15 @@ pub enum Derivation {
         option_source: Option<String>,
         option_name: String,
 
+        #[serde(deserialize_with = "rendered_doc_string", default)]
         option_description: Option<DocString>,
 
         option_type: Option<String>,
 
+        #[serde(deserialize_with = "rendered_doc_value", default)]
         option_default: Option<DocValue>,
 
+        #[serde(deserialize_with = "rendered_doc_value", default)]
         option_example: Option<DocValue>,
 
         option_flake: Option<ModulePath>,
@@ -216,9 +226,12 @@ pub enum Derivation {
     Service {
         option_source: Option<String>,
         option_name: String,
+        #[serde(deserialize_with = "rendered_doc_string", default)]
         option_description: Option<DocString>,
         option_type: Option<String>,
+        #[serde(deserialize_with = "rendered_doc_value", default)]
         option_default: Option<DocValue>,
+        #[serde(deserialize_with = "rendered_doc_value", default)]
         option_example: Option<DocValue>,
         option_flake: Option<ModulePath>,
         service_package: Option<String>,
@@ -229,14 +242,34 @@ pub enum Derivation {
     HomeManagerOption {
         option_source: Option<String>,
         option_name: String,
+        #[serde(deserialize_with = "rendered_doc_string", default)]
         option_description: Option<DocString>,
         option_type: Option<String>,
+        #[serde(deserialize_with = "rendered_doc_value", default)]
         option_default: Option<DocValue>,
+        #[serde(deserialize_with = "rendered_doc_value", default)]
         option_example: Option<DocValue>,
         option_flake: Option<ModulePath>,
     },
 }
 
+/// Exported documentation has already been rendered, read it back verbatim
+/// instead of rendering it a second time.
+fn rendered_doc_string<'de, D>(deserializer: D) -> Result<Option<DocString>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<String>::deserialize(deserializer)?.map(DocString::Rendered))
+}
+
+/// Exported values have already been pretty printed, read them back verbatim.
+fn rendered_doc_value<'de, D>(deserializer: D) -> Result<Option<DocValue>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<String>::deserialize(deserializer)?.map(DocValue::Rendered))
+}
+
 // ----- Conversions
 
 impl TryFrom<(import::FlakeEntry, super::Flake)> for Derivation {
@@ -605,8 +638,15 @@ impl From<import::Team> for Team {
 
 // ----- output type
 
+/// Version of the exported document format.
+///
+/// Dumps written with [Export::to_writer] record this version and
+/// [Export::from_reader] only accepts dumps with the same version.
+pub const SCHEMA_VERSION: u32 = 1;
+
 /// Export type that brings together derivation and optional flake info
-#[derive(Debug, Clone, PartialEq, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "ExportedDocument")]
 pub struct Export {
     #[serde(flatten)]
     flake: Option<Flake>,
@@ -615,6 +655,46 @@ pub struct Export {
     item: Derivation,
 }
 
+/// The flake fields as they appear in an exported document.
+///
+/// [Flake] itself deserializes the output of `nix flake metadata`, which uses
+/// different field names than the ones it is serialized to.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct ExportedFlake {
+    flake_description: Option<String>,
+    flake_resolved: Repo,
+    flake_name: String,
+    revision: Option<String>,
+    flake_source: Option<Source>,
+}
+
+/// Deserialization counterpart of [Export]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct ExportedDocument {
+    #[serde(flatten)]
+    flake: Option<ExportedFlake>,
+
+    #[serde(flatten)]
+    item: Derivation,
+}
+
+impl From<ExportedDocument> for Export {
+    fn from(document: ExportedDocument) -> Self {
+        Export {
+            flake: document.flake.map(|flake| Flake {
+                description: flake.flake_description,
+                path: PathBuf::new(),
+                resolved: flake.flake_resolved,
+                name: flake.flake_name,
+                revision: flake.revision,
+                source: flake.flake_source,
+                nixpkgs: None,
+            }),
+            item: document.item,
+        }
+    }
+}
+
 impl Export {
     /// Construct Export from Flake and Flake entry
     pub fn flake(flake: Flake, item: import::FlakeEntry) -> anyhow::Result<Self> {
@@ -631,6 +711,338 @@ impl Export {
             item: Derivation::try_from(item)?,
         })
     }
+
+    /// The flake this export was taken from, `None` for nixpkgs exports
+    pub fn flake_info(&self) -> Option<&Flake> {
+        self.flake.as_ref()
+    }
+
+    /// A stable identifier of this export, derived from its type, the flake
+    /// it was exported from and its attribute or option name. Packages are
+    /// also identified by their package set, pname and system, modular
+    /// services by the package and module declaring them.
+    ///
+    /// Used as Elasticsearch document id, so that pushing the same export
+    /// twice replaces the document instead of duplicating it.
+    pub fn id(&self) -> String {
+        let source = match &self.flake {
+            Some(Flake {
+                source: Some(source),
+                ..
+            }) => source.to_flake_ref(),
+            Some(flake) => serde_json::to_string(&flake.resolved).unwrap_or_default(),
+            None => String::new(),
+        };
+        let (document_type, names): (&str, Vec<&str>) = match &self.item {
+            // the `packages` and `legacyPackages` of a flake may both contain an attribute
+            Derivation::Package {
+                package_attr_name,
+                package_attr_set,
+                package_pname,
+                package_system,
+                ..
+            } => (
+                "package",
+                vec![
+                    package_attr_name.as_str(),
+                    package_attr_set.as_str(),
+                    package_pname.as_str(),
+                    package_system.as_str(),
+                ],
+            ),
+            Derivation::App { app_attr_name, .. } => ("app", vec![app_attr_name.as_str()]),
+            Derivation::Option { option_name, .. } => ("option", vec![option_name.as_str()]),
+            // modules of different packages may declare the same option
+            Derivation::Service {
+                option_name,
+                service_package,
+                service_module,
+                ..
+            } => (
+                "service",
+                vec![
+                    option_name.as_str(),
+                    service_package.as_deref().unwrap_or_default(),
+                    service_module.as_deref().unwrap_or_default(),
+                ],
+            ),
+            Derivation::HomeManagerOption { option_name, .. } => {
+                ("home-manager-option", vec![option_name.as_str()])
+            }
+        };
+
+        let mut sha = Sha256::new();
+        for part in [document_type, source.as_str()].iter().chain(&names) {
+            sha.update(part);
+            sha.update(b"\0");
+        }
+        format!("{:x}", sha.finalize())
+    }
+
+    /// Paths of the fields of exported documents, prefixed with the
+    /// document type, e.g. `package/package_license.fullName`.
+    ///
+    /// Derived by round tripping one document of each type with every
+    /// optional field set, so adding, removing or renaming a field of
+    /// [Derivation] or [Flake] changes the result.
+    pub fn schema_fields() -> anyhow::Result<Vec<String>> {
+        let mut fields = BTreeSet::new();
+        for sample in sample_documents() {
+            let export: Export =
+                serde_json::from_value(sample).context("Sample document does not match Export")?;
+            let document = serde_json::to_value(&export)?;
+            let document_type = document["type"].as_str().unwrap_or_default().to_owned();
+            collect_fields(&document_type, &document, &mut fields);
+        }
+        Ok(fields.into_iter().collect())
+    }
+
+    /// Writes `exports` as a JSON dump, i.e. an object of the form
+    /// `{"schema_version": SCHEMA_VERSION, "ident": {...}, "exports": [...]}`
+    pub fn to_writer(
+        writer: impl Write,
+        ident: Option<&Ident>,
+        exports: &[Export],
+    ) -> anyhow::Result<()> {
+        serde_json::to_writer(
+            writer,
+            &JsonDump {
+                header: DumpHeader::new(ident),
+                exports,
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Writes `exports` as a bare JSON array without schema version or ident,
+    /// the format `--json` used before dumps were versioned
+    pub fn to_array_writer(writer: impl Write, exports: &[Export]) -> anyhow::Result<()> {
+        serde_json::to_writer(writer, exports)?;
+        Ok(())
+    }
+
+    /// Reads a dump written by [Export::to_writer], [Export::to_array_writer]
+    /// or [NdjsonWriter].
+    ///
+    /// Dumps of a different [SCHEMA_VERSION] are rejected. A top level array
+    /// is read as an unversioned dump, as written by `--json` before dumps
+    /// were versioned, and has no ident.
+    pub fn from_reader(reader: impl Read) -> anyhow::Result<Vec<Export>> {
+        Ok(Export::read_dump(reader)?.1)
+    }
+
+    /// Like [Export::from_reader], but also returns the ident stored in the dump
+    pub fn read_dump(reader: impl Read) -> anyhow::Result<(Option<Ident>, Vec<Export>)> {
+        let mut values = serde_json::Deserializer::from_reader(reader).into_iter::<Value>();
+
+        let mut first = values
+            .next()
+            .context("Empty dump")?
+            .context("Invalid JSON dump")?;
+        let header: DumpHeader = match &first {
+            Value::Object(header) if header.contains_key("schema_version") => {
+                serde_json::from_value(first.clone()).context("Invalid dump header")?
+            }
+            Value::Array(_) => {
+                let exports =
+                    serde_json::from_value(first).context("Invalid exports in unversioned dump")?;
+                return Ok((None, exports));
+            }
+            _ => anyhow::bail!(
+                "Dump has no schema version, it was likely written by an older flake-info"
+            ),
+        };
+
+        anyhow::ensure!(
+            header.schema_version == SCHEMA_VERSION,
+            "Dump has schema version {} but this flake-info reads version {}, re-export it with a matching version",
+            header.schema_version,
+            SCHEMA_VERSION
+        );
+
+        let exports: Vec<Export> = match first.get_mut("exports") {
+            // a JSON dump holds all exports in a single object
+            Some(exports) => {
+                serde_json::from_value(exports.take()).context("Invalid exports in dump")?
+            }
+            // NDJSON continues with one export per line
+            None => values
+                .enumerate()
+                .map(|(n, value)| {
+                    serde_json::from_value(value?)
+                        .with_context(|| format!("Invalid export on line {}", n + 2))
+                })
+                .collect::<anyhow::Result<_>>()?,
+        };
+
+        Ok((header.ident, exports))
+    }
+}
+
+fn collect_fields(prefix: &str, value: &Value, fields: &mut BTreeSet<String>) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                let separator = if prefix.contains('/') { "." } else { "/" };
+                let path = format!("{}{}{}", prefix, separator, key);
+                collect_fields(&path, value, fields);
+                fields.insert(path);
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                collect_fields(prefix, value, fields);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// One exported document of each type, see [Export::schema_fields]
+fn sample_documents() -> Vec<Value> {
+    let flake = serde_json::json!({
+        "flake_description": "A flake",
+        "flake_resolved": {"type": "github", "owner": "owner", "repo": "repo"},
+        "flake_name": "repo",
+        "revision": "0000000000000000000000000000000000000000",
+        "flake_source": {"type": "git", "url": "https://example.org/repo.git"},
+    });
+    let maintainer =
+        serde_json::json!({"name": "Jane", "github": "jane", "email": "jane@example.org"});
+    let option = serde_json::json!({
+        "option_source": "modules/module.nix",
+        "option_name": "services.foo.enable",
+        "option_description": "<p>Enable</p>",
+        "option_type": "boolean",
+        "option_default": "false",
+        "option_example": "true",
+        "option_flake": ["repo", "default"],
+    });
+
+    let mut package = serde_json::json!({
+        "type": "package",
+        "package_attr_name": "hello",
+        "package_attr_set": "No package set",
+        "package_pname": "hello",
+        "package_pversion": "2.12",
+        "package_platforms": ["x86_64-linux"],
+        "package_outputs": ["out"],
+        "package_default_output": "out",
+        "package_programs": ["hello"],
+        "package_mainProgram": "hello",
+        "package_license": [{"fullName": "MIT License", "url": "https://spdx.org/licenses/MIT"}],
+        "package_license_set": ["MIT License"],
+        "package_license_expression": {"kind": "leaf", "fullName": "MIT License", "url": null},
+        "package_maintainers": [maintainer],
+        "package_maintainers_set": ["Jane"],
+        "package_teams": [{"members": [maintainer], "scope": "Hello", "shortName": "hello", "githubTeams": ["hello"]}],
+        "package_teams_set": ["hello"],
+        "package_description": "Hello",
+        "package_longDescription": "<p>Hello</p>",
+        "package_hydra": null,
+        "package_system": "x86_64-linux",
+        "package_homepage": ["https://example.org"],
+        "package_position": "pkgs/hello/default.nix:1",
+        "package_modular_services": ["hello"],
+    });
+    package
+        .as_object_mut()
+        .unwrap()
+        .extend(flake.as_object().unwrap().clone());
+
+    let with_type = |document_type: &str, extra: Value| {
+        let mut document = option.clone();
+        let object = document.as_object_mut().unwrap();
+        object.insert("type".to_owned(), document_type.into());
+        object.extend(extra.as_object().unwrap().clone());
+        document
+    };
+
+    vec![
+        package,
+        serde_json::json!({
+            "type": "app",
+            "app_attr_name": "hello",
+            "app_platforms": ["x86_64-linux"],
+            "app_type": "app",
+            "app_bin": "/nix/store/hello/bin/hello",
+        }),
+        with_type("option", serde_json::json!({})),
+        with_type(
+            "service",
+            serde_json::json!({
+                "service_package": "hello",
+                "service_module": "hello",
+                "service_packages": ["hello"],
+            }),
+        ),
+        with_type("home-manager-option", serde_json::json!({})),
+    ]
+}
+
+/// Identifies the source of a set of exports.
+///
+/// Used to name the Elasticsearch index the exports are pushed to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ident {
+    /// `flake`, `nixos` or `group`
+    pub kind: String,
+    pub name: String,
+    /// Revision or combined hash of the sources
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct DumpHeader {
+    schema_version: u32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ident: Option<Ident>,
+}
+
+impl DumpHeader {
+    fn new(ident: Option<&Ident>) -> Self {
+        DumpHeader {
+            schema_version: SCHEMA_VERSION,
+            ident: ident.cloned(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonDump<'a> {
+    #[serde(flatten)]
+    header: DumpHeader,
+    exports: &'a [Export],
+}
+
+/// Writes exports as newline delimited JSON.
+///
+/// The first line is a header `{"schema_version": SCHEMA_VERSION, "ident": {...}}`,
+/// every following line holds a single export.
+pub struct NdjsonWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> NdjsonWriter<W> {
+    /// Writes the header to `writer`
+    pub fn new(mut writer: W, ident: Option<&Ident>) -> anyhow::Result<Self> {
+        serde_json::to_writer(&mut writer, &DumpHeader::new(ident))?;
+        writer.write_all(b"\n")?;
+        Ok(NdjsonWriter { writer })
+    }
+
+    pub fn write(&mut self, export: &Export) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.writer, export)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Flushes and returns the underlying writer
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
 }
 
 #[cfg(test)]
@@ -657,4 +1069,228 @@ mod tests {
 
         println!("{}", serde_json::to_string_pretty(&option).unwrap());
     }
+
+    #[test]
+    fn test_export_roundtrip() {
+        let flake: Flake = serde_json::from_str::<Flake>(
+            r#"{"description":"A flake","path":"/nix/store/z4fp2fc9hca40nnvxi0116pfbrla5zgl-source","resolved":{"owner":"pi-lar","repo":"neuropil","type":"gitlab"},"revision":"9e2f634ffa45da3f5feb158a12ee32e1673bfe35"}"#,
+        )
+        .unwrap()
+        .resolve_name();
+        let flake = Flake {
+            source: Some(Source::Gitlab {
+                owner: "pi-lar".into(),
+                repo: "neuropil".into(),
+                git_ref: None,
+            }),
+            ..flake
+        };
+
+        let package: import::FlakeEntry = serde_json::from_str(
+            r#"{
+                "entry_type": "package",
+                "attribute_name": "neuropil",
+                "name": "neuropil-0.1.0",
+                "version": "0.1.0",
+                "platforms": ["x86_64-linux"],
+                "outputs": ["out"],
+                "default_output": "out",
+                "description": "Secure messaging library",
+                "license": "MIT"
+            }"#,
+        )
+        .unwrap();
+
+        let option: NixOption = serde_json::from_str(
+            r#"{
+                "declarations": ["nixos/modules/services/neuropil.nix"],
+                "name": "services.neuropil.settings",
+                "type": "attribute set",
+                "default": {"port": 3141, "hosts": ["a", "b"]},
+                "example": {"_type": "literalExpression", "text": "{ port = 1; }"}
+            }"#,
+        )
+        .unwrap();
+
+        let exports = vec![
+            Export::flake(flake, package).unwrap(),
+            Export::nixpkgs(import::NixpkgsEntry::Option(option)).unwrap(),
+        ];
+
+        let serialized = serde_json::to_value(&exports).unwrap();
+        let deserialized: Vec<Export> = serde_json::from_value(serialized.clone()).unwrap();
+
+        assert_eq!(serde_json::to_value(&deserialized).unwrap(), serialized);
+        assert!(deserialized[0].flake.is_some());
+        assert!(deserialized[1].flake.is_none());
+
+        let mut dump = Vec::new();
+        Export::to_writer(&mut dump, None, &exports).unwrap();
+        assert_eq!(Export::from_reader(&dump[..]).unwrap(), deserialized);
+    }
+
+    #[test]
+    fn test_ndjson_writer() {
+        let option: NixOption = serde_json::from_str(
+            r#"{"declarations": [], "name": "services.foo.enable", "type": "boolean"}"#,
+        )
+        .unwrap();
+        let export = Export::nixpkgs(import::NixpkgsEntry::Option(option)).unwrap();
+
+        let ident = Ident {
+            kind: "nixos".into(),
+            name: "unstable".into(),
+            hash: "abcdef".into(),
+        };
+        let mut writer = NdjsonWriter::new(Vec::new(), Some(&ident)).unwrap();
+        writer.write(&export).unwrap();
+        writer.write(&export).unwrap();
+        let output = String::from_utf8(writer.finish().unwrap()).unwrap();
+
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["schema_version"], SCHEMA_VERSION);
+        assert_eq!(lines[1], serde_json::to_value(&export).unwrap());
+
+        let (read_ident, exports) = Export::read_dump(output.as_bytes()).unwrap();
+        assert_eq!(read_ident, Some(ident));
+        assert_eq!(exports.len(), 2);
+    }
+
+    #[test]
+    fn test_export_id() {
+        let option = |name: &str| -> NixOption {
+            serde_json::from_value(serde_json::json!({
+                "declarations": [],
+                "name": name,
+                "type": "boolean"
+            }))
+            .unwrap()
+        };
+
+        let export = Export::nixpkgs(import::NixpkgsEntry::Option(option("foo.enable"))).unwrap();
+        let same = Export::nixpkgs(import::NixpkgsEntry::Option(option("foo.enable"))).unwrap();
+        let other = Export::nixpkgs(import::NixpkgsEntry::Option(option("bar.enable"))).unwrap();
+
+        assert_eq!(export.id(), same.id());
+        assert_ne!(export.id(), other.id());
+        assert_eq!(export.id().len(), 64);
+    }
+
+    #[test]
+    fn test_service_id_collision() {
+        let service = |package: &str, module: &str| {
+            let option: NixOption = serde_json::from_value(serde_json::json!({
+                "declarations": [],
+                "name": "settings",
+                "type": "attribute set",
+                "service_package": package,
+                "service_module": module,
+            }))
+            .unwrap();
+            Export::nixpkgs(import::NixpkgsEntry::Service(option)).unwrap()
+        };
+
+        assert_eq!(
+            service("php", "default").id(),
+            service("php", "default").id()
+        );
+        assert_ne!(service("php", "default").id(), service("php", "fpm").id());
+        assert_ne!(
+            service("php", "default").id(),
+            service("nginx", "default").id()
+        );
+    }
+
+    #[test]
+    fn test_package_id_collision() {
+        let flake: Flake = serde_json::from_str(
+            r#"{"description":"A flake","path":"/nix/store/z4fp2fc9hca40nnvxi0116pfbrla5zgl-source","resolved":{"owner":"owner","repo":"repo","type":"github"},"revision":null}"#,
+        )
+        .unwrap();
+        let package = |name: &str| {
+            let entry: import::FlakeEntry = serde_json::from_value(serde_json::json!({
+                "entry_type": "package",
+                "attribute_name": "tool",
+                "name": name,
+                "version": "1.0",
+                "platforms": ["x86_64-linux"],
+                "outputs": ["out"],
+                "default_output": "out",
+                "description": null,
+                "longDescription": null,
+                "license": null,
+            }))
+            .unwrap();
+            Export::flake(flake.clone(), entry).unwrap()
+        };
+
+        // a `tool` in both `packages` and `legacyPackages`, built from different derivations
+        assert_eq!(package("tool").id(), package("tool").id());
+        assert_ne!(package("tool").id(), package("tool-unwrapped").id());
+
+        let nixpkgs = |system: &str| {
+            let package: import::Package = serde_json::from_value(serde_json::json!({
+                "pname": "hello",
+                "version": "2.12",
+                "system": system,
+            }))
+            .unwrap();
+            Export::nixpkgs(import::NixpkgsEntry::Derivation {
+                attribute: "hello".to_owned(),
+                package,
+                programs: Vec::new(),
+                modular_services: Vec::new(),
+            })
+            .unwrap()
+        };
+        assert_ne!(nixpkgs("x86_64-linux").id(), nixpkgs("aarch64-linux").id());
+    }
+
+    #[test]
+    fn test_schema_fields() {
+        let fields = Export::schema_fields().unwrap();
+
+        for field in &[
+            "package/package_pversion",
+            "package/package_license.fullName",
+            "package/package_teams.members.github",
+            "package/flake_resolved.owner",
+            "app/app_bin",
+            "service/service_packages",
+            "home-manager-option/option_default",
+        ] {
+            assert!(fields.iter().any(|f| f == field), "missing {}", field);
+        }
+        assert!(!fields.iter().any(|f| f == "app/package_pversion"));
+    }
+
+    #[test]
+    fn test_read_unversioned_dump() {
+        let exports: Vec<Export> = sample_documents()
+            .into_iter()
+            .map(|sample| serde_json::from_value(sample).unwrap())
+            .collect();
+        let mut output = Vec::new();
+        Export::to_array_writer(&mut output, &exports).unwrap();
+        assert!(output.starts_with(b"["));
+
+        let (ident, read) = Export::read_dump(&output[..]).unwrap();
+        assert_eq!(ident, None);
+        assert_eq!(read, exports);
+        assert!(Export::from_reader(&b"[]"[..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reject_incompatible_dump() {
+        let error = Export::from_reader(&b"{}"[..]).unwrap_err();
+        assert!(error.to_string().contains("no schema version"));
+
+        let dump = serde_json::json!({"schema_version": SCHEMA_VERSION + 1, "exports": []});
+        let error = Export::from_reader(dump.to_string().as_bytes()).unwrap_err();
+        assert!(error.to_string().contains("schema version"));
+    }
 }
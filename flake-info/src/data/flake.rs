@@ -2,7 +2,7 @@ use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
-use super::Source;
+use super::{Source, SourceRevision};
 
 /// Holds general infoamtion about a flake
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -25,6 +25,10 @@ pub struct Flake {
         skip_serializing_if = "Option::is_none"
     )]
     pub source: Option<Source>,
+
+    /// The nixpkgs the flake was evaluated with
+    #[serde(skip)]
+    pub nixpkgs: Option<SourceRevision>,
 }
 
 impl Flake {
@@ -71,6 +75,7 @@ mod tests {
                 },
                 name: "".into(),
                 source: None,
+                nixpkgs: None,
                 revision: Some("9e2f634ffa45da3f5feb158a12ee32e1673bfe35".into())
             }
         );
@@ -102,6 +102,10 @@ pub enum DocString {
     DocFormat(DocFormat),
     Literal(Literal),
     String(String),
+    /// Documentation that has already been rendered to HTML, e.g. when reading
+    /// back previously exported data. Never produced when parsing nix output.
+    #[serde(skip_deserializing)]
+    Rendered(String),
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize)]
@@ -116,6 +120,10 @@ pub enum DocFormat {
 pub enum DocValue {
     Literal(Literal),
     Value(Value),
+    /// A value that has already been pretty printed, e.g. when reading back
+    /// previously exported data. Never produced when parsing nix output.
+    #[serde(skip_deserializing)]
+    Rendered(String),
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize)]
@@ -155,6 +163,7 @@ impl Serialize for DocString {
                     md.to_owned()
                 })),
             DocString::Literal(literal) => serializer.serialize_str(&render_literal(literal)),
+            DocString::Rendered(html) => serializer.serialize_str(html),
         }
     }
 }
@@ -167,6 +176,7 @@ impl Serialize for DocValue {
         match self {
             DocValue::Literal(literal) => serializer.serialize_str(&render_literal(literal)),
             DocValue::Value(v) => serializer.serialize_str(&print_value(v)),
+            DocValue::Rendered(s) => serializer.serialize_str(s),
         }
     }
 }
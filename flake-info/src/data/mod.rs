@@ -3,9 +3,11 @@ mod flake;
 pub mod import;
 mod pandoc;
 mod prettyprint;
+mod provenance;
 mod source;
 mod utility;
 
-pub use export::Export;
+pub use export::{Export, Ident, NdjsonWriter, SCHEMA_VERSION};
 pub use flake::{Flake, Repo};
+pub use provenance::{Provenance, SourceRevision};
 pub use source::{FlakeRef, Hash, Nixpkgs, Source};
@@ -0,0 +1,60 @@
+/// Describes how a set of exports was produced, i.e. which sources at which
+/// revisions were evaluated, when, and by which versions of nix and flake-info.
+///
+/// Stored alongside the exports, e.g. in the `_meta` of an Elasticsearch index,
+/// so that it can be traced back to its inputs later on.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Ident, Source};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Ident the exports were named after, if known
+    pub ident: Option<Ident>,
+    pub sources: Vec<SourceRevision>,
+    /// Seconds since the epoch at which the import started
+    pub timestamp: u64,
+    /// Output of `builtins.nixVersion`, `None` if nix did not run, e.g. when pushing a dump
+    pub nix_version: Option<String>,
+    pub flake_info_version: String,
+    /// Number of group members that could not be evaluated
+    pub failed_members: usize,
+}
+
+/// A source and the revision it was evaluated at
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceRevision {
+    pub source: String,
+    pub revision: Option<String>,
+    /// The nixpkgs a flake was evaluated with, see [EvalNixpkgs](crate::commands::EvalNixpkgs)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nixpkgs: Option<Box<SourceRevision>>,
+}
+
+impl SourceRevision {
+    pub fn new(source: &Source, revision: Option<String>) -> Self {
+        SourceRevision {
+            source: source.to_flake_ref(),
+            revision,
+            nixpkgs: None,
+        }
+    }
+}
+
+impl Provenance {
+    /// Provenance of an import starting now, without any sources yet
+    pub fn new(ident: Option<Ident>, nix_version: Option<String>) -> Self {
+        Provenance {
+            ident,
+            sources: Vec::new(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs()),
+            nix_version,
+            flake_info_version: env!("CARGO_PKG_VERSION").to_owned(),
+            failed_members: 0,
+        }
+    }
+}
@@ -19,7 +19,7 @@ pub enum Source {
         owner: String,
         repo: String,
         description: Option<String>,
-        #[serde(rename(deserialize = "hash"))]
+        #[serde(rename(deserialize = "hash"), alias = "git_ref")]
         git_ref: Option<Hash>,
     },
     Gitlab {
@@ -116,32 +116,38 @@ impl Source {
             sha: String,
         }
 
-        let request = reqwest::Client::builder()
-            .user_agent("nixos-search")
-            .build()?
-            .get(format!(
-                "https://api.github.com/repos/nixos/nixpkgs/branches/nixos-{}",
-                channel
-            ));
-
-        let request = match std::env::var("GITHUB_TOKEN") {
-            Ok(token) => request.bearer_auth(token),
-            _ => request,
-        };
-
-        let response = request.send().await?;
-
-        if !response.status().is_success() {
-            Err(anyhow::anyhow!(
-                "GitHub returned {:?} {}",
-                response.status(),
-                response.text().await?
-            ))
-        } else {
-            let git_ref = response.json::<ApiResult>().await?.commit.sha;
-            let nixpkgs = Nixpkgs { channel, git_ref };
-            Ok(nixpkgs)
-        }
+        let url = format!(
+            "https://api.github.com/repos/nixos/nixpkgs/branches/nixos-{}",
+            channel
+        );
+        let body = crate::commands::fetch(&url, async {
+            let request = reqwest::Client::builder()
+                .user_agent("nixos-search")
+                .build()?
+                .get(&url);
+
+            let request = match std::env::var("GITHUB_TOKEN") {
+                Ok(token) => request.bearer_auth(token),
+                _ => request,
+            };
+
+            let response = request.send().await?;
+
+            if !response.status().is_success() {
+                Err(anyhow::anyhow!(
+                    "GitHub returned {:?} {}",
+                    response.status(),
+                    response.text().await?
+                ))
+            } else {
+                Ok(response.bytes().await?.to_vec())
+            }
+        })
+        .await?;
+
+        let git_ref = serde_json::from_slice::<ApiResult>(&body)?.commit.sha;
+        let nixpkgs = Nixpkgs { channel, git_ref };
+        Ok(nixpkgs)
     }
 }
 
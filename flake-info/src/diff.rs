@@ -0,0 +1,350 @@
+/// Compares two sets of exports.
+///
+/// Documents are matched by their type, the flake they belong to and their
+/// name (`package_attr_name`, `app_attr_name` or `option_name`). Matching
+/// documents are compared on the fields that are interesting to a reader of a
+/// changelog, i.e. versions and licenses of packages and types and defaults
+/// of options.
+use std::{collections::BTreeMap, fmt, fs::File, io::BufReader, path::Path};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::data::Export;
+
+/// Fields compared for each document type, with a label for text output
+const COMPARED_FIELDS: &[(&str, &str, &str)] = &[
+    ("package", "package_pversion", "version"),
+    ("package", "package_license_set", "license"),
+    ("app", "app_type", "type"),
+    ("app", "app_bin", "program"),
+    ("option", "option_type", "type"),
+    ("option", "option_default", "default"),
+    ("service", "option_type", "type"),
+    ("service", "option_default", "default"),
+    ("home-manager-option", "option_type", "type"),
+    ("home-manager-option", "option_default", "default"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Key {
+    #[serde(rename = "type")]
+    pub document_type: String,
+    pub flake: Option<String>,
+    pub name: String,
+}
+
+impl Key {
+    fn of(document: &Value) -> Option<Self> {
+        let document_type = document["type"].as_str()?.to_owned();
+        let name = match document_type.as_str() {
+            "package" => &document["package_attr_name"],
+            "app" => &document["app_attr_name"],
+            _ => &document["option_name"],
+        };
+        Some(Key {
+            flake: document["flake_name"].as_str().map(ToOwned::to_owned),
+            name: name.as_str()?.to_owned(),
+            document_type,
+        })
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.document_type)?;
+        if let Some(flake) = &self.flake {
+            write!(f, "{}#", flake)?;
+        }
+        write!(f, "{}", self.name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Change {
+    #[serde(flatten)]
+    pub key: Key,
+    pub changes: Vec<FieldChange>,
+}
+
+/// A key shared by several documents of one side of the diff.
+///
+/// Only the first of these documents is compared.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Duplicate {
+    #[serde(flatten)]
+    pub key: Key,
+    /// `old` or `new`
+    pub side: &'static str,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Diff {
+    pub added: Vec<Key>,
+    pub removed: Vec<Key>,
+    pub changed: Vec<Change>,
+    pub duplicates: Vec<Duplicate>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.duplicates.is_empty()
+    }
+}
+
+/// Documents of `exports` by their key, adding keys that occur more than
+/// once to `duplicates`
+fn index(
+    exports: &[Export],
+    side: &'static str,
+    duplicates: &mut Vec<Duplicate>,
+) -> Result<BTreeMap<Key, Value>> {
+    let mut documents = BTreeMap::new();
+    let mut counts: BTreeMap<Key, usize> = BTreeMap::new();
+    for export in exports {
+        let document = serde_json::to_value(export)?;
+        if let Some(key) = Key::of(&document) {
+            *counts.entry(key.clone()).or_default() += 1;
+            documents.entry(key).or_insert(document);
+        }
+    }
+    duplicates.extend(
+        counts
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(key, count)| Duplicate { key, side, count }),
+    );
+    Ok(documents)
+}
+
+/// Reads exports written with `--json`
+pub fn read_exports(path: &Path) -> Result<Vec<Export>> {
+    let file = File::open(path).with_context(|| format!("Couldn't open {}", path.display()))?;
+    Export::from_reader(BufReader::new(file))
+        .with_context(|| format!("Couldn't read exports from {}", path.display()))
+}
+
+/// Computes the differences between `old` and `new`
+pub fn diff(old: &[Export], new: &[Export]) -> Result<Diff> {
+    let mut diff = Diff::default();
+    let old = index(old, "old", &mut diff.duplicates)?;
+    let new = index(new, "new", &mut diff.duplicates)?;
+
+    for (key, old_document) in &old {
+        let new_document = match new.get(key) {
+            Some(document) => document,
+            None => {
+                diff.removed.push(key.clone());
+                continue;
+            }
+        };
+
+        let changes: Vec<FieldChange> = COMPARED_FIELDS
+            .iter()
+            .filter(|(document_type, ..)| *document_type == key.document_type)
+            .filter(|(_, field, _)| old_document[field] != new_document[field])
+            .map(|(_, field, _)| FieldChange {
+                field: field.to_string(),
+                old: old_document[field].clone(),
+                new: new_document[field].clone(),
+            })
+            .collect();
+
+        if !changes.is_empty() {
+            diff.changed.push(Change {
+                key: key.clone(),
+                changes,
+            });
+        }
+    }
+
+    diff.added = new
+        .keys()
+        .filter(|key| !old.contains_key(key))
+        .cloned()
+        .collect();
+
+    Ok(diff)
+}
+
+fn display_value(value: &Value) -> String {
+    match value {
+        Value::Null => "(none)".to_owned(),
+        Value::String(s) => s.to_owned(),
+        Value::Array(values) => values
+            .iter()
+            .map(display_value)
+            .collect::<Vec<_>>()
+            .join(", "),
+        other => other.to_string(),
+    }
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for key in &self.added {
+            writeln!(f, "+ {}", key)?;
+        }
+        for key in &self.removed {
+            writeln!(f, "- {}", key)?;
+        }
+        for change in &self.changed {
+            writeln!(f, "~ {}", change.key)?;
+            for field_change in &change.changes {
+                let label = COMPARED_FIELDS
+                    .iter()
+                    .find(|(_, field, _)| *field == field_change.field)
+                    .map_or(field_change.field.as_str(), |(.., label)| label);
+                writeln!(
+                    f,
+                    "    {}: {} -> {}",
+                    label,
+                    display_value(&field_change.old),
+                    display_value(&field_change.new)
+                )?;
+            }
+        }
+        for duplicate in &self.duplicates {
+            writeln!(
+                f,
+                "! {} occurs {} times in {}",
+                duplicate.key, duplicate.count, duplicate.side
+            )?;
+        }
+        write!(
+            f,
+            "{} added, {} removed, {} changed",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        )?;
+        if !self.duplicates.is_empty() {
+            write!(f, ", {} duplicated", self.duplicates.len())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn package(name: &str, version: &str, license: &str) -> Value {
+        json!({
+            "type": "package",
+            "package_attr_name": name,
+            "package_attr_set": "No package set",
+            "package_pname": name,
+            "package_pversion": version,
+            "package_platforms": [],
+            "package_outputs": ["out"],
+            "package_default_output": "out",
+            "package_programs": [],
+            "package_mainProgram": null,
+            "package_license": [{"fullName": license, "url": null}],
+            "package_license_set": [license],
+            "package_license_expression": null,
+            "package_maintainers": [],
+            "package_maintainers_set": [],
+            "package_teams": [],
+            "package_teams_set": [],
+            "package_description": null,
+            "package_longDescription": null,
+            "package_hydra": null,
+            "package_system": "x86_64-linux",
+            "package_homepage": [],
+            "package_position": null,
+            "package_modular_services": []
+        })
+    }
+
+    fn option(name: &str, default: &str) -> Value {
+        json!({
+            "type": "option",
+            "option_source": null,
+            "option_name": name,
+            "option_description": null,
+            "option_type": "boolean",
+            "option_default": default,
+            "option_example": null,
+            "option_flake": null
+        })
+    }
+
+    fn exports(documents: Vec<Value>) -> Vec<Export> {
+        serde_json::from_value(Value::Array(documents)).unwrap()
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = exports(vec![
+            package("hello", "2.12", "GPL-3.0"),
+            package("cowsay", "3.7", "GPL-3.0"),
+            option("programs.hello.enable", "false"),
+        ]);
+        let new = exports(vec![
+            package("hello", "2.12.1", "GPL-3.0"),
+            package("figlet", "2.2.5", "BSD-3-Clause"),
+            option("programs.hello.enable", "true"),
+        ]);
+
+        let diff = diff(&old, &new).unwrap();
+
+        assert_eq!(
+            diff.added.iter().map(|k| &k.name[..]).collect::<Vec<_>>(),
+            ["figlet"]
+        );
+        assert_eq!(
+            diff.removed.iter().map(|k| &k.name[..]).collect::<Vec<_>>(),
+            ["cowsay"]
+        );
+        assert_eq!(diff.changed.len(), 2);
+        assert_eq!(diff.changed[0].key.name, "programs.hello.enable");
+        assert_eq!(diff.changed[0].changes[0].field, "option_default");
+        assert_eq!(diff.changed[1].key.name, "hello");
+        assert_eq!(
+            diff.changed[1].changes,
+            [FieldChange {
+                field: "package_pversion".to_owned(),
+                old: json!("2.12"),
+                new: json!("2.12.1"),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let old = exports(vec![
+            package("hello", "2.12", "GPL-3.0"),
+            package("hello", "2.10", "GPL-3.0"),
+        ]);
+        let new = exports(vec![package("hello", "2.12", "GPL-3.0")]);
+
+        let diff = diff(&old, &new).unwrap();
+
+        // the first document is compared, the second one reported
+        assert!(diff.changed.is_empty());
+        assert_eq!(diff.duplicates.len(), 1);
+        assert_eq!(diff.duplicates[0].key.name, "hello");
+        assert_eq!(diff.duplicates[0].side, "old");
+        assert_eq!(diff.duplicates[0].count, 2);
+        assert!(!diff.is_empty());
+        assert!(
+            diff.to_string()
+                .starts_with("! package hello occurs 2 times in old\n")
+        );
+    }
+}
@@ -1,16 +1,39 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use anyhow::Context;
 use clap::arg_enum;
 pub use elasticsearch::http::transport::Transport;
-use elasticsearch::{BulkOperation, Elasticsearch as Client, http::response, indices::*};
+use elasticsearch::{
+    CatIndicesParts, ClearScrollParts, Elasticsearch as Client, ScrollParts, SearchParts,
+    auth::{ClientCertificate, Credentials},
+    cert::{Certificate, CertificateValidation},
+    http::{
+        StatusCode, Url, response,
+        transport::{SingleNodeConnectionPool, TransportBuilder},
+    },
+    indices::*,
+};
 use lazy_static::lazy_static;
 use log::{info, warn};
+use serde::Serialize;
 use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 
-use crate::data::Export;
+use crate::{
+    data::{Export, Provenance},
+    query::{Query, read_hits},
+    sink::{Failure, PartialFile, Sink},
+};
+
 lazy_static! {
-    static ref MAPPING: Value = json!({
+    pub(crate) static ref MAPPING: Value = json!({
         "mappings": {
             "properties": {
                 "type": {"type": "keyword"},
@@ -226,6 +249,492 @@ lazy_static! {
     });
 }
 
+/// Key of the schema fingerprint in the `_meta` of an index mapping
+const FINGERPRINT_KEY: &str = "schema_fingerprint";
+
+/// Fingerprint of the index mapping and the fields of exported documents
+/// (see [Export::schema_fields]).
+///
+/// Stored in the `_meta` of every index created by flake-info, exports are
+/// never pushed into an index with a different fingerprint.
+pub fn schema_fingerprint() -> Result<String, ElasticsearchError> {
+    let fields = Export::schema_fields().map_err(ElasticsearchError::FingerprintError)?;
+
+    let mut sha = Sha256::new();
+    sha.update(MAPPING.to_string());
+    for field in fields {
+        sha.update(b"\0");
+        sha.update(field);
+    }
+    Ok(format!("{:x}", sha.finalize()))
+}
+
+/// Key of the [Provenance] in the `_meta` of an index mapping
+const PROVENANCE_KEY: &str = "provenance";
+
+/// `_meta` of an index mapping holding the schema fingerprint and `provenance`
+fn index_meta(provenance: Option<&Provenance>) -> Result<Value, ElasticsearchError> {
+    let mut meta = json!({ FINGERPRINT_KEY: schema_fingerprint()? });
+    if let Some(provenance) = provenance {
+        meta[PROVENANCE_KEY] = serde_json::to_value(provenance)?;
+    }
+    Ok(meta)
+}
+
+/// [MAPPING] with the `_meta` described by [index_meta]
+pub(crate) fn index_body(provenance: Option<&Provenance>) -> Result<Value, ElasticsearchError> {
+    let mut body = MAPPING.clone();
+    body["mappings"]["_meta"] = index_meta(provenance)?;
+    Ok(body)
+}
+
+/// Partial update that creates the document if it does not exist yet
+#[derive(Serialize)]
+struct Upsert<'a> {
+    doc: &'a Export,
+    doc_as_upsert: bool,
+}
+
+/// Serializes the bulk action for `export` followed by its source.
+///
+/// Documents are identified by [Export::id]. With [ExistsStrategy::Update]
+/// or [ExistsStrategy::Sync] they are written as upserts, which leave unchanged documents untouched,
+/// otherwise existing documents are replaced.
+pub fn bulk_operation(
+    export: &Export,
+    strategy: ExistsStrategy,
+) -> Result<String, serde_json::Error> {
+    let id = export.id();
+    Ok(match strategy {
+        ExistsStrategy::Update | ExistsStrategy::Sync => format!(
+            "{}\n{}",
+            json!({"update": {"_id": id}}),
+            serde_json::to_string(&Upsert {
+                doc: export,
+                doc_as_upsert: true,
+            })?
+        ),
+        _ => format!(
+            "{}\n{}",
+            json!({"index": {"_id": id}}),
+            serde_json::to_string(export)?
+        ),
+    })
+}
+
+/// Splits bulk operations into chunks whose request body is at most
+/// `max_bytes` large. Operations larger than `max_bytes` are sent on their own.
+pub(crate) fn chunk_by_size(operations: &[String], max_bytes: usize) -> Vec<&[String]> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut size = 0;
+
+    for (n, operation) in operations.iter().enumerate() {
+        // each operation is followed by a newline
+        let operation_size = operation.len() + 1;
+        if n > start && size + operation_size > max_bytes {
+            chunks.push(&operations[start..n]);
+            start = n;
+            size = 0;
+        }
+        size += operation_size;
+    }
+
+    if start < operations.len() {
+        chunks.push(&operations[start..]);
+    }
+    chunks
+}
+
+pub(crate) const SCROLL_TIMEOUT: &str = "1m";
+
+/// Fields of `flake_source` that are mapped as keywords
+const SOURCE_KEYWORDS: &[&str] = &["type", "owner", "repo", "url", "git_ref"];
+
+/// Query matching all documents exported from the same sources as `exports`,
+/// `None` if there are no such sources
+pub(crate) fn scope_query(exports: &[Export]) -> Option<Value> {
+    let mut sources = Vec::new();
+    let mut nixpkgs = false;
+
+    for export in exports {
+        match export.flake_info() {
+            Some(flake) => match &flake.source {
+                Some(source) if !sources.contains(source) => sources.push(source.clone()),
+                Some(_) => {}
+                None => warn!("Cannot delete stale documents of flake {}", flake.name),
+            },
+            None => nixpkgs = true,
+        }
+    }
+
+    let mut should: Vec<Value> = sources
+        .iter()
+        .map(|source| {
+            let source = serde_json::to_value(source).unwrap_or_default();
+            let mut filter = Vec::new();
+            let mut must_not = Vec::new();
+            for field in SOURCE_KEYWORDS {
+                let path = format!("flake_source.{}", field);
+                match source.get(field) {
+                    Some(Value::String(value)) => filter.push(json!({"term": {path: value}})),
+                    Some(Value::Null) => must_not.push(json!({"exists": {"field": path}})),
+                    _ => {}
+                }
+            }
+            json!({
+                "nested": {
+                    "path": "flake_source",
+                    "query": {"bool": {"filter": filter, "must_not": must_not}},
+                }
+            })
+        })
+        .collect();
+
+    if nixpkgs {
+        should.push(json!({"bool": {"must_not": {"exists": {"field": "flake_name"}}}}));
+    }
+
+    if should.is_empty() {
+        return None;
+    }
+    Some(json!({"bool": {"should": should, "minimum_should_match": 1}}))
+}
+
+/// A document that could not be indexed
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedDocument {
+    pub id: String,
+    pub status: u16,
+    pub reason: String,
+}
+
+impl FailedDocument {
+    /// Reads the result of a single bulk action, `None` if it succeeded
+    fn from_item(result: &Value) -> Option<Self> {
+        let status = result["status"].as_u64()? as u16;
+        if status < 300 {
+            return None;
+        }
+
+        Some(FailedDocument {
+            id: result["_id"].as_str().unwrap_or("(unknown)").to_owned(),
+            status,
+            reason: format!(
+                "{}: {}",
+                result["error"]["type"].as_str().unwrap_or("unknown"),
+                result["error"]["reason"].as_str().unwrap_or("")
+            ),
+        })
+    }
+}
+
+/// Reads the response of a bulk request sent for `pending`.
+///
+/// Returns the operations rejected because the cluster is overloaded, if
+/// `retries_left`, and adds all other failed documents to `failed`.
+pub(crate) fn triage_bulk_items<'a>(
+    pending: Vec<&'a String>,
+    response: &Value,
+    retries_left: bool,
+    failed: &mut Vec<FailedDocument>,
+) -> Vec<&'a String> {
+    // The bulk API returns HTTP 200 even when some items fail
+    // Reference: https://www.elastic.co/guide/en/elasticsearch/reference/current/docs-bulk.html
+    let items = response["items"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+
+    let mut retry = Vec::new();
+    for (operation, item) in pending.into_iter().zip(items) {
+        // each item is keyed by its action, i.e. `index` or `delete`
+        let (action, result) = match item.as_object().and_then(|item| item.iter().next()) {
+            Some(item) => item,
+            None => continue,
+        };
+        // the document to delete is already gone, e.g. deleted by a concurrent run
+        if action == "delete" && result["status"] == StatusCode::NOT_FOUND.as_u16() {
+            continue;
+        }
+        let failure = match FailedDocument::from_item(result) {
+            Some(failure) => failure,
+            None => continue,
+        };
+
+        if failure.status == StatusCode::TOO_MANY_REQUESTS.as_u16() && retries_left {
+            retry.push(operation);
+        } else {
+            warn!("Failed to index document: {}", failure);
+            failed.push(failure);
+        }
+    }
+    retry
+}
+
+/// Outcome of a single bulk request, see [Backend::send_bulk]
+pub enum BulkAttempt {
+    /// The cluster answered with the result of every operation
+    Items(Value),
+    /// The request failed in a way that may succeed when sent again, e.g. it
+    /// timed out or the cluster is overloaded (status 429 or 5xx)
+    Transient(ElasticsearchError),
+}
+
+/// Sends `operations` using bulk requests of at most
+/// [PushOptions::max_chunk_bytes] each.
+///
+/// Documents rejected because the cluster is overloaded (status 429) are
+/// sent again with exponential backoff. Documents that could not be
+/// indexed in the end are listed in a
+/// [ElasticsearchError::BulkRequestPartialFailure].
+async fn push_operations<B: Backend + ?Sized>(
+    backend: &B,
+    config: &Config<'_>,
+    operations: &[String],
+) -> Result<(), ElasticsearchError> {
+    let mut failed = Vec::new();
+    for chunk in chunk_by_size(operations, config.push.max_chunk_bytes) {
+        failed.extend(push_chunk(backend, config, chunk).await?);
+    }
+
+    if !failed.is_empty() {
+        return Err(ElasticsearchError::BulkRequestPartialFailure(failed));
+    }
+    Ok(())
+}
+
+/// Sends a single chunk, retrying rejected documents.
+/// Returns the documents that failed permanently.
+async fn push_chunk<B: Backend + ?Sized>(
+    backend: &B,
+    config: &Config<'_>,
+    operations: &[String],
+) -> Result<Vec<FailedDocument>, ElasticsearchError> {
+    let mut pending: Vec<&String> = operations.iter().collect();
+    let mut failed = Vec::new();
+    let mut backoff = config.push.initial_backoff;
+    let mut attempt = 0;
+
+    while !pending.is_empty() {
+        let retries_left = attempt < config.push.max_retries;
+
+        let retry = match backend.send_bulk(config.index, &pending).await? {
+            BulkAttempt::Transient(e) if retries_left => {
+                warn!("Bulk request failed: {}", e);
+                pending
+            }
+            BulkAttempt::Transient(e) => return Err(e),
+            BulkAttempt::Items(response) => {
+                triage_bulk_items(pending, &response, retries_left, &mut failed)
+            }
+        };
+
+        if !retry.is_empty() {
+            warn!(
+                "Retrying {} rejected documents in {:?}",
+                retry.len(),
+                backoff
+            );
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+        pending = retry;
+    }
+
+    Ok(failed)
+}
+
+/// Body of the initial search request of [Backend::delete_stale]
+pub(crate) fn stale_search(query: Value) -> Value {
+    json!({
+        "_source": false,
+        "size": 10_000,
+        "sort": ["_doc"],
+        "query": query,
+    })
+}
+
+/// Delete operations for the hits of a scroll `response` that are not in `ids`
+pub(crate) fn stale_deletions(response: &Value, ids: &HashSet<String>) -> Vec<String> {
+    response["hits"]["hits"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|hit| hit["_id"].as_str())
+        .filter(|id| !ids.contains(*id))
+        .map(|id| json!({"delete": {"_id": id}}).to_string())
+        .collect()
+}
+
+/// What to do with an index that already exists
+pub(crate) enum ExistingIndex {
+    /// Push into the index, replacing its provenance if `update_provenance` is set
+    Keep {
+        update_provenance: bool,
+    },
+    Recreate,
+}
+
+/// Decides how to handle an existing index following [Config::exists_strategy]
+pub(crate) fn existing_index(config: &Config<'_>) -> Result<ExistingIndex, ElasticsearchError> {
+    let (description, action) = match config.exists_strategy {
+        ExistsStrategy::Abort => {
+            warn!(
+                "Index \"{}\" already exists, strategy is: Abort push",
+                config.index
+            );
+            return Err(ElasticsearchError::IndexExistsError(
+                config.index.to_owned(),
+            ));
+        }
+        ExistsStrategy::Ignore => (
+            "Ignore, proceed push",
+            ExistingIndex::Keep {
+                update_provenance: false,
+            },
+        ),
+        ExistsStrategy::Update => (
+            "Update changed documents",
+            ExistingIndex::Keep {
+                update_provenance: true,
+            },
+        ),
+        ExistsStrategy::Sync => (
+            "Sync documents",
+            ExistingIndex::Keep {
+                update_provenance: true,
+            },
+        ),
+        ExistsStrategy::Recreate => ("Recreate index", ExistingIndex::Recreate),
+    };
+    warn!(
+        "Index \"{}\" already exists, strategy is: {}",
+        config.index, description
+    );
+    Ok(action)
+}
+
+/// The `_meta` of the first index in a get mapping `response`, `Value::Null` if it has none
+pub(crate) fn mapping_meta(response: &Value) -> Value {
+    // the response is keyed by the concrete index name, which differs if an alias was requested
+    response
+        .as_object()
+        .and_then(|indices| indices.values().next())
+        .map_or(Value::Null, |index| index["mappings"]["_meta"].clone())
+}
+
+/// Fails unless `meta` holds the current [schema_fingerprint]
+pub(crate) fn compare_fingerprint(index: &str, meta: &Value) -> Result<(), ElasticsearchError> {
+    let found = meta[FINGERPRINT_KEY].as_str().map(ToOwned::to_owned);
+    let expected = schema_fingerprint()?;
+
+    if found.as_ref() == Some(&expected) {
+        Ok(())
+    } else {
+        Err(ElasticsearchError::SchemaMismatch {
+            index: index.to_owned(),
+            expected,
+            found,
+        })
+    }
+}
+
+/// Reads the [Provenance] from the `_meta` of an index
+pub(crate) fn read_provenance(mut meta: Value) -> Result<Option<Provenance>, serde_json::Error> {
+    match meta.get_mut(PROVENANCE_KEY) {
+        Some(provenance) => Ok(Some(serde_json::from_value(provenance.take())?)),
+        None => Ok(None),
+    }
+}
+
+/// Body of a request that updates the `_meta` of an existing index
+pub(crate) fn provenance_update(
+    provenance: Option<&Provenance>,
+) -> Result<Value, ElasticsearchError> {
+    Ok(json!({ "_meta": index_meta(provenance)? }))
+}
+
+/// Alias actions pointing `alias` to `index` only
+pub(crate) fn alias_actions(previous: &[String], index: &str, alias: &str) -> Value {
+    let mut actions: Vec<Value> = previous
+        .iter()
+        .filter(|previous| *previous != index)
+        .map(|previous| json!({"remove": {"index": previous, "alias": alias}}))
+        .collect();
+    actions.push(json!({"add": {"index": index, "alias": alias}}));
+    json!({ "actions": actions })
+}
+
+/// Search request body aggregating the number of documents per `type`
+pub(crate) fn type_counts_search() -> Value {
+    json!({
+        "size": 0,
+        "aggs": {"types": {"terms": {"field": "type", "size": 100}}},
+    })
+}
+
+/// Reads the response of a [type_counts_search]
+pub(crate) fn type_counts(response: &Value) -> HashMap<String, u64> {
+    response["aggregations"]["types"]["buckets"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|bucket| {
+            Some((
+                bucket["key"].as_str()?.to_owned(),
+                bucket["doc_count"].as_u64()?,
+            ))
+        })
+        .collect()
+}
+
+/// Combines the responses of a get index request and a cat indices request
+/// (with columns `index` and `docs.count`) for the same pattern
+pub(crate) fn index_infos(indices: HashMap<String, Value>, documents: &[Value]) -> Vec<IndexInfo> {
+    let documents: HashMap<&str, u64> = documents
+        .iter()
+        .filter_map(|row| {
+            Some((
+                row["index"].as_str()?,
+                row["docs.count"].as_str()?.parse().ok()?,
+            ))
+        })
+        .collect();
+
+    let mut indices: Vec<IndexInfo> = indices
+        .into_iter()
+        .map(|(name, index)| {
+            let mut aliases: Vec<String> = index["aliases"]
+                .as_object()
+                .map(|aliases| aliases.keys().cloned().collect())
+                .unwrap_or_default();
+            aliases.sort();
+            IndexInfo {
+                parsed: IndexName::parse(&name),
+                documents: documents.get(name.as_str()).copied().unwrap_or(0),
+                created: index["settings"]["index"]["creation_date"]
+                    .as_str()
+                    .and_then(|date| date.parse().ok())
+                    .unwrap_or(0),
+                aliases,
+                name,
+            }
+        })
+        .collect();
+    indices.sort_by(|a, b| a.name.cmp(&b.name));
+    indices
+}
+
+impl std::fmt::Display for FailedDocument {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (status {}): {}", self.id, self.status, self.reason)
+    }
+}
+
 #[derive(Default)]
 pub struct Elasticsearch {
     client: Client,
@@ -235,13 +744,15 @@ pub struct Elasticsearch {
 pub enum ElasticsearchError {
     #[error("Transport failed to initialize: {0}")]
     TransportInitError(elasticsearch::Error),
+    #[error("Couldn't read certificate {0}: {1}")]
+    CertificateReadError(PathBuf, std::io::Error),
 
     #[error("Failed to send push exports: {0}")]
     PushError(elasticsearch::Error),
     #[error("Push exports returned bad result: {0:?}")]
     PushResponseError(response::Exception),
-    #[error("One or more bulk requests had failed items")]
-    BulkRequestPartialFailure,
+    #[error("{} documents could not be indexed:\n{}", .0.len(), .0.iter().map(ToString::to_string).collect::<Vec<String>>().join("\n"))]
+    BulkRequestPartialFailure(Vec<FailedDocument>),
 
     #[error("Failed to iitialize index: {0}")]
     InitIndexError(elasticsearch::Error),
@@ -256,138 +767,108 @@ pub enum ElasticsearchError {
 
     #[error("An index with the name \"{0}\" already exists and the (default) stategy is abort")]
     IndexExistsError(String),
-}
 
-impl Elasticsearch {
-    pub fn new(url: &str) -> Result<Self, ElasticsearchError> {
-        let transport =
-            Transport::single_node(url).map_err(ElasticsearchError::TransportInitError)?;
-        let client = Client::new(transport);
-        Ok(Elasticsearch { client })
-    }
-    pub fn with_transport(transport: Transport) -> Self {
-        let client = Client::new(transport);
-        Elasticsearch { client }
-    }
+    #[error("Index \"{0}\" failed validation: {1}")]
+    ValidationError(String, String),
 
-    pub async fn push_exports(
-        &self,
-        config: &Config<'_>,
-        exports: &[Export],
-    ) -> Result<(), ElasticsearchError> {
-        // let exports: Result<Vec<Value>, serde_json::Error> = exports.iter().map(serde_json::to_value).collect();
-        // let exports = exports?;
-        let bodies = exports.chunks(7_000).map(|chunk| {
-            chunk
-                .iter()
-                .map(|e| BulkOperation::from(BulkOperation::index(e)))
-        });
+    #[error("Invalid url {0}: {1}")]
+    InvalidUrl(String, String),
+    #[error("{0} are not supported by this backend")]
+    UnsupportedAuth(&'static str),
+    #[error("Request failed: {0}")]
+    RequestError(#[from] reqwest::Error),
+    #[error("Request failed with status {0}: {1}")]
+    ResponseError(u16, String),
 
-        let mut had_failures = false;
+    #[error("Index \"{index}\" has schema fingerprint {}, but this flake-info writes {expected}, push to a new index instead", .found.as_deref().unwrap_or("(none)"))]
+    SchemaMismatch {
+        index: String,
+        expected: String,
+        found: Option<String>,
+    },
+    #[error("Couldn't compute the schema fingerprint: {0}")]
+    FingerprintError(anyhow::Error),
+}
 
-        for body in bodies {
-            let response = self
-                .client
-                .bulk(elasticsearch::BulkParts::Index(config.index))
-                .body(body.collect())
-                .send()
-                .await
-                .map_err(ElasticsearchError::PushError)?;
+/// How to authenticate with Elasticsearch
+#[derive(Debug, Clone)]
+pub enum Auth {
+    Basic {
+        user: String,
+        password: String,
+    },
+    ApiKey {
+        id: String,
+        key: String,
+    },
+    Bearer(String),
+    /// PEM file containing the client certificate and its private key
+    ClientCertificate(PathBuf),
+}
 
-            let response = dbg!(response);
-            if response.status_code().is_client_error() || response.status_code().is_server_error()
-            {
-                return response
-                    .exception()
-                    .await
-                    .map_err(ElasticsearchError::ClientError)?
-                    .map(ElasticsearchError::PushResponseError)
-                    .map_or(Ok(()), Err);
-            }
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionOptions {
+    pub auth: Option<Auth>,
+    /// PEM bundle of CA certificates to trust in addition to the system ones
+    pub ca_certificate: Option<PathBuf>,
+}
 
-            // Elasticsearch bulk API returns HTTP 200 even when some items fail
-            // Reference: https://www.elastic.co/guide/en/elasticsearch/reference/current/docs-bulk.html
-            let response_body: serde_json::Value = response
-                .json()
-                .await
-                .map_err(ElasticsearchError::ClientError)?;
-
-            // Warn if any items in the bulk request failed
-            if let Some(true) = response_body.get("errors").and_then(|v| v.as_bool()) {
-                if let Some(items) = response_body.get("items").and_then(|v| v.as_array()) {
-                    let mut failed_count = 0;
-
-                    for (idx, item) in items.iter().enumerate() {
-                        if let Some(index_result) = item.get("index") {
-                            if let Some(status) =
-                                index_result.get("status").and_then(|s| s.as_u64())
-                            {
-                                if status >= 400 {
-                                    failed_count += 1;
-                                    let error_type = index_result
-                                        .get("error")
-                                        .and_then(|e| e.get("type"))
-                                        .and_then(|t| t.as_str())
-                                        .unwrap_or("unknown");
-                                    let error_reason = index_result
-                                        .get("error")
-                                        .and_then(|e| e.get("reason"))
-                                        .and_then(|r| r.as_str())
-                                        .unwrap_or("");
-                                    warn!(
-                                        "  Item {}: status {}, type: {}, reason: {}",
-                                        idx, status, error_type, error_reason
-                                    );
-                                }
-                            }
-                        }
-                    }
+pub(crate) fn read_certificate(path: &Path) -> Result<Vec<u8>, ElasticsearchError> {
+    fs::read(path).map_err(|e| ElasticsearchError::CertificateReadError(path.to_owned(), e))
+}
 
-                    warn!(
-                        "Bulk request had {} failed items out of {}",
-                        failed_count,
-                        items.len()
-                    );
-                    had_failures = true;
+impl Elasticsearch {
+    pub fn new(url: &str) -> Result<Self, ElasticsearchError> {
+        Self::connect(url, &ConnectionOptions::default())
+    }
+
+    /// Connects to a single node at `url`, authenticating as configured in `options`
+    pub fn connect(url: &str, options: &ConnectionOptions) -> Result<Self, ElasticsearchError> {
+        let url = Url::parse(url).map_err(|e| ElasticsearchError::TransportInitError(e.into()))?;
+        let mut builder = TransportBuilder::new(SingleNodeConnectionPool::new(url));
+
+        if let Some(auth) = &options.auth {
+            builder = builder.auth(match auth {
+                Auth::Basic { user, password } => {
+                    Credentials::Basic(user.to_owned(), password.to_owned())
                 }
-            }
+                Auth::ApiKey { id, key } => Credentials::ApiKey(id.to_owned(), key.to_owned()),
+                Auth::Bearer(token) => Credentials::Bearer(token.to_owned()),
+                Auth::ClientCertificate(path) => {
+                    Credentials::Certificate(ClientCertificate::Pem(read_certificate(path)?))
+                }
+            });
         }
 
-        if had_failures {
-            return Err(ElasticsearchError::BulkRequestPartialFailure);
+        if let Some(path) = &options.ca_certificate {
+            let certificate = Certificate::from_pem(&read_certificate(path)?)
+                .map_err(ElasticsearchError::TransportInitError)?;
+            builder = builder.cert_validation(CertificateValidation::Full(certificate));
         }
 
-        Ok(())
+        let transport = builder
+            .build()
+            .map_err(|e| ElasticsearchError::TransportInitError(e.into()))?;
+        Ok(Self::with_transport(transport))
+    }
+    pub fn with_transport(transport: Transport) -> Self {
+        let client = Client::new(transport);
+        Elasticsearch { client }
     }
 
     pub async fn ensure_index(&self, config: &Config<'_>) -> Result<(), ElasticsearchError> {
         let exists = self.check_index(config).await?;
 
         if exists {
-            match config.exists_strategy {
-                ExistsStrategy::Abort => {
-                    warn!(
-                        "Index \"{}\" already exists, strategy is: Abort push",
-                        config.index
-                    );
-                    return Err(ElasticsearchError::IndexExistsError(
-                        config.index.to_owned(),
-                    ));
-                }
-                ExistsStrategy::Ignore => {
-                    warn!(
-                        "Index \"{}\" already exists, strategy is: Ignore, proceed push",
-                        config.index
-                    );
+            match existing_index(config)? {
+                ExistingIndex::Keep { update_provenance } => {
+                    self.check_fingerprint(config.index).await?;
+                    if update_provenance {
+                        self.write_provenance(config).await?;
+                    }
                     return Ok(());
                 }
-                ExistsStrategy::Recreate => {
-                    warn!(
-                        "Index \"{}\" already exists, strategy is: Recreate index",
-                        config.index
-                    );
-                    self.clear_index(config).await?;
-                }
+                ExistingIndex::Recreate => self.clear_index(config).await?,
             }
         }
 
@@ -395,7 +876,7 @@ impl Elasticsearch {
             .client
             .indices()
             .create(IndicesCreateParts::Index(config.index))
-            .body(MAPPING.as_object())
+            .body(index_body(config.provenance)?)
             .send()
             .await
             .map_err(ElasticsearchError::InitIndexError)?;
@@ -410,11 +891,65 @@ impl Elasticsearch {
         Ok(())
     }
 
+    /// The `_meta` of the mapping of `index`, `Value::Null` if it has none
+    async fn fetch_meta(&self, index: &str) -> Result<Value, ElasticsearchError> {
+        let mapping = self
+            .client
+            .indices()
+            .get_mapping(IndicesGetMappingParts::Index(&[index]))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status_code())
+            .map_err(ElasticsearchError::ClientError)?
+            .json::<Value>()
+            .await
+            .map_err(ElasticsearchError::ClientError)?;
+
+        Ok(mapping_meta(&mapping))
+    }
+
+    /// Fails if `index` was not created with the current [schema_fingerprint]
+    pub async fn check_fingerprint(&self, index: &str) -> Result<(), ElasticsearchError> {
+        compare_fingerprint(index, &self.fetch_meta(index).await?)
+    }
+
+    /// Provenance recorded when `index` was created or last updated
+    pub async fn provenance(&self, index: &str) -> Result<Option<Provenance>, ElasticsearchError> {
+        Ok(read_provenance(self.fetch_meta(index).await?)?)
+    }
+
+    /// Replaces the provenance of an existing index with the one of `config`
+    async fn write_provenance(&self, config: &Config<'_>) -> Result<(), ElasticsearchError> {
+        if config.provenance.is_none() {
+            return Ok(());
+        }
+
+        let response = self
+            .client
+            .indices()
+            .put_mapping(IndicesPutMappingParts::Index(&[config.index]))
+            .body(provenance_update(config.provenance)?)
+            .send()
+            .await
+            .map_err(ElasticsearchError::InitIndexError)?;
+
+        response
+            .exception()
+            .await
+            .map_err(ElasticsearchError::ClientError)?
+            .map(ElasticsearchError::PushResponseError)
+            .map_or(Ok(()), Err)
+    }
+
     pub async fn check_index(&self, config: &Config<'_>) -> Result<bool, ElasticsearchError> {
+        self.index_exists(config.index).await
+    }
+
+    pub async fn index_exists(&self, index: &str) -> Result<bool, ElasticsearchError> {
         let response = self
             .client
             .indices()
-            .exists(IndicesExistsParts::Index(&[config.index]))
+            .exists(IndicesExistsParts::Index(&[index]))
             .send()
             .await
             .map_err(ElasticsearchError::InitIndexError)?;
@@ -423,10 +958,14 @@ impl Elasticsearch {
     }
 
     pub async fn clear_index(&self, config: &Config<'_>) -> Result<(), ElasticsearchError> {
+        self.delete_index(config.index).await
+    }
+
+    pub async fn delete_index(&self, index: &str) -> Result<(), ElasticsearchError> {
         let response = self
             .client
             .indices()
-            .delete(IndicesDeleteParts::Index(&[config.index]))
+            .delete(IndicesDeleteParts::Index(&[index]))
             .send()
             .await
             .map_err(ElasticsearchError::InitIndexError)?;
@@ -439,93 +978,1411 @@ impl Elasticsearch {
             .map_or(Ok(()), Err)
     }
 
+    /// Points `alias` to `index` and removes it from all other indices in a
+    /// single request, so that the alias never points to no index at all.
     pub async fn write_alias(
         &self,
         _config: &Config<'_>,
         index: &str,
         alias: &str,
     ) -> Result<(), ElasticsearchError> {
-        // delete old alias
-        info!("Try deleting old alias");
+        let previous = self.alias_indices(alias).await?;
+
+        info!("Switching alias {} from {:?} to {}", alias, previous, index);
         let response = self
             .client
             .indices()
-            .get_alias(IndicesGetAliasParts::Name(&[alias]))
+            .update_aliases()
+            .body(alias_actions(&previous, index, alias))
             .send()
             .await
             .map_err(ElasticsearchError::InitIndexError)?;
-        let indices = response
-            .json::<HashMap<String, Value>>()
+
+        response
+            .exception()
             .await
-            .map_err(ElasticsearchError::InitIndexError)?
-            .keys()
-            .cloned()
-            .collect::<Vec<String>>();
+            .map_err(ElasticsearchError::ClientError)?
+            .map(ElasticsearchError::PushResponseError)
+            .map_or(Ok(()), Err)
+    }
 
-        self.client
-            .indices()
-            .delete_alias(IndicesDeleteAliasParts::IndexName(
-                &indices.iter().map(AsRef::as_ref).collect::<Vec<_>>(),
-                &[alias],
-            ))
+    /// Number of documents per `type` in `index`
+    pub async fn count_types(
+        &self,
+        index: &str,
+    ) -> Result<HashMap<String, u64>, ElasticsearchError> {
+        let response = self
+            .client
+            .search(SearchParts::Index(&[index]))
+            .body(type_counts_search())
             .send()
             .await
-            .map_err(ElasticsearchError::InitIndexError)?;
+            .map_err(ElasticsearchError::ClientError)?
+            .json::<Value>()
+            .await
+            .map_err(ElasticsearchError::ClientError)?;
+
+        Ok(type_counts(&response))
+    }
 
-        // put new alias
-        info!("Putting new alias");
+    /// Runs `query` against `index` (or an alias), returning at most `size` exports
+    pub async fn search(
+        &self,
+        index: &str,
+        query: &Query,
+        size: usize,
+    ) -> Result<Vec<Export>, ElasticsearchError> {
         let response = self
+            .client
+            .search(SearchParts::Index(&[index]))
+            .body(query.body(size))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status_code())
+            .map_err(ElasticsearchError::ClientError)?
+            .json::<Value>()
+            .await
+            .map_err(ElasticsearchError::ClientError)?;
+
+        Ok(read_hits(&response)?)
+    }
+
+    /// Lists the indices matching `pattern` with their document counts and aliases
+    pub async fn list_indices(&self, pattern: &str) -> Result<Vec<IndexInfo>, ElasticsearchError> {
+        let indices = self
             .client
             .indices()
-            .put_alias(IndicesPutAliasParts::IndexName(&[index], alias))
+            .get(IndicesGetParts::Index(&[pattern]))
             .send()
             .await
-            .map_err(ElasticsearchError::InitIndexError)?;
+            .and_then(|response| response.error_for_status_code())
+            .map_err(ElasticsearchError::ClientError)?
+            .json::<HashMap<String, Value>>()
+            .await
+            .map_err(ElasticsearchError::ClientError)?;
 
-        dbg!(response)
-            .exception()
+        let documents = self
+            .client
+            .cat()
+            .indices(CatIndicesParts::Index(&[pattern]))
+            .format("json")
+            .h(&["index", "docs.count"])
+            .send()
             .await
             .map_err(ElasticsearchError::ClientError)?
-            .map(ElasticsearchError::PushResponseError)
-            .map_or(Ok(()), Err)
+            .json::<Vec<Value>>()
+            .await
+            .map_err(ElasticsearchError::ClientError)?;
+
+        Ok(index_infos(indices, &documents))
     }
 }
 
-#[derive(Debug)]
-pub struct Config<'a> {
-    pub index: &'a str,
-    pub exists_strategy: ExistsStrategy,
-}
+/// A search engine exports are pushed to, i.e. [Elasticsearch] or, with the
+/// `opensearch` feature, [OpenSearch](crate::opensearch::OpenSearch).
+///
+/// Both use the same mapping, document ids and index naming.
+#[allow(async_fn_in_trait)]
+pub trait Backend {
+    /// Creates the index of `config`, following its [ExistsStrategy] if it exists
+    async fn ensure_index(&self, config: &Config<'_>) -> Result<(), ElasticsearchError>;
 
-arg_enum! {
-    /// Different strategies to deal with eisting indices
-    /// Abort: cancel push, return with an error
-    /// Ignore: Reuse existing index, appending new data
-    /// Recreate: Drop the existing index and start with a new one
-    #[derive(Debug, Clone, Copy)]
-    pub enum ExistsStrategy {
-        Abort,
-        Ignore,
-        Recreate,
-    }
-}
+    async fn index_exists(&self, index: &str) -> Result<bool, ElasticsearchError>;
 
-#[cfg(test)]
-mod tests {
-    use std::path::Path;
+    async fn delete_index(&self, index: &str) -> Result<(), ElasticsearchError>;
 
-    use super::*;
+    /// Indexes `exports` into the index of `config`, see [push_operations]
+    async fn push_exports(
+        &self,
+        config: &Config<'_>,
+        exports: &[Export],
+    ) -> Result<(), ElasticsearchError> {
+        let operations = exports
+            .iter()
+            .map(|export| bulk_operation(export, config.exists_strategy))
+            .collect::<Result<Vec<_>, _>>()?;
+        push_operations(self, config, &operations).await
+    }
+
+    /// Deletes documents that belong to the same sources as `exports` but are
+    /// not part of `exports` anymore. Returns the number of deleted documents.
+    ///
+    /// The sources are the flakes of `exports` and, if `exports` contains
+    /// nixpkgs exports, all documents that do not belong to a flake. Other
+    /// documents in the index, e.g. those of group members that failed to
+    /// evaluate, are kept.
+    async fn delete_stale(
+        &self,
+        config: &Config<'_>,
+        exports: &[Export],
+    ) -> Result<usize, ElasticsearchError> {
+        let query = match scope_query(exports) {
+            Some(query) => query,
+            None => return Ok(0),
+        };
+        let ids: HashSet<String> = exports.iter().map(Export::id).collect();
+
+        let mut stale = Vec::new();
+        let mut response = self
+            .scroll_search(config.index, stale_search(query))
+            .await?;
+        while response["hits"]["hits"]
+            .as_array()
+            .is_some_and(|hits| !hits.is_empty())
+        {
+            stale.extend(stale_deletions(&response, &ids));
+            response = self.scroll(response["_scroll_id"].clone()).await?;
+        }
+
+        if let Some(scroll_id) = response["_scroll_id"].as_str() {
+            self.clear_scroll(scroll_id).await?;
+        }
+
+        info!("Deleting {} stale documents", stale.len());
+        push_operations(self, config, &stale).await?;
+        Ok(stale.len())
+    }
+
+    /// Checks that `index` is fit to replace the index `alias` points to
+    async fn validate_index(
+        &self,
+        index: &str,
+        alias: &str,
+        validation: &Validation,
+    ) -> Result<(), ElasticsearchError> {
+        // make the pushed documents visible to searches
+        self.refresh(index).await?;
+
+        let counts = self.count_types(index).await?;
+        let previous = match self.alias_indices(alias).await?.first() {
+            Some(previous) if previous != index => Some(self.count_types(previous).await?),
+            _ => None,
+        };
+
+        validation
+            .check(&counts, previous.as_ref())
+            .map_err(|reason| ElasticsearchError::ValidationError(index.to_owned(), reason))
+    }
+
+    /// Atomically points `alias` to `index` only
+    async fn write_alias(
+        &self,
+        config: &Config<'_>,
+        index: &str,
+        alias: &str,
+    ) -> Result<(), ElasticsearchError>;
+
+    async fn list_indices(&self, pattern: &str) -> Result<Vec<IndexInfo>, ElasticsearchError>;
+
+    /// Deletes indices matching `pattern` following [select_garbage].
+    /// Returns the deleted indices, or those that would be deleted if `dry_run` is set.
+    async fn prune_indices(
+        &self,
+        pattern: &str,
+        keep: usize,
+        dry_run: bool,
+    ) -> Result<Vec<String>, ElasticsearchError> {
+        let indices = self.list_indices(pattern).await?;
+        let garbage: Vec<String> = select_garbage(&indices, keep)
+            .into_iter()
+            .map(|index| index.name.to_owned())
+            .collect();
+
+        if !garbage.is_empty() && !dry_run {
+            info!("Deleting old indices {:?}", garbage);
+            self.delete_index(&garbage.join(",")).await?;
+        }
+
+        Ok(garbage)
+    }
+
+    /// Number of documents per `type` in `index`
+    async fn count_types(&self, index: &str) -> Result<HashMap<String, u64>, ElasticsearchError>;
+
+    /// Provenance recorded when `index` was created or last updated
+    async fn provenance(&self, index: &str) -> Result<Option<Provenance>, ElasticsearchError>;
+
+    /// Runs `query` against `index` (or an alias), returning at most `size` exports
+    async fn search(
+        &self,
+        index: &str,
+        query: &Query,
+        size: usize,
+    ) -> Result<Vec<Export>, ElasticsearchError>;
+
+    // The requests the operations above are built on
+
+    /// Sends a single bulk request to `index`. Fails if the request was
+    /// rejected and sending it again would not help.
+    async fn send_bulk(
+        &self,
+        index: &str,
+        operations: &[&String],
+    ) -> Result<BulkAttempt, ElasticsearchError>;
+
+    /// First page of a search of `index` kept open for [SCROLL_TIMEOUT]
+    async fn scroll_search(&self, index: &str, body: Value) -> Result<Value, ElasticsearchError>;
+
+    /// Next page of a search started with [Backend::scroll_search]
+    async fn scroll(&self, scroll_id: Value) -> Result<Value, ElasticsearchError>;
+
+    async fn clear_scroll(&self, scroll_id: &str) -> Result<(), ElasticsearchError>;
+
+    /// Makes the documents pushed to `index` visible to searches
+    async fn refresh(&self, index: &str) -> Result<(), ElasticsearchError>;
+
+    /// Indices `alias` currently points to
+    async fn alias_indices(&self, alias: &str) -> Result<Vec<String>, ElasticsearchError>;
+}
+
+impl Backend for Elasticsearch {
+    async fn ensure_index(&self, config: &Config<'_>) -> Result<(), ElasticsearchError> {
+        Elasticsearch::ensure_index(self, config).await
+    }
+
+    async fn index_exists(&self, index: &str) -> Result<bool, ElasticsearchError> {
+        Elasticsearch::index_exists(self, index).await
+    }
+
+    async fn delete_index(&self, index: &str) -> Result<(), ElasticsearchError> {
+        Elasticsearch::delete_index(self, index).await
+    }
+
+    async fn write_alias(
+        &self,
+        config: &Config<'_>,
+        index: &str,
+        alias: &str,
+    ) -> Result<(), ElasticsearchError> {
+        Elasticsearch::write_alias(self, config, index, alias).await
+    }
+
+    async fn list_indices(&self, pattern: &str) -> Result<Vec<IndexInfo>, ElasticsearchError> {
+        Elasticsearch::list_indices(self, pattern).await
+    }
+
+    async fn count_types(&self, index: &str) -> Result<HashMap<String, u64>, ElasticsearchError> {
+        Elasticsearch::count_types(self, index).await
+    }
+
+    async fn provenance(&self, index: &str) -> Result<Option<Provenance>, ElasticsearchError> {
+        Elasticsearch::provenance(self, index).await
+    }
+
+    async fn search(
+        &self,
+        index: &str,
+        query: &Query,
+        size: usize,
+    ) -> Result<Vec<Export>, ElasticsearchError> {
+        Elasticsearch::search(self, index, query, size).await
+    }
+
+    async fn send_bulk(
+        &self,
+        index: &str,
+        operations: &[&String],
+    ) -> Result<BulkAttempt, ElasticsearchError> {
+        let body: Vec<&str> = operations
+            .iter()
+            .map(|operation| operation.as_str())
+            .collect();
+        let response = match self
+            .client
+            .bulk(elasticsearch::BulkParts::Index(index))
+            .body(body)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => return Ok(BulkAttempt::Transient(ElasticsearchError::PushError(e))),
+        };
+
+        let status = response.status_code();
+        if status.is_client_error() || status.is_server_error() {
+            let error = match response
+                .exception()
+                .await
+                .map_err(ElasticsearchError::ClientError)?
+            {
+                Some(exception) => ElasticsearchError::PushResponseError(exception),
+                None => ElasticsearchError::ResponseError(status.as_u16(), String::new()),
+            };
+            return if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+                Ok(BulkAttempt::Transient(error))
+            } else {
+                Err(error)
+            };
+        }
+
+        Ok(BulkAttempt::Items(
+            response
+                .json()
+                .await
+                .map_err(ElasticsearchError::ClientError)?,
+        ))
+    }
+
+    async fn scroll_search(&self, index: &str, body: Value) -> Result<Value, ElasticsearchError> {
+        self.client
+            .search(SearchParts::Index(&[index]))
+            .scroll(SCROLL_TIMEOUT)
+            .body(body)
+            .send()
+            .await
+            .map_err(ElasticsearchError::ClientError)?
+            .json::<Value>()
+            .await
+            .map_err(ElasticsearchError::ClientError)
+    }
+
+    async fn scroll(&self, scroll_id: Value) -> Result<Value, ElasticsearchError> {
+        self.client
+            .scroll(ScrollParts::None)
+            .body(json!({"scroll": SCROLL_TIMEOUT, "scroll_id": scroll_id}))
+            .send()
+            .await
+            .map_err(ElasticsearchError::ClientError)?
+            .json::<Value>()
+            .await
+            .map_err(ElasticsearchError::ClientError)
+    }
+
+    async fn clear_scroll(&self, scroll_id: &str) -> Result<(), ElasticsearchError> {
+        self.client
+            .clear_scroll(ClearScrollParts::None)
+            .body(json!({ "scroll_id": [scroll_id] }))
+            .send()
+            .await
+            .map_err(ElasticsearchError::ClientError)?;
+        Ok(())
+    }
+
+    async fn refresh(&self, index: &str) -> Result<(), ElasticsearchError> {
+        self.client
+            .indices()
+            .refresh(IndicesRefreshParts::Index(&[index]))
+            .send()
+            .await
+            .map_err(ElasticsearchError::ClientError)?;
+        Ok(())
+    }
+
+    async fn alias_indices(&self, alias: &str) -> Result<Vec<String>, ElasticsearchError> {
+        let response = self
+            .client
+            .indices()
+            .get_alias(IndicesGetAliasParts::Name(&[alias]))
+            .send()
+            .await
+            .map_err(ElasticsearchError::InitIndexError)?;
+
+        if response.status_code() == StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+
+        Ok(response
+            .json::<HashMap<String, Value>>()
+            .await
+            .map_err(ElasticsearchError::InitIndexError)?
+            .into_keys()
+            .collect())
+    }
+}
+
+/// The parts of an automatically named index, `{kind}-{schema}-{name}-{hash}`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IndexName {
+    /// `flake`, `nixos` or `group`
+    pub kind: String,
+    pub schema: usize,
+    /// Channel, flake or group name
+    pub name: String,
+    pub hash: String,
+}
+
+impl IndexName {
+    pub fn parse(index: &str) -> Option<Self> {
+        let mut parts = index.splitn(3, '-');
+        let kind = parts.next()?;
+        let schema = parts.next()?.parse().ok()?;
+        // names may contain dashes, hashes do not
+        let (name, hash) = parts.next()?.rsplit_once('-')?;
+
+        if !matches!(kind, "flake" | "nixos" | "group") || name.is_empty() || hash.is_empty() {
+            return None;
+        }
+
+        Some(IndexName {
+            kind: kind.to_owned(),
+            schema,
+            name: name.to_owned(),
+            hash: hash.to_owned(),
+        })
+    }
+}
+
+impl std::fmt::Display for IndexName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}-{}-{}",
+            self.kind, self.schema, self.name, self.hash
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexInfo {
+    pub name: String,
+    /// `None` if the index was not named automatically
+    pub parsed: Option<IndexName>,
+    pub documents: u64,
+    pub aliases: Vec<String>,
+    /// Creation time in milliseconds since the epoch
+    pub created: u64,
+}
+
+/// Selects the indices to delete so that only the `keep` most recently
+/// created indices of each kind, schema and name remain.
+///
+/// Indices that are not named automatically or that have an alias are never selected.
+pub fn select_garbage(indices: &[IndexInfo], keep: usize) -> Vec<&IndexInfo> {
+    let mut groups: HashMap<(&str, usize, &str), Vec<&IndexInfo>> = HashMap::new();
+    for index in indices {
+        if let Some(parsed) = &index.parsed {
+            groups
+                .entry((parsed.kind.as_str(), parsed.schema, parsed.name.as_str()))
+                .or_default()
+                .push(index);
+        }
+    }
+
+    let mut garbage: Vec<&IndexInfo> = groups
+        .into_values()
+        .flat_map(|mut group| {
+            group.sort_by(|a, b| b.created.cmp(&a.created));
+            group.into_iter().skip(keep)
+        })
+        .filter(|index| index.aliases.is_empty())
+        .collect();
+    garbage.sort_by(|a, b| a.name.cmp(&b.name));
+    garbage
+}
+
+/// The alias an [ElasticSink] switches to its index once all exports are pushed
+#[derive(Debug, Clone)]
+pub struct AliasOptions {
+    pub alias: String,
+    pub validation: Validation,
+    /// Pattern matching older indices of the alias and how many of them to keep
+    pub prune: Option<(String, usize)>,
+}
+
+/// A [Sink] pushing exports into the index of a [Config] on any [Backend]
+pub struct ElasticSink<'a, B: Backend> {
+    backend: &'a B,
+    config: &'a Config<'a>,
+    alias: Option<AliasOptions>,
+    /// Exports pushed so far, only kept to delete stale documents when syncing
+    pushed: Vec<Export>,
+    /// Whether the index was created by this sink, and may be deleted on rollback
+    created: bool,
+}
+
+impl<'a, B: Backend> ElasticSink<'a, B> {
+    pub fn new(backend: &'a B, config: &'a Config<'a>, alias: Option<AliasOptions>) -> Self {
+        ElasticSink {
+            backend,
+            config,
+            alias,
+            pushed: Vec::new(),
+            created: false,
+        }
+    }
+}
+
+impl<B: Backend> Sink for ElasticSink<'_, B> {
+    /// Fails with [ElasticsearchError::IndexExistsError] if the index exists
+    /// and the [ExistsStrategy] is `Abort`
+    async fn prepare(&mut self) -> anyhow::Result<()> {
+        let existed = self.backend.index_exists(self.config.index).await?;
+        self.backend.ensure_index(self.config).await?;
+        self.created = !existed || self.config.exists_strategy == ExistsStrategy::Recreate;
+        Ok(())
+    }
+
+    async fn write_batch(&mut self, exports: &[Export]) -> anyhow::Result<()> {
+        self.backend
+            .push_exports(self.config, exports)
+            .await
+            .context("Failed to push results to elasticsearch")?;
+        if self.config.exists_strategy == ExistsStrategy::Sync {
+            self.pushed.extend_from_slice(exports);
+        }
+        Ok(())
+    }
+
+    async fn finalize(&mut self) -> anyhow::Result<()> {
+        let index = self.config.index;
+
+        if self.config.exists_strategy == ExistsStrategy::Sync {
+            let deleted = self
+                .backend
+                .delete_stale(self.config, &self.pushed)
+                .await
+                .context("Failed to delete stale documents")?;
+            info!("Deleted {} stale documents", deleted);
+        }
+
+        if let Some(AliasOptions {
+            alias,
+            validation,
+            prune,
+        }) = &self.alias
+        {
+            self.backend
+                .validate_index(index, alias, validation)
+                .await
+                .with_context(|| format!("Not switching alias {}", alias))?;
+
+            self.backend
+                .write_alias(self.config, index, alias)
+                .await
+                .context("Failed to create alias")?;
+
+            if let Some((pattern, keep)) = prune {
+                let deleted = self
+                    .backend
+                    .prune_indices(pattern, *keep, false)
+                    .await
+                    .context("Failed to delete old indices")?;
+                info!("Deleted {} old indices", deleted.len());
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the index if this sink created it and the exports failed.
+    ///
+    /// If pushing or validating failed the index is kept, so that it can be
+    /// inspected and the push retried; the alias was not switched to it.
+    /// Documents pushed into an index that existed before stay in place.
+    async fn rollback(&mut self, failure: Failure) -> anyhow::Result<()> {
+        if failure == Failure::Sink {
+            warn!(
+                "Keeping index {} for inspection, no alias was switched to it",
+                self.config.index
+            );
+            self.pushed.clear();
+        } else if self.created {
+            warn!("Deleting incomplete index {}", self.config.index);
+            self.backend.delete_index(self.config.index).await?;
+            self.created = false;
+        } else {
+            self.pushed.clear();
+        }
+        Ok(())
+    }
+}
+
+/// A [Sink] writing exports as `index` actions of a `_bulk` request to a file.
+///
+/// The actions do not name an index, so the request has to target one, e.g.
+/// `curl -XPOST -H 'Content-Type: application/x-ndjson' $ES/$INDEX/_bulk --data-binary @file`.
+/// The file only replaces an earlier one once all exports were written.
+pub struct BulkFileSink {
+    file: PartialFile,
+}
+
+impl BulkFileSink {
+    pub fn new(path: &Path) -> Self {
+        BulkFileSink {
+            file: PartialFile::new(path),
+        }
+    }
+}
+
+impl Sink for BulkFileSink {
+    async fn prepare(&mut self) -> anyhow::Result<()> {
+        self.file.create()?;
+        Ok(())
+    }
+
+    async fn write_batch(&mut self, exports: &[Export]) -> anyhow::Result<()> {
+        let writer = self.file.writer()?;
+        for export in exports {
+            writer.write_all(bulk_operation(export, ExistsStrategy::Ignore)?.as_bytes())?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    async fn finalize(&mut self) -> anyhow::Result<()> {
+        self.file.persist()
+    }
+
+    async fn rollback(&mut self, _failure: Failure) -> anyhow::Result<()> {
+        self.file.discard()
+    }
+}
+
+/// Requirements an index has to meet before an alias is switched to it
+#[derive(Debug, Clone, Copy)]
+pub struct Validation {
+    /// Minimum number of documents of each type found in the new or the previous index
+    pub min_documents: u64,
+    /// Minimum ratio of the number of documents in the new and the previous index
+    pub min_ratio: f64,
+}
+
+impl Default for Validation {
+    fn default() -> Self {
+        Validation {
+            min_documents: 1,
+            min_ratio: 0.5,
+        }
+    }
+}
+
+impl Validation {
+    pub(crate) fn check(
+        &self,
+        counts: &HashMap<String, u64>,
+        previous: Option<&HashMap<String, u64>>,
+    ) -> Result<(), String> {
+        if counts.is_empty() {
+            return Err("the index is empty".to_owned());
+        }
+
+        let mut types: Vec<&String> = counts
+            .keys()
+            .chain(previous.into_iter().flat_map(HashMap::keys))
+            .collect();
+        types.sort();
+        types.dedup();
+
+        for document_type in types {
+            let count = counts.get(document_type).copied().unwrap_or(0);
+            if count < self.min_documents {
+                return Err(format!(
+                    "found {} documents of type {}, expected at least {}",
+                    count, document_type, self.min_documents
+                ));
+            }
+        }
+
+        if let Some(previous) = previous {
+            let total: u64 = counts.values().sum();
+            let previous_total: u64 = previous.values().sum();
+            if (total as f64) < self.min_ratio * previous_total as f64 {
+                return Err(format!(
+                    "found {} documents, the previous index had {}",
+                    total, previous_total
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Config<'a> {
+    pub index: &'a str,
+    pub exists_strategy: ExistsStrategy,
+    pub push: PushOptions,
+    /// Recorded in the `_meta` of the index when it is created
+    pub provenance: Option<&'a Provenance>,
+}
+
+/// Size limits and retry behaviour of bulk requests
+#[derive(Debug, Clone, Copy)]
+pub struct PushOptions {
+    /// Maximum size of a single bulk request body in bytes
+    pub max_chunk_bytes: usize,
+    /// How often documents rejected by an overloaded cluster are sent again
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every further retry
+    pub initial_backoff: Duration,
+}
+
+impl Default for PushOptions {
+    fn default() -> Self {
+        PushOptions {
+            max_chunk_bytes: 10 * 1024 * 1024,
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+arg_enum! {
+    /// Different strategies to deal with eisting indices
+    /// Abort: cancel push, return with an error
+    /// Ignore: Reuse existing index, appending new data
+    /// Recreate: Drop the existing index and start with a new one
+    /// Update: Reuse existing index, only rewriting changed documents
+    /// Sync: Like Update, but also delete documents of the pushed sources that were not pushed
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum ExistsStrategy {
+        Abort,
+        Ignore,
+        Recreate,
+        Update,
+        Sync,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        path::Path,
+        sync::{Arc, Mutex},
+    };
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
     use crate::{
         data::{self, import::Kind},
         process_flake,
     };
 
+    #[test]
+    fn test_connect() {
+        let options = ConnectionOptions {
+            auth: Some(Auth::Basic {
+                user: "elastic".to_owned(),
+                password: "changeme".to_owned(),
+            }),
+            ca_certificate: None,
+        };
+        assert!(Elasticsearch::connect("https://localhost:9200", &options).is_ok());
+
+        assert!(matches!(
+            Elasticsearch::connect("not a url", &options),
+            Err(ElasticsearchError::TransportInitError(..))
+        ));
+
+        let options = ConnectionOptions {
+            auth: None,
+            ca_certificate: Some(PathBuf::from("/nonexistent/ca.pem")),
+        };
+        assert!(matches!(
+            Elasticsearch::connect("https://localhost:9200", &options),
+            Err(ElasticsearchError::CertificateReadError(..))
+        ));
+    }
+
+    #[test]
+    fn test_chunk_by_size() {
+        let operations: Vec<String> = ["a".repeat(10), "b".repeat(10), "c".repeat(100)]
+            .iter()
+            .cloned()
+            .collect();
+
+        let chunks = chunk_by_size(&operations, 22);
+        assert_eq!(chunks, [&operations[0..2], &operations[2..3]]);
+
+        let chunks = chunk_by_size(&operations, 1);
+        assert_eq!(chunks.len(), 3);
+    }
+
+    #[test]
+    fn test_bulk_operation() {
+        let export = &options("services.foo")[0];
+
+        let operation = bulk_operation(export, ExistsStrategy::Ignore).unwrap();
+        let lines: Vec<Value> = operation
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0], json!({"index": {"_id": export.id()}}));
+        assert_eq!(lines[1], serde_json::to_value(export).unwrap());
+
+        let operation = bulk_operation(export, ExistsStrategy::Update).unwrap();
+        let lines: Vec<Value> = operation
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0], json!({"update": {"_id": export.id()}}));
+        assert_eq!(lines[1]["doc_as_upsert"], true);
+    }
+
+    /// Serves HTTP/1.1 on a local port, answering the n-th request with `respond(n, body)`.
+    /// Returns the url of the server and the bodies of all requests.
+    pub(crate) async fn mock_server<F>(respond: F) -> (String, Arc<Mutex<Vec<String>>>)
+    where
+        F: Fn(usize, &str) -> (u16, Value) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let respond = Arc::new(respond);
+
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (respond, recorded) = (respond.clone(), recorded.clone());
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let mut line = String::new();
+                    // one request per iteration, the client keeps connections alive
+                    while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+                        // responses to HEAD requests have no body
+                        let head = line.starts_with("HEAD ");
+                        let mut content_length = 0;
+                        loop {
+                            line.clear();
+                            stream.read_line(&mut line).await.unwrap();
+                            if line == "\r\n" {
+                                break;
+                            }
+                            if let Some((name, value)) = line.split_once(':') {
+                                if name.eq_ignore_ascii_case("content-length") {
+                                    content_length = value.trim().parse().unwrap();
+                                }
+                            }
+                        }
+                        let mut body = vec![0; content_length];
+                        stream.read_exact(&mut body).await.unwrap();
+                        let body = String::from_utf8(body).unwrap();
+
+                        let (status, response) = {
+                            let mut recorded = recorded.lock().unwrap();
+                            recorded.push(body.clone());
+                            respond(recorded.len(), &body)
+                        };
+                        let response = response.to_string();
+                        let response = format!(
+                            "HTTP/1.1 {} Mock\r\ncontent-type: application/json\r\nx-elastic-product: Elasticsearch\r\ncontent-length: {}\r\n\r\n{}",
+                            status,
+                            response.len(),
+                            if head { "" } else { &response }
+                        );
+                        stream
+                            .get_mut()
+                            .write_all(response.as_bytes())
+                            .await
+                            .unwrap();
+                        line.clear();
+                    }
+                });
+            }
+        });
+
+        (url, requests)
+    }
+
+    pub(crate) fn item(id: &str, status: u16, error: Option<&str>) -> Value {
+        let mut result = json!({"_id": id, "status": status});
+        if let Some(error) = error {
+            result["error"] = json!({"type": error, "reason": format!("{} reason", error)});
+        }
+        json!({ "index": result })
+    }
+
+    pub(crate) fn options(path: &str) -> Vec<Export> {
+        (0..3)
+            .map(|n| {
+                let option: data::import::NixOption = serde_json::from_value(json!({
+                    "declarations": [],
+                    "name": format!("{}.{}", path, n),
+                    "type": "boolean"
+                }))
+                .unwrap();
+                Export::nixpkgs(data::import::NixpkgsEntry::Option(option)).unwrap()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_push_retries_rejected_documents() {
+        let (url, requests) = mock_server(|n, _| match n {
+            1 => (
+                200,
+                json!({"errors": true, "items": [
+                    item("a", 201, None),
+                    item("b", 429, Some("es_rejected_execution_exception")),
+                    item("c", 400, Some("mapper_parsing_exception")),
+                ]}),
+            ),
+            2 => (503, json!({"error": "unavailable", "status": 503})),
+            _ => (
+                200,
+                json!({"errors": false, "items": [item("b", 201, None)]}),
+            ),
+        })
+        .await;
+
+        let es = Elasticsearch::new(&url).unwrap();
+        let config = Config {
+            index: "flakes_index",
+            exists_strategy: ExistsStrategy::Ignore,
+            push: PushOptions {
+                initial_backoff: Duration::from_millis(1),
+                ..PushOptions::default()
+            },
+            provenance: None,
+        };
+
+        let result = es.push_exports(&config, &options("services.foo")).await;
+
+        match result {
+            Err(ElasticsearchError::BulkRequestPartialFailure(failed)) => assert_eq!(
+                failed,
+                [FailedDocument {
+                    id: "c".to_owned(),
+                    status: 400,
+                    reason: "mapper_parsing_exception: mapper_parsing_exception reason".to_owned(),
+                }]
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        // the retries only contain the rejected document
+        assert_eq!(requests[0].lines().count(), 6);
+        assert_eq!(requests[1].lines().count(), 2);
+        assert!(requests[2].contains("services.foo.1"));
+    }
+
+    #[tokio::test]
+    async fn test_push_gives_up_after_retries() {
+        let (url, requests) = mock_server(|_, _| {
+            (
+                200,
+                json!({"errors": true, "items": [
+                    item("a", 429, Some("es_rejected_execution_exception")),
+                ]}),
+            )
+        })
+        .await;
+
+        let es = Elasticsearch::new(&url).unwrap();
+        let config = Config {
+            index: "flakes_index",
+            exists_strategy: ExistsStrategy::Ignore,
+            push: PushOptions {
+                max_chunk_bytes: 1,
+                max_retries: 2,
+                initial_backoff: Duration::from_millis(1),
+            },
+            provenance: None,
+        };
+
+        let result = es
+            .push_exports(&config, &options("services.foo")[..1])
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(ElasticsearchError::BulkRequestPartialFailure(failed)) if failed.len() == 1
+        ));
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    fn flake_option(repo: &str, name: &str) -> Export {
+        serde_json::from_value(json!({
+            "flake_name": repo,
+            "flake_description": null,
+            "flake_resolved": {"type": "github", "owner": "nix-community", "repo": repo},
+            "flake_source": {"type": "github", "owner": "nix-community", "repo": repo, "git_ref": null},
+            "revision": null,
+            "type": "option",
+            "option_source": null,
+            "option_name": name,
+            "option_description": null,
+            "option_type": "boolean",
+            "option_default": null,
+            "option_example": null,
+            "option_flake": null
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_scope_query() {
+        assert_eq!(scope_query(&[]), None);
+
+        let exports = [
+            flake_option("home-manager", "programs.a.enable"),
+            flake_option("home-manager", "programs.b.enable"),
+            options("services.foo").remove(0),
+        ];
+        let query = scope_query(&exports).unwrap();
+        let should = query["bool"]["should"].as_array().unwrap();
+
+        assert_eq!(should.len(), 2);
+        assert_eq!(
+            should[0]["nested"]["query"]["bool"]["filter"],
+            json!([
+                {"term": {"flake_source.type": "github"}},
+                {"term": {"flake_source.owner": "nix-community"}},
+                {"term": {"flake_source.repo": "home-manager"}},
+            ])
+        );
+        assert_eq!(
+            should[0]["nested"]["query"]["bool"]["must_not"],
+            json!([{"exists": {"field": "flake_source.git_ref"}}])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_stale() {
+        let exports = [flake_option("home-manager", "programs.a.enable")];
+        let kept = exports[0].id();
+        let stale = flake_option("home-manager", "programs.b.enable").id();
+
+        let hits = json!([{"_id": kept}, {"_id": stale}]);
+        let deleted = stale.clone();
+        let (url, requests) = mock_server(move |n, _| match n {
+            // search, scroll, clear scroll and bulk delete
+            1 => (200, json!({"_scroll_id": "s", "hits": {"hits": hits}})),
+            2 => (200, json!({"_scroll_id": "s", "hits": {"hits": []}})),
+            3 => (200, json!({"succeeded": true})),
+            // the stale document was deleted in the meantime
+            _ => (
+                200,
+                json!({"errors": true, "items": [{"delete": {"_id": deleted, "status": 404, "result": "not_found"}}]}),
+            ),
+        })
+        .await;
+
+        let es = Elasticsearch::new(&url).unwrap();
+        let config = Config {
+            index: "group-index",
+            exists_strategy: ExistsStrategy::Sync,
+            push: PushOptions::default(),
+            provenance: None,
+        };
+
+        assert_eq!(es.delete_stale(&config, &exports).await.unwrap(), 1);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(
+            requests[3].trim(),
+            json!({"delete": {"_id": stale}}).to_string()
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        let counts = |counts: &[(&str, u64)]| -> HashMap<String, u64> {
+            counts
+                .iter()
+                .map(|(document_type, count)| (document_type.to_string(), *count))
+                .collect()
+        };
+        let validation = Validation {
+            min_documents: 10,
+            min_ratio: 0.9,
+        };
+
+        let previous = counts(&[("package", 100), ("option", 100)]);
+        assert!(
+            validation
+                .check(&counts(&[("package", 95), ("option", 90)]), Some(&previous))
+                .is_ok()
+        );
+        assert!(validation.check(&counts(&[("package", 10)]), None).is_ok());
+
+        // a type is missing
+        assert!(
+            validation
+                .check(&counts(&[("package", 200)]), Some(&previous))
+                .is_err()
+        );
+        // too few documents compared to the previous index
+        assert!(
+            validation
+                .check(&counts(&[("package", 50), ("option", 50)]), Some(&previous))
+                .is_err()
+        );
+        assert!(validation.check(&counts(&[]), None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_write_alias_is_atomic() {
+        let (url, requests) = mock_server(|n, _| match n {
+            1 => (
+                200,
+                json!({"nixos-48-unstable-old": {"aliases": {"latest-48-nixos-unstable": {}}}}),
+            ),
+            _ => (200, json!({"acknowledged": true})),
+        })
+        .await;
+
+        let es = Elasticsearch::new(&url).unwrap();
+        let config = Config {
+            index: "nixos-48-unstable-new",
+            exists_strategy: ExistsStrategy::Abort,
+            push: PushOptions::default(),
+            provenance: None,
+        };
+        es.write_alias(&config, config.index, "latest-48-nixos-unstable")
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            serde_json::from_str::<Value>(&requests[1]).unwrap(),
+            json!({"actions": [
+                {"remove": {"index": "nixos-48-unstable-old", "alias": "latest-48-nixos-unstable"}},
+                {"add": {"index": "nixos-48-unstable-new", "alias": "latest-48-nixos-unstable"}},
+            ]})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_fingerprint() {
+        let (url, _) = mock_server(|n, _| {
+            let fingerprint = if n == 1 {
+                schema_fingerprint().unwrap()
+            } else {
+                "outdated".to_owned()
+            };
+            (
+                200,
+                json!({"nixos-48-unstable-a": {"mappings": {"_meta": {"schema_fingerprint": fingerprint}}}}),
+            )
+        })
+        .await;
+
+        let es = Elasticsearch::new(&url).unwrap();
+        es.check_fingerprint("nixos-48-unstable-a").await.unwrap();
+        assert!(matches!(
+            es.check_fingerprint("latest-48-nixos-unstable").await,
+            Err(ElasticsearchError::SchemaMismatch { found: Some(found), .. }) if found == "outdated"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sink_rollback() {
+        let config = Config {
+            index: "nixos-48-unstable-a",
+            exists_strategy: ExistsStrategy::Abort,
+            push: PushOptions::default(),
+            provenance: None,
+        };
+
+        // 1, 2: HEAD, the index does not exist; 3: PUT creates it; 4: DELETE on rollback
+        let (url, requests) = mock_server(|n, _| {
+            (
+                if n <= 2 { 404 } else { 200 },
+                json!({"acknowledged": true}),
+            )
+        })
+        .await;
+        let es = Elasticsearch::new(&url).unwrap();
+        let mut sink = ElasticSink::new(&es, &config, None);
+        let exports = vec![Err(anyhow::anyhow!("evaluation failed"))];
+        let error = crate::sink::write_exports(&mut sink, exports, 10)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "evaluation failed");
+        assert_eq!(requests.lock().unwrap().len(), 4);
+
+        // an existing index is neither written to nor deleted
+        let (url, requests) = mock_server(|_, _| (200, json!({}))).await;
+        let es = Elasticsearch::new(&url).unwrap();
+        let mut sink = ElasticSink::new(&es, &config, None);
+        let error = crate::sink::write_exports(
+            &mut sink,
+            options("services.foo")
+                .into_iter()
+                .map(Ok::<_, anyhow::Error>),
+            10,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(ElasticsearchError::IndexExistsError(_))
+        ));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        // an index the push failed for is kept for inspection
+        // 1, 2: HEAD; 3: PUT; 4: bulk request rejecting a document
+        let (url, requests) = mock_server(|n, _| match n {
+            1 | 2 => (404, json!({})),
+            3 => (200, json!({"acknowledged": true})),
+            _ => (
+                200,
+                json!({"errors": true, "items": [
+                    item("a", 400, Some("mapper_parsing_exception")),
+                ]}),
+            ),
+        })
+        .await;
+        let es = Elasticsearch::new(&url).unwrap();
+        let mut sink = ElasticSink::new(&es, &config, None);
+        let error = crate::sink::write_exports(
+            &mut sink,
+            options("services.foo")
+                .into_iter()
+                .map(Ok::<_, anyhow::Error>),
+            10,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(ElasticsearchError::BulkRequestPartialFailure(_))
+        ));
+        assert_eq!(requests.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_file_sink() {
+        let dir = std::env::temp_dir().join(format!("flake-info-bulk-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("exports.bulk");
+
+        let mut sink = BulkFileSink::new(&path);
+        let exports = options("services.foo");
+        crate::sink::write_exports(
+            &mut sink,
+            exports.iter().cloned().map(Ok::<_, anyhow::Error>),
+            2,
+        )
+        .await
+        .unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            serde_json::from_str::<Value>(lines[0]).unwrap(),
+            json!({"index": {"_id": exports[0].id()}})
+        );
+
+        // a failed evaluation leaves the previous file in place
+        let mut sink = BulkFileSink::new(&path);
+        let exports = vec![Err(anyhow::anyhow!("evaluation failed"))];
+        crate::sink::write_exports(&mut sink, exports, 2)
+            .await
+            .unwrap_err();
+        assert_eq!(fs::read_to_string(&path).unwrap(), written);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_index_body() {
+        let body = index_body(None).unwrap();
+        assert_eq!(
+            body["mappings"]["_meta"],
+            json!({"schema_fingerprint": schema_fingerprint().unwrap()})
+        );
+        assert_eq!(
+            body["mappings"]["properties"],
+            MAPPING["mappings"]["properties"]
+        );
+
+        let provenance = Provenance::new(None, Some("2.24.0".to_owned()));
+        let body = index_body(Some(&provenance)).unwrap();
+        assert_eq!(
+            body["mappings"]["_meta"]["provenance"]["nix_version"],
+            "2.24.0"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_provenance() {
+        let mut provenance = Provenance::new(None, Some("2.24.0".to_owned()));
+        provenance.sources.push(data::SourceRevision {
+            source: "github:NixOS/nixpkgs".to_owned(),
+            revision: Some("0123abcd".to_owned()),
+            nixpkgs: None,
+        });
+        provenance.failed_members = 1;
+
+        let meta = index_meta(Some(&provenance)).unwrap();
+        let (url, _) = mock_server(move |n, _| match n {
+            1 => (
+                200,
+                json!({"group-48-a-1": {"mappings": {"_meta": meta.clone()}}}),
+            ),
+            _ => (200, json!({"group-48-a-2": {"mappings": {}}})),
+        })
+        .await;
+
+        let es = Elasticsearch::new(&url).unwrap();
+        assert_eq!(
+            es.provenance("group-48-a-1").await.unwrap(),
+            Some(provenance)
+        );
+        assert_eq!(es.provenance("group-48-a-2").await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_index_name() {
+        let name = IndexName::parse("group-48-ngi-nix-0a1b2c3d").unwrap();
+        assert_eq!(
+            name,
+            IndexName {
+                kind: "group".to_owned(),
+                schema: 48,
+                name: "ngi-nix".to_owned(),
+                hash: "0a1b2c3d".to_owned(),
+            }
+        );
+        assert_eq!(name.to_string(), "group-48-ngi-nix-0a1b2c3d");
+
+        assert!(IndexName::parse("nixos-48-unstable-latest").is_some());
+        assert_eq!(IndexName::parse("48-custom-index"), None);
+        assert_eq!(IndexName::parse("latest-48-nixos-unstable"), None);
+        assert_eq!(IndexName::parse("nixos-48-unstable"), None);
+    }
+
+    #[test]
+    fn test_select_garbage() {
+        let index = |name: &str, created: u64, aliased: bool| IndexInfo {
+            name: name.to_owned(),
+            parsed: IndexName::parse(name),
+            documents: 0,
+            aliases: if aliased {
+                vec!["latest".to_owned()]
+            } else {
+                Vec::new()
+            },
+            created,
+        };
+        let indices = [
+            index("nixos-48-unstable-a", 1, true),
+            index("nixos-48-unstable-b", 2, false),
+            index("nixos-48-unstable-c", 3, false),
+            index("nixos-48-unstable-d", 4, false),
+            index("nixos-48-24.05-a", 1, false),
+            index("48-custom", 0, false),
+        ];
+
+        let garbage: Vec<&str> = select_garbage(&indices, 2)
+            .iter()
+            .map(|index| index.name.as_str())
+            .collect();
+        assert_eq!(garbage, ["nixos-48-unstable-b"]);
+
+        let garbage = select_garbage(&indices, 0);
+        assert_eq!(garbage.len(), 4);
+    }
+
     #[tokio::test]
     async fn test_delete() -> Result<(), Box<dyn std::error::Error>> {
         let es = Elasticsearch::new("http://localhost:9200").unwrap();
         let config = &Config {
             index: "flakes_index",
             exists_strategy: ExistsStrategy::Ignore,
+            push: PushOptions::default(),
+            provenance: None,
         };
         es.ensure_index(config).await?;
         es.clear_index(config).await?;
@@ -542,6 +2399,8 @@ mod tests {
         let config = &Config {
             index: "flakes_index",
             exists_strategy: ExistsStrategy::Recreate,
+            push: PushOptions::default(),
+            provenance: None,
         };
 
         es.ensure_index(config).await?;
@@ -557,18 +2416,31 @@ mod tests {
         let sources: Vec<data::Source> =
             data::Source::read_sources_file(Path::new("./examples/examples.in.json"))?;
 
-        let exports = sources
-            .iter()
-            .flat_map(|s| process_flake(s, &Kind::All, false, &[], false))
-            .map(|(_info, exports)| exports)
-            .flatten()
-            .collect::<Vec<Export>>();
+        let exports = {
+            let _runner = crate::commands::test_runner(crate::commands::SystemRunner);
+            let mut exports = Vec::new();
+            for source in &sources {
+                let (_info, flake_exports) = process_flake(
+                    source,
+                    &Kind::All,
+                    false,
+                    &[],
+                    false,
+                    None,
+                    &Default::default(),
+                )?;
+                exports.extend(flake_exports);
+            }
+            exports
+        };
         println!("{}", serde_json::to_string(&exports[1]).unwrap());
 
         let es = Elasticsearch::new("http://localhost:9200").unwrap();
         let config = &Config {
             index: "flakes_index",
             exists_strategy: ExistsStrategy::Recreate,
+            push: PushOptions::default(),
+            provenance: None,
         };
 
         es.ensure_index(config).await?;
@@ -583,6 +2455,8 @@ mod tests {
         let config = &Config {
             index: "flakes_index",
             exists_strategy: ExistsStrategy::Abort,
+            push: PushOptions::default(),
+            provenance: None,
         };
 
         es.ensure_index(&Config {
@@ -1,18 +1,36 @@
 #![recursion_limit = "256"]
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use cache::ExportCache;
+use commands::EvalNixpkgs;
 use data::{Export, Flake, Source, import::Kind};
 use lazy_static::lazy_static;
 use std::path::{Path, PathBuf};
 
+pub mod cache;
 pub mod commands;
 pub mod data;
+pub mod diff;
+pub mod sink;
+pub mod sqlite;
 
 #[cfg(feature = "elastic")]
 pub mod elastic;
 
+#[cfg(feature = "local")]
+pub mod local;
+
+#[cfg(feature = "meilisearch")]
+pub mod meilisearch;
+
+#[cfg(feature = "opensearch")]
+pub mod opensearch;
+
+#[cfg(feature = "elastic")]
+pub mod query;
+
 pub use commands::get_flake_info;
-use log::{info, trace};
+use log::{info, trace, warn};
 
 lazy_static! {
     static ref DATADIR: PathBuf =
@@ -25,15 +43,44 @@ pub fn process_flake(
     temp_store: bool,
     extra: &[String],
     with_gc: bool,
+    cache: Option<&ExportCache>,
+    nixpkgs: &EvalNixpkgs,
 ) -> Result<(Flake, Vec<Export>)> {
-    let mut info = commands::get_flake_info(source.to_flake_ref(), temp_store, extra)?;
+    let flake_ref = source.to_flake_ref();
+    let metadata = commands::get_flake_metadata(&flake_ref, temp_store, extra)
+        .with_context(|| format!("Failed to gather information about {}", flake_ref))?;
+    let nixpkgs = nixpkgs.resolve(&metadata)?;
+    let mut info = commands::flake_info(metadata)?;
     info.source = Some(source.clone());
+    info.nixpkgs = Some(nixpkgs.clone());
     info!(
-        "Resolved {} to revision {}",
-        source.to_flake_ref(),
+        "Resolved {} to revision {}, evaluating with nixpkgs {}",
+        flake_ref,
         info.revision.as_deref().unwrap_or("(unknown)"),
+        nixpkgs.revision.as_deref().unwrap_or(&nixpkgs.source),
     );
-    let packages = commands::get_derivation_info(source.to_flake_ref(), *kind, temp_store, extra)?;
+
+    // Unlocked flakes (e.g. dirty local checkouts) have no revision and are never
+    // cached, neither are flakes evaluated with a nixpkgs that is not pinned
+    let nixpkgs_key = commands::cache_key(&nixpkgs);
+    let cache = cache
+        .zip(info.revision.clone())
+        .filter(|_| nixpkgs_key.is_some());
+    // exports depend on the nixpkgs they were evaluated with as much as on extra arguments
+    let cache_args: Vec<String> = extra
+        .iter()
+        .cloned()
+        .chain(nixpkgs_key.map(|key| format!("nixpkgs={}", key)))
+        .collect();
+    if let Some((cache, revision)) = &cache {
+        if let Some(exports) = cache.get(&flake_ref, revision, *kind, &cache_args) {
+            info!("Reusing cached exports of {} at {}", flake_ref, revision);
+            return Ok((info, exports));
+        }
+    }
+
+    let nixpkgs = commands::lookup_path(&nixpkgs, temp_store, extra)?;
+    let packages = commands::get_derivation_info(&flake_ref, *kind, temp_store, extra, &nixpkgs)?;
 
     if with_gc {
         commands::run_garbage_collection()?;
@@ -47,6 +94,12 @@ pub fn process_flake(
         .map(|p| Export::flake(info.clone(), p))
         .collect::<Result<Vec<Export>>>()?;
 
+    if let Some((cache, revision)) = &cache {
+        if let Err(e) = cache.put(&flake_ref, revision, *kind, &cache_args, &exports) {
+            warn!("Could not cache exports of {}: {:?}", flake_ref, e);
+        }
+    }
+
     Ok((info, exports))
 }
 
@@ -56,6 +109,16 @@ pub fn process_nixpkgs(
     attribute: &Option<String>,
     packages_json_url: &Option<String>,
 ) -> Result<Vec<Export>, anyhow::Error> {
+    nixpkgs_exports(nixpkgs, kind, attribute, packages_json_url)?.collect()
+}
+
+/// Like [process_nixpkgs], but converts the entries to exports lazily
+pub fn nixpkgs_exports(
+    nixpkgs: &Source,
+    kind: &Kind,
+    attribute: &Option<String>,
+    packages_json_url: &Option<String>,
+) -> Result<impl Iterator<Item = Result<Export>>> {
     let drvs = if matches!(kind, Kind::All | Kind::Package) {
         commands::get_nixpkgs_info(nixpkgs, attribute, packages_json_url)?
     } else {
@@ -85,9 +148,5 @@ pub fn process_nixpkgs(
     all.append(&mut services);
     all.append(&mut hm_options);
 
-    let exports = all
-        .into_iter()
-        .map(Export::nixpkgs)
-        .collect::<Result<Vec<Export>>>()?;
-    Ok(exports)
+    Ok(all.into_iter().map(Export::nixpkgs))
 }
@@ -0,0 +1,452 @@
+/// An embedded full text index that can be searched without an Elasticsearch
+/// cluster.
+///
+/// The index mirrors the fields of the Elasticsearch [MAPPING](crate::elastic):
+/// keyword fields are matched exactly (ignoring case), english text fields are
+/// stemmed and the `edge`, `attr_path` and `attr_path_reverse` sub-fields hold
+/// the same tokens the corresponding Elasticsearch analyzers would produce.
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::info;
+use serde_json::Value;
+use tantivy::{
+    Index, IndexWriter, TantivyDocument, Term,
+    collector::TopDocs,
+    directory::MmapDirectory,
+    query::{BooleanQuery, BoostQuery, Occur, Query, QueryParser, TermQuery},
+    schema::{
+        Field, IndexRecordOption, STORED, Schema, TextFieldIndexing, TextOptions, Value as _,
+    },
+    tokenizer::{LowerCaser, RawTokenizer, TextAnalyzer},
+};
+
+use crate::data::{Export, import::Kind};
+use crate::sink::{Failure, Sink};
+
+/// Analysis applied to the main field
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Analysis {
+    /// Exact match, ignoring case
+    Keyword,
+    /// Stemmed english text
+    English,
+    /// Plain text
+    Text,
+}
+
+/// Describes how a top level document field is indexed
+struct FieldSpec {
+    name: &'static str,
+    analysis: Analysis,
+    /// add an `edge` sub-field with edge n-grams of every word
+    edge: bool,
+    /// add `attr_path` and `attr_path_reverse` sub-fields with the attribute
+    /// path hierarchy
+    attr_path: bool,
+}
+
+const fn field(name: &'static str, analysis: Analysis, edge: bool, attr_path: bool) -> FieldSpec {
+    FieldSpec {
+        name,
+        analysis,
+        edge,
+        attr_path,
+    }
+}
+
+use Analysis::*;
+
+const FIELDS: &[FieldSpec] = &[
+    field("type", Keyword, false, false),
+    field("flake_name", English, false, false),
+    field("flake_description", English, false, false),
+    field("package_attr_name", Keyword, true, true),
+    field("package_attr_set", Keyword, true, false),
+    field("package_pname", Keyword, true, false),
+    field("package_pversion", Keyword, false, false),
+    field("package_platforms", Keyword, false, false),
+    field("package_system", Keyword, false, false),
+    field("package_position", Text, false, false),
+    field("package_outputs", Keyword, false, false),
+    field("package_default_output", Keyword, false, false),
+    field("package_programs", Keyword, false, false),
+    field("package_description", English, true, false),
+    field("package_longDescription", English, true, false),
+    field("package_license_set", Keyword, false, false),
+    field("package_maintainers_set", Keyword, false, false),
+    field("package_teams_set", Keyword, false, false),
+    field("package_homepage", Keyword, false, false),
+    field("package_modular_services", Keyword, false, false),
+    field("option_name", Keyword, true, true),
+    field("option_description", English, true, false),
+    field("option_type", Keyword, false, false),
+    field("option_default", Text, false, false),
+    field("option_example", Text, false, false),
+    field("option_source", Keyword, false, false),
+    field("service_package", Keyword, true, false),
+    field("service_module", Keyword, false, false),
+    field("service_packages", Keyword, true, false),
+];
+
+/// Same bounds as the `edge` tokenizer in the Elasticsearch mapping
+const EDGE_MIN_GRAM: usize = 2;
+const EDGE_MAX_GRAM: usize = 50;
+
+/// Memory budget of the index writer
+const WRITER_HEAP_SIZE: usize = 100_000_000;
+
+/// Name of the field holding the complete exported document
+const DOCUMENT_FIELD: &str = "document";
+
+struct Fields {
+    /// `(spec, main, edge, (attr_path, attr_path_reverse))`
+    fields: Vec<(
+        &'static FieldSpec,
+        Field,
+        Option<Field>,
+        Option<(Field, Field)>,
+    )>,
+    document: Field,
+}
+
+fn schema() -> (Schema, Fields) {
+    let keyword = TextOptions::default().set_indexing_options(
+        TextFieldIndexing::default()
+            .set_tokenizer("lowercase")
+            .set_index_option(IndexRecordOption::Basic),
+    );
+    let english = TextOptions::default().set_indexing_options(
+        TextFieldIndexing::default()
+            .set_tokenizer("en_stem")
+            .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+    );
+    let text = TextOptions::default().set_indexing_options(
+        TextFieldIndexing::default()
+            .set_tokenizer("default")
+            .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+    );
+
+    let mut builder = Schema::builder();
+    let fields = FIELDS
+        .iter()
+        .map(|spec| {
+            let options = match spec.analysis {
+                Keyword => keyword.clone(),
+                English => english.clone(),
+                Text => text.clone(),
+            };
+            let main = builder.add_text_field(spec.name, options);
+            // sub-fields are filled with pre-analyzed tokens
+            let edge = spec
+                .edge
+                .then(|| builder.add_text_field(&format!("{}.edge", spec.name), keyword.clone()));
+            let attr_path = spec.attr_path.then(|| {
+                (
+                    builder.add_text_field(&format!("{}.attr_path", spec.name), keyword.clone()),
+                    builder.add_text_field(
+                        &format!("{}.attr_path_reverse", spec.name),
+                        keyword.clone(),
+                    ),
+                )
+            });
+            (spec, main, edge, attr_path)
+        })
+        .collect();
+    let document = builder.add_text_field(DOCUMENT_FIELD, STORED);
+
+    (builder.build(), Fields { fields, document })
+}
+
+fn open(dir: &Path) -> Result<(Index, Fields)> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Couldn't create index directory {}", dir.display()))?;
+
+    let (schema, fields) = schema();
+    let directory = MmapDirectory::open(dir)?;
+    let index = Index::open_or_create(directory, schema).with_context(|| {
+        format!(
+            "Couldn't open local index at {}, if it was created by another version remove it first",
+            dir.display()
+        )
+    })?;
+    index.tokenizers().register(
+        "lowercase",
+        TextAnalyzer::builder(RawTokenizer::default())
+            .filter(LowerCaser)
+            .build(),
+    );
+
+    Ok((index, fields))
+}
+
+/// Collects the string values of a document field, which may be a single
+/// value or a list
+fn values(document: &Value, name: &str) -> Vec<String> {
+    match document.get(name) {
+        Some(Value::String(s)) => vec![s.to_owned()],
+        Some(Value::Array(a)) => a
+            .iter()
+            .filter_map(|v| v.as_str().map(ToOwned::to_owned))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Edge n-grams of every word, like the `edge` analyzer.
+///
+/// Words consist of letters, digits, punctuation and `+=~`.
+fn edge_ngrams(text: &str) -> Vec<String> {
+    text.split(|c: char| {
+        !(c.is_alphanumeric() || c.is_ascii_punctuation() || "+=~".contains(c)) || "<>".contains(c)
+    })
+    .filter(|word| !word.is_empty())
+    .flat_map(|word| {
+        let word: Vec<char> = word.to_lowercase().chars().collect();
+        (EDGE_MIN_GRAM..=word.len().min(EDGE_MAX_GRAM))
+            .map(move |n| word[..n].iter().collect::<String>())
+    })
+    .collect()
+}
+
+/// Path hierarchy of an attribute path, like the `attr_path` analyzer
+/// (`a.b.c` → `a`, `a.b`, `a.b.c`) and the `attr_path_reverse` analyzer
+/// (`a.b.c` → `a.b.c`, `b.c`, `c`).
+fn attr_path_hierarchy(attr_path: &str) -> (Vec<String>, Vec<String>) {
+    let attr_path = attr_path.to_lowercase();
+    let parts: Vec<&str> = attr_path.split('.').collect();
+    let forward = (1..=parts.len()).map(|n| parts[..n].join(".")).collect();
+    let reverse = (0..parts.len()).map(|n| parts[n..].join(".")).collect();
+    (forward, reverse)
+}
+
+/// Replaces the contents of the local index at `dir` with `exports`
+pub fn write_exports(dir: &Path, exports: &[Export]) -> Result<()> {
+    let (index, fields) = open(dir)?;
+    let mut writer: IndexWriter = index.writer(WRITER_HEAP_SIZE)?;
+    writer.delete_all_documents()?;
+
+    for export in exports {
+        writer.add_document(document(&fields, export)?)?;
+    }
+
+    writer.commit()?;
+    info!("Wrote {} documents to {}", exports.len(), dir.display());
+    Ok(())
+}
+
+/// A [Sink] replacing the contents of the local index at a directory.
+///
+/// Nothing changes in the index until the sink is finalized.
+pub struct LocalIndexSink {
+    dir: PathBuf,
+    writer: Option<(IndexWriter, Fields)>,
+    written: usize,
+}
+
+impl LocalIndexSink {
+    pub fn new(dir: &Path) -> Self {
+        LocalIndexSink {
+            dir: dir.to_owned(),
+            writer: None,
+            written: 0,
+        }
+    }
+
+    fn writer(&mut self) -> Result<&mut (IndexWriter, Fields)> {
+        self.writer
+            .as_mut()
+            .context("Local index sink is not prepared")
+    }
+}
+
+impl Sink for LocalIndexSink {
+    async fn prepare(&mut self) -> Result<()> {
+        let (index, fields) = open(&self.dir)?;
+        let mut writer: IndexWriter = index.writer(WRITER_HEAP_SIZE)?;
+        writer.delete_all_documents()?;
+        self.writer = Some((writer, fields));
+        Ok(())
+    }
+
+    async fn write_batch(&mut self, exports: &[Export]) -> Result<()> {
+        let (writer, fields) = self.writer()?;
+        for export in exports {
+            writer.add_document(document(fields, export)?)?;
+        }
+        self.written += exports.len();
+        Ok(())
+    }
+
+    async fn finalize(&mut self) -> Result<()> {
+        self.writer()?.0.commit()?;
+        info!("Wrote {} documents to {}", self.written, self.dir.display());
+        Ok(())
+    }
+
+    /// Discards all uncommitted changes, leaving the previous contents in place
+    async fn rollback(&mut self, _failure: Failure) -> Result<()> {
+        if let Some((mut writer, _)) = self.writer.take() {
+            writer.rollback()?;
+        }
+        Ok(())
+    }
+}
+
+/// The indexed form of `export`
+fn document(fields: &Fields, export: &Export) -> Result<TantivyDocument> {
+    let document = serde_json::to_value(export)?;
+    let mut indexed = TantivyDocument::default();
+
+    for (spec, main, edge, attr_path) in &fields.fields {
+        for value in values(&document, spec.name) {
+            if let Some(edge) = edge {
+                for gram in edge_ngrams(&value) {
+                    indexed.add_text(*edge, gram);
+                }
+            }
+            if let Some((forward, reverse)) = attr_path {
+                let (forward_paths, reverse_paths) = attr_path_hierarchy(&value);
+                for path in forward_paths {
+                    indexed.add_text(*forward, path);
+                }
+                for path in reverse_paths {
+                    indexed.add_text(*reverse, path);
+                }
+            }
+            indexed.add_text(*main, value);
+        }
+    }
+    indexed.add_text(fields.document, document.to_string());
+    Ok(indexed)
+}
+
+/// A document found by [search]
+#[derive(Debug, Clone)]
+pub struct Hit {
+    pub score: f32,
+    /// The exported document as it would be stored in Elasticsearch
+    pub document: Value,
+}
+
+/// The `type` of documents produced for a [Kind]
+fn document_type(kind: Kind) -> Option<&'static str> {
+    match kind {
+        Kind::App => Some("app"),
+        Kind::Package => Some("package"),
+        Kind::Option => Some("option"),
+        Kind::HomeManagerOption => Some("home-manager-option"),
+        Kind::ModularService => Some("service"),
+        Kind::All => None,
+    }
+}
+
+/// Searches the local index at `dir`, restricted to documents of `kind`
+pub fn search(dir: &Path, query: &str, kind: Kind, limit: usize) -> Result<Vec<Hit>> {
+    let (index, fields) = open(dir)?;
+    let field = |name: &str| {
+        fields
+            .fields
+            .iter()
+            .find(|(spec, ..)| spec.name == name)
+            .expect("field is part of the schema")
+    };
+    let term = |field: Field, text: &str, boost: f32| -> (Occur, Box<dyn Query>) {
+        let query = TermQuery::new(Term::from_field_text(field, text), IndexRecordOption::Basic);
+        (
+            Occur::Should,
+            Box::new(BoostQuery::new(Box::new(query), boost)),
+        )
+    };
+
+    let query = query.trim().to_lowercase();
+    let mut should: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+
+    // exact names and programs rank highest
+    for name in ["package_attr_name", "option_name", "package_programs"] {
+        should.push(term(field(name).1, &query, 10.0));
+    }
+
+    // attribute path hierarchies, e.g. `services.nginx` matches all nginx options
+    for name in ["package_attr_name", "option_name"] {
+        if let Some((forward, reverse)) = field(name).3 {
+            should.push(term(forward, &query, 5.0));
+            should.push(term(reverse, &query, 5.0));
+        }
+    }
+
+    // prefixes of every word in names and descriptions
+    for word in query.split_whitespace() {
+        for (spec, _, edge, _) in &fields.fields {
+            if let Some(edge) = edge {
+                let boost = if spec.analysis == English { 1.0 } else { 2.0 };
+                should.push(term(*edge, word, boost));
+            }
+        }
+    }
+
+    // stemmed full text
+    let english: Vec<Field> = fields
+        .fields
+        .iter()
+        .filter(|(spec, ..)| spec.analysis == English)
+        .map(|(_, main, ..)| *main)
+        .collect();
+    let (text_query, _errors) = QueryParser::for_index(&index, english).parse_query_lenient(&query);
+    should.push((Occur::Should, text_query));
+
+    let mut clauses: Vec<(Occur, Box<dyn Query>)> =
+        vec![(Occur::Must, Box::new(BooleanQuery::new(should)))];
+    if let Some(document_type) = document_type(kind) {
+        clauses.push((
+            Occur::Must,
+            Box::new(TermQuery::new(
+                Term::from_field_text(field("type").1, document_type),
+                IndexRecordOption::Basic,
+            )),
+        ));
+    }
+
+    let searcher = index.reader()?.searcher();
+    searcher
+        .search(&BooleanQuery::new(clauses), &TopDocs::with_limit(limit))?
+        .into_iter()
+        .map(|(score, address)| -> Result<Hit> {
+            let stored: TantivyDocument = searcher.doc(address)?;
+            let document = stored
+                .get_first(fields.document)
+                .and_then(|v| v.as_str())
+                .map(serde_json::from_str)
+                .transpose()?
+                .unwrap_or(Value::Null);
+            Ok(Hit { score, document })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edge_ngrams() {
+        assert_eq!(
+            edge_ngrams("GNU <hello>"),
+            vec!["gn", "gnu", "he", "hel", "hell", "hello"]
+        );
+        assert_eq!(edge_ngrams("c++"), vec!["c+", "c++"]);
+    }
+
+    #[test]
+    fn test_attr_path_hierarchy() {
+        let (forward, reverse) = attr_path_hierarchy("services.nginx.enable");
+        assert_eq!(
+            forward,
+            vec!["services", "services.nginx", "services.nginx.enable"]
+        );
+        assert_eq!(
+            reverse,
+            vec!["services.nginx.enable", "nginx.enable", "enable"]
+        );
+    }
+}
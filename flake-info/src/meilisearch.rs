@@ -0,0 +1,455 @@
+/// Writes exports to a Meilisearch index.
+///
+/// Searchable and filterable attributes are derived from the Elasticsearch
+/// [MAPPING]: keyword fields with analyzed sub-fields (attribute and option
+/// names) and english text fields are searchable, keyword fields are filterable.
+///
+/// [MeilisearchSink] fills `<index>-partial` and swaps it with `<index>` once
+/// all exports are written, so searches never see a half written index.
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use reqwest::{Client, Method, RequestBuilder, StatusCode};
+use serde_json::{Map, Value, json};
+
+use crate::{
+    data::Export,
+    elastic::{MAPPING, chunk_by_size},
+    sink::{Failure, Sink},
+};
+
+/// Keyword fields search terms are matched against, although the mapping does not analyze them
+const SEARCHABLE_KEYWORDS: &[&str] = &["package_programs"];
+
+/// Name of the field holding [Export::id], the primary key of every index
+const PRIMARY_KEY: &str = "id";
+
+/// Delay between two polls of a pending task
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn properties() -> &'static Map<String, Value> {
+    MAPPING["mappings"]["properties"]
+        .as_object()
+        .expect("the mapping has properties")
+}
+
+/// Keyword fields with analyzed sub-fields, e.g. `package_attr_name.edge`
+fn is_name(mapping: &Value) -> bool {
+    mapping["type"] == "keyword" && mapping["fields"].is_object()
+}
+
+/// Attributes search terms are matched against, most relevant first.
+///
+/// Names come first, followed by [SEARCHABLE_KEYWORDS] and english text such
+/// as descriptions. Meilisearch ranks matches by the position of their attribute.
+pub fn searchable_attributes() -> Vec<String> {
+    let mut attributes: Vec<(u8, &String)> = properties()
+        .iter()
+        .filter_map(|(name, mapping)| {
+            let rank = if is_name(mapping) {
+                0
+            } else if SEARCHABLE_KEYWORDS.contains(&name.as_str()) {
+                1
+            } else if mapping["type"] == "text" && mapping["analyzer"] == "english" {
+                2
+            } else {
+                return None;
+            };
+            Some((rank, name))
+        })
+        .collect();
+    attributes.sort();
+    attributes
+        .into_iter()
+        .map(|(_, name)| name.to_owned())
+        .collect()
+}
+
+/// Attributes results can be filtered and faceted by, i.e. all keyword fields
+pub fn filterable_attributes() -> Vec<String> {
+    properties()
+        .iter()
+        .filter(|(_, mapping)| mapping["type"] == "keyword")
+        .map(|(name, _)| name.to_owned())
+        .collect()
+}
+
+/// Settings of every index created by flake-info
+pub fn settings() -> Value {
+    json!({
+        "searchableAttributes": searchable_attributes(),
+        "filterableAttributes": filterable_attributes(),
+    })
+}
+
+/// Serializes `export` as a Meilisearch document, identified by [Export::id]
+fn document(export: &Export) -> Result<String> {
+    let mut document = serde_json::to_value(export)?;
+    document[PRIMARY_KEY] = Value::String(export.id());
+    Ok(document.to_string())
+}
+
+#[derive(Debug, Clone)]
+pub struct MeilisearchOptions {
+    pub api_key: Option<String>,
+    /// Maximum size of a single documents request body in bytes
+    pub max_chunk_bytes: usize,
+    /// How long to wait for Meilisearch to process a task
+    pub task_timeout: Duration,
+}
+
+impl Default for MeilisearchOptions {
+    fn default() -> Self {
+        MeilisearchOptions {
+            api_key: None,
+            max_chunk_bytes: 10 * 1024 * 1024,
+            task_timeout: Duration::from_secs(600),
+        }
+    }
+}
+
+pub struct Meilisearch {
+    client: Client,
+    /// Base url without a trailing slash
+    url: String,
+    options: MeilisearchOptions,
+}
+
+impl Meilisearch {
+    pub fn new(url: &str, options: MeilisearchOptions) -> Result<Self> {
+        reqwest::Url::parse(url).with_context(|| format!("Invalid Meilisearch url {}", url))?;
+        Ok(Meilisearch {
+            client: Client::builder().build()?,
+            url: url.trim_end_matches('/').to_owned(),
+            options,
+        })
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}/{}", self.url, path));
+        match &self.options.api_key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        }
+    }
+
+    /// Sends `request` and reads its JSON response, failing on error status codes
+    async fn send(request: RequestBuilder) -> Result<Value> {
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            anyhow::bail!(
+                "Meilisearch responded with {}: {}",
+                status,
+                response.text().await?
+            );
+        }
+        Ok(response.json().await?)
+    }
+
+    /// Sends `request`, which enqueues a task, and waits until the task is processed
+    async fn run_task(&self, request: RequestBuilder) -> Result<()> {
+        let task = Self::send(request).await?;
+        let uid = task["taskUid"]
+            .as_u64()
+            .with_context(|| format!("Meilisearch did not enqueue a task: {}", task))?;
+
+        let start = Instant::now();
+        loop {
+            let task = Self::send(self.request(Method::GET, &format!("tasks/{}", uid))).await?;
+            match task["status"].as_str() {
+                Some("succeeded") => return Ok(()),
+                Some(status @ ("failed" | "canceled")) => anyhow::bail!(
+                    "Meilisearch task {} ({}) {}: {}",
+                    uid,
+                    task["type"],
+                    status,
+                    task["error"]["message"]
+                ),
+                _ if start.elapsed() > self.options.task_timeout => anyhow::bail!(
+                    "Meilisearch task {} did not finish within {:?}",
+                    uid,
+                    self.options.task_timeout
+                ),
+                _ => tokio::time::sleep(POLL_INTERVAL).await,
+            }
+        }
+    }
+
+    pub async fn index_exists(&self, index: &str) -> Result<bool> {
+        let response = self
+            .request(Method::GET, &format!("indexes/{}", index))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
+    /// Creates an empty index with the flake-info [settings]
+    pub async fn create_index(&self, index: &str) -> Result<()> {
+        self.run_task(
+            self.request(Method::POST, "indexes")
+                .json(&json!({"uid": index, "primaryKey": PRIMARY_KEY})),
+        )
+        .await
+        .with_context(|| format!("Failed to create index {}", index))?;
+
+        self.run_task(
+            self.request(Method::PATCH, &format!("indexes/{}/settings", index))
+                .json(&settings()),
+        )
+        .await
+        .with_context(|| format!("Failed to configure index {}", index))
+    }
+
+    pub async fn delete_index(&self, index: &str) -> Result<()> {
+        self.run_task(self.request(Method::DELETE, &format!("indexes/{}", index)))
+            .await
+            .with_context(|| format!("Failed to delete index {}", index))
+    }
+
+    /// Adds `exports` to `index`, replacing documents with the same id
+    pub async fn add_documents(&self, index: &str, exports: &[Export]) -> Result<()> {
+        let documents = exports.iter().map(document).collect::<Result<Vec<_>>>()?;
+
+        for chunk in chunk_by_size(&documents, self.options.max_chunk_bytes) {
+            self.run_task(
+                self.request(Method::POST, &format!("indexes/{}/documents", index))
+                    .query(&[("primaryKey", PRIMARY_KEY)])
+                    .header("Content-Type", "application/x-ndjson")
+                    .body(chunk.join("\n")),
+            )
+            .await
+            .with_context(|| format!("Failed to add documents to {}", index))?;
+        }
+        Ok(())
+    }
+
+    /// Atomically exchanges the documents and settings of both indices
+    pub async fn swap_indices(&self, a: &str, b: &str) -> Result<()> {
+        self.run_task(
+            self.request(Method::POST, "swap-indexes")
+                .json(&json!([{ "indexes": [a, b] }])),
+        )
+        .await
+        .with_context(|| format!("Failed to swap {} and {}", a, b))
+    }
+}
+
+/// A [Sink] replacing the contents of a Meilisearch index
+pub struct MeilisearchSink<'a> {
+    client: &'a Meilisearch,
+    index: String,
+    /// Index the exports are written to until the sink is finalized
+    partial: String,
+}
+
+impl<'a> MeilisearchSink<'a> {
+    /// `index` may only consist of alphanumeric characters, `-` and `_`
+    pub fn new(client: &'a Meilisearch, index: &str) -> Self {
+        MeilisearchSink {
+            client,
+            index: index.to_owned(),
+            partial: format!("{}-partial", index),
+        }
+    }
+}
+
+impl Sink for MeilisearchSink<'_> {
+    async fn prepare(&mut self) -> Result<()> {
+        if self.client.index_exists(&self.partial).await? {
+            warn!("Deleting leftover index {}", self.partial);
+            self.client.delete_index(&self.partial).await?;
+        }
+        self.client.create_index(&self.partial).await
+    }
+
+    async fn write_batch(&mut self, exports: &[Export]) -> Result<()> {
+        self.client.add_documents(&self.partial, exports).await
+    }
+
+    async fn finalize(&mut self) -> Result<()> {
+        // both indices of a swap have to exist
+        if !self.client.index_exists(&self.index).await? {
+            self.client.create_index(&self.index).await?;
+        }
+        self.client.swap_indices(&self.index, &self.partial).await?;
+        info!("Replaced the contents of {}", self.index);
+
+        // now holds the previous contents of the index
+        self.client.delete_index(&self.partial).await
+    }
+
+    /// Deletes the partial index if the exports failed. If pushing failed it
+    /// is kept for inspection, the next import replaces it.
+    async fn rollback(&mut self, failure: Failure) -> Result<()> {
+        if failure == Failure::Sink {
+            warn!("Keeping partial index {}", self.partial);
+            return Ok(());
+        }
+        if self.client.index_exists(&self.partial).await? {
+            self.client.delete_index(&self.partial).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        path::PathBuf,
+        process::{Child, Command, Stdio},
+    };
+
+    use super::*;
+    use crate::{elastic::tests::options, sink::write_exports};
+
+    #[test]
+    fn test_attributes() {
+        let searchable = searchable_attributes();
+        let position = |field: &str| searchable.iter().position(|name| name == field);
+        for field in &[
+            "package_attr_name",
+            "package_programs",
+            "package_description",
+            "option_name",
+            "option_description",
+        ] {
+            assert!(position(field).is_some(), "{} is not searchable", field);
+        }
+        assert!(position("package_attr_name") < position("package_programs"));
+        assert!(position("package_programs") < position("package_description"));
+        assert_eq!(position("package_platforms"), None);
+
+        let filterable = filterable_attributes();
+        for field in &[
+            "type",
+            "package_license_set",
+            "package_platforms",
+            "package_maintainers_set",
+        ] {
+            assert!(
+                filterable.iter().any(|name| name == field),
+                "{} is not filterable",
+                field
+            );
+        }
+        assert!(!filterable.iter().any(|name| name == "package_description"));
+    }
+
+    #[test]
+    fn test_document() {
+        let export = &options("services.foo")[0];
+        let document: Value = serde_json::from_str(&document(export).unwrap()).unwrap();
+        assert_eq!(document[PRIMARY_KEY], export.id());
+        assert_eq!(document["option_name"], "services.foo.0");
+    }
+
+    /// A Meilisearch server on a free local port, killed when dropped
+    struct Server {
+        process: Child,
+        url: String,
+        db: PathBuf,
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.process.kill();
+            let _ = self.process.wait();
+            let _ = std::fs::remove_dir_all(&self.db);
+        }
+    }
+
+    /// Starts `$MEILISEARCH_BIN` or `meilisearch` from the `PATH`
+    async fn start_server() -> Server {
+        let binary = std::env::var("MEILISEARCH_BIN").unwrap_or_else(|_| "meilisearch".to_owned());
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let db = std::env::temp_dir().join(format!("flake-info-meili-{}", std::process::id()));
+
+        let process = Command::new(&binary)
+            .arg("--db-path")
+            .arg(&db)
+            .args(&["--http-addr", &format!("127.0.0.1:{}", port)])
+            .args(&["--env", "development", "--no-analytics"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|e| panic!("Couldn't start {}: {}", binary, e));
+        let server = Server {
+            process,
+            url: format!("http://127.0.0.1:{}", port),
+            db,
+        };
+
+        let start = Instant::now();
+        while reqwest::get(format!("{}/health", server.url))
+            .await
+            .is_err()
+        {
+            assert!(
+                start.elapsed() < Duration::from_secs(30),
+                "Meilisearch did not start"
+            );
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        server
+    }
+
+    #[tokio::test]
+    #[ignore = "needs meilisearch on the PATH or in MEILISEARCH_BIN"]
+    async fn test_sink() {
+        let server = start_server().await;
+        let client = Meilisearch::new(&server.url, Default::default()).unwrap();
+        let search = |query: &str| {
+            let request = client
+                .request(Method::POST, "indexes/options/search")
+                .json(&json!({"q": query, "filter": "type = option"}));
+            async move { Meilisearch::send(request).await.unwrap()["hits"].clone() }
+        };
+
+        let mut sink = MeilisearchSink::new(&client, "options");
+        let exports = options("services.foo");
+        write_exports(
+            &mut sink,
+            exports.into_iter().map(Ok::<_, anyhow::Error>),
+            2,
+        )
+        .await
+        .unwrap();
+        assert_eq!(search("foo").await.as_array().unwrap().len(), 3);
+
+        // a failed write leaves the index untouched
+        let mut sink = MeilisearchSink::new(&client, "options");
+        let exports = vec![
+            Ok(options("services.bar").remove(0)),
+            Err(anyhow::anyhow!("evaluation failed")),
+        ];
+        write_exports(&mut sink, exports, 1).await.unwrap_err();
+        assert!(!client.index_exists("options-partial").await.unwrap());
+        assert_eq!(search("bar").await.as_array().unwrap().len(), 0);
+
+        // a complete write replaces it
+        let mut sink = MeilisearchSink::new(&client, "options");
+        let exports = options("services.bar");
+        write_exports(
+            &mut sink,
+            exports.into_iter().map(Ok::<_, anyhow::Error>),
+            2,
+        )
+        .await
+        .unwrap();
+        assert_eq!(search("bar").await.as_array().unwrap().len(), 3);
+        assert_eq!(search("foo").await.as_array().unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,383 @@
+/// Pushes exports to OpenSearch.
+///
+/// OpenSearch rejects the compatibility headers the `elasticsearch` client
+/// sends with every request, so this backend talks to the REST API directly.
+/// Indices, aliases and bulk requests behave exactly like the ones written by
+/// [Elasticsearch](crate::elastic::Elasticsearch), see [Backend].
+use std::collections::HashMap;
+
+use log::info;
+use reqwest::{Client, Method, RequestBuilder, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+
+use crate::data::{Export, Provenance};
+use crate::elastic::{
+    Auth, Backend, BulkAttempt, Config, ConnectionOptions, ElasticsearchError, ExistingIndex,
+    IndexInfo, SCROLL_TIMEOUT, alias_actions, compare_fingerprint, existing_index, index_body,
+    index_infos, mapping_meta, provenance_update, read_certificate, read_provenance, type_counts,
+    type_counts_search,
+};
+use crate::query::{Query, read_hits};
+
+pub struct OpenSearch {
+    client: Client,
+    /// Base url without a trailing slash
+    url: String,
+    auth: Option<Auth>,
+}
+
+impl OpenSearch {
+    pub fn new(url: &str) -> Result<Self, ElasticsearchError> {
+        Self::connect(url, &ConnectionOptions::default())
+    }
+
+    /// Connects to the cluster at `url`, authenticating as configured in `options`.
+    ///
+    /// OpenSearch does not support API keys.
+    pub fn connect(url: &str, options: &ConnectionOptions) -> Result<Self, ElasticsearchError> {
+        Url::parse(url)
+            .map_err(|e| ElasticsearchError::InvalidUrl(url.to_owned(), e.to_string()))?;
+
+        let mut builder = Client::builder();
+        match &options.auth {
+            Some(Auth::ApiKey { .. }) => {
+                return Err(ElasticsearchError::UnsupportedAuth("API keys"));
+            }
+            Some(Auth::ClientCertificate(path)) => {
+                // only rustls reads a certificate and its key from a single PEM file
+                let identity = reqwest::Identity::from_pem(&read_certificate(path)?)?;
+                builder = builder.use_rustls_tls().identity(identity);
+            }
+            _ => {}
+        }
+
+        if let Some(path) = &options.ca_certificate {
+            builder = builder
+                .add_root_certificate(reqwest::Certificate::from_pem(&read_certificate(path)?)?);
+        }
+
+        Ok(OpenSearch {
+            client: builder.build()?,
+            url: url.trim_end_matches('/').to_owned(),
+            auth: options.auth.clone(),
+        })
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}/{}", self.url, path));
+        match &self.auth {
+            Some(Auth::Basic { user, password }) => request.basic_auth(user, Some(password)),
+            Some(Auth::Bearer(token)) => request.bearer_auth(token),
+            _ => request,
+        }
+    }
+
+    /// Sends `request` and reads its JSON response, failing on error status codes
+    async fn send<T: DeserializeOwned>(request: RequestBuilder) -> Result<T, ElasticsearchError> {
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            return Err(ElasticsearchError::ResponseError(
+                status.as_u16(),
+                response.text().await?,
+            ));
+        }
+        Ok(response.json().await?)
+    }
+
+    /// The `_meta` of the mapping of `index`, `Value::Null` if it has none
+    async fn fetch_meta(&self, index: &str) -> Result<Value, ElasticsearchError> {
+        let mapping: Value =
+            Self::send(self.request(Method::GET, &format!("{}/_mapping", index))).await?;
+        Ok(mapping_meta(&mapping))
+    }
+}
+
+impl Backend for OpenSearch {
+    async fn ensure_index(&self, config: &Config<'_>) -> Result<(), ElasticsearchError> {
+        if self.index_exists(config.index).await? {
+            match existing_index(config)? {
+                ExistingIndex::Keep { update_provenance } => {
+                    compare_fingerprint(config.index, &self.fetch_meta(config.index).await?)?;
+                    if update_provenance && config.provenance.is_some() {
+                        Self::send::<Value>(
+                            self.request(Method::PUT, &format!("{}/_mapping", config.index))
+                                .json(&provenance_update(config.provenance)?),
+                        )
+                        .await?;
+                    }
+                    return Ok(());
+                }
+                ExistingIndex::Recreate => self.delete_index(config.index).await?,
+            }
+        }
+
+        Self::send::<Value>(
+            self.request(Method::PUT, config.index)
+                .json(&index_body(config.provenance)?),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn index_exists(&self, index: &str) -> Result<bool, ElasticsearchError> {
+        let response = self.request(Method::HEAD, index).send().await?;
+        Ok(response.status() == StatusCode::OK)
+    }
+
+    async fn delete_index(&self, index: &str) -> Result<(), ElasticsearchError> {
+        Self::send::<Value>(self.request(Method::DELETE, index)).await?;
+        Ok(())
+    }
+
+    async fn write_alias(
+        &self,
+        _config: &Config<'_>,
+        index: &str,
+        alias: &str,
+    ) -> Result<(), ElasticsearchError> {
+        let previous = self.alias_indices(alias).await?;
+
+        info!("Switching alias {} from {:?} to {}", alias, previous, index);
+        Self::send::<Value>(
+            self.request(Method::POST, "_aliases")
+                .json(&alias_actions(&previous, index, alias)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn list_indices(&self, pattern: &str) -> Result<Vec<IndexInfo>, ElasticsearchError> {
+        let indices: HashMap<String, Value> =
+            Self::send(self.request(Method::GET, pattern)).await?;
+        let documents: Vec<Value> = Self::send(
+            self.request(Method::GET, &format!("_cat/indices/{}", pattern))
+                .query(&[("format", "json"), ("h", "index,docs.count")]),
+        )
+        .await?;
+
+        Ok(index_infos(indices, &documents))
+    }
+
+    async fn count_types(&self, index: &str) -> Result<HashMap<String, u64>, ElasticsearchError> {
+        let response: Value = Self::send(
+            self.request(Method::POST, &format!("{}/_search", index))
+                .json(&type_counts_search()),
+        )
+        .await?;
+        Ok(type_counts(&response))
+    }
+
+    async fn provenance(&self, index: &str) -> Result<Option<Provenance>, ElasticsearchError> {
+        Ok(read_provenance(self.fetch_meta(index).await?)?)
+    }
+
+    async fn search(
+        &self,
+        index: &str,
+        query: &Query,
+        size: usize,
+    ) -> Result<Vec<Export>, ElasticsearchError> {
+        let response: Value = Self::send(
+            self.request(Method::POST, &format!("{}/_search", index))
+                .json(&query.body(size)),
+        )
+        .await?;
+        Ok(read_hits(&response)?)
+    }
+
+    async fn send_bulk(
+        &self,
+        index: &str,
+        operations: &[&String],
+    ) -> Result<BulkAttempt, ElasticsearchError> {
+        let mut body = String::new();
+        for operation in operations {
+            body.push_str(operation);
+            body.push('\n');
+        }
+
+        let response = match self
+            .request(Method::POST, &format!("{}/_bulk", index))
+            .header("Content-Type", "application/x-ndjson")
+            .body(body)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => return Ok(BulkAttempt::Transient(e.into())),
+        };
+
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            let error = ElasticsearchError::ResponseError(status.as_u16(), response.text().await?);
+            return if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+                Ok(BulkAttempt::Transient(error))
+            } else {
+                Err(error)
+            };
+        }
+        Ok(BulkAttempt::Items(response.json().await?))
+    }
+
+    async fn scroll_search(&self, index: &str, body: Value) -> Result<Value, ElasticsearchError> {
+        Self::send(
+            self.request(Method::POST, &format!("{}/_search", index))
+                .query(&[("scroll", SCROLL_TIMEOUT)])
+                .json(&body),
+        )
+        .await
+    }
+
+    async fn scroll(&self, scroll_id: Value) -> Result<Value, ElasticsearchError> {
+        Self::send(
+            self.request(Method::POST, "_search/scroll")
+                .json(&json!({"scroll": SCROLL_TIMEOUT, "scroll_id": scroll_id})),
+        )
+        .await
+    }
+
+    async fn clear_scroll(&self, scroll_id: &str) -> Result<(), ElasticsearchError> {
+        Self::send::<Value>(
+            self.request(Method::DELETE, "_search/scroll")
+                .json(&json!({ "scroll_id": [scroll_id] })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn refresh(&self, index: &str) -> Result<(), ElasticsearchError> {
+        Self::send::<Value>(self.request(Method::POST, &format!("{}/_refresh", index))).await?;
+        Ok(())
+    }
+
+    async fn alias_indices(&self, alias: &str) -> Result<Vec<String>, ElasticsearchError> {
+        let response = self
+            .request(Method::GET, &format!("_alias/{}", alias))
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+
+        Ok(response
+            .error_for_status()?
+            .json::<HashMap<String, Value>>()
+            .await?
+            .into_keys()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elastic::{
+        ExistsStrategy, PushOptions,
+        tests::{item, mock_server, options},
+    };
+
+    #[tokio::test]
+    async fn test_push() {
+        let exports = options("services.foo");
+        let (url, requests) = mock_server(|n, _| match n {
+            // index does not exist yet
+            1 => (404, json!({})),
+            3 => (
+                200,
+                json!({"errors": false, "items": [item("a", 201, None), item("b", 201, None), item("c", 201, None)]}),
+            ),
+            // alias does not exist yet
+            4 => (404, json!({})),
+            _ => (200, json!({"acknowledged": true})),
+        })
+        .await;
+
+        let opensearch = OpenSearch::new(&url).unwrap();
+        let config = Config {
+            index: "nixos-48-unstable-abc",
+            exists_strategy: ExistsStrategy::Abort,
+            push: PushOptions::default(),
+            provenance: None,
+        };
+        opensearch.ensure_index(&config).await.unwrap();
+        opensearch.push_exports(&config, &exports).await.unwrap();
+        opensearch
+            .write_alias(&config, config.index, "latest-48-nixos-unstable")
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 5);
+        let body: Value = serde_json::from_str(&requests[1]).unwrap();
+        assert_eq!(body, index_body(None).unwrap());
+        assert_eq!(requests[2].lines().count(), 6);
+        assert_eq!(
+            serde_json::from_str::<Value>(&requests[4]).unwrap(),
+            json!({"actions": [{"add": {"index": "nixos-48-unstable-abc", "alias": "latest-48-nixos-unstable"}}]})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_push_retries_rejected_documents() {
+        let (url, requests) = mock_server(|n, _| match n {
+            1 => (
+                200,
+                json!({"errors": true, "items": [item("a", 429, Some("es_rejected_execution_exception"))]}),
+            ),
+            2 => (503, json!({"error": "unavailable", "status": 503})),
+            _ => (
+                200,
+                json!({"errors": false, "items": [item("a", 201, None)]}),
+            ),
+        })
+        .await;
+
+        let opensearch = OpenSearch::new(&url).unwrap();
+        let config = Config {
+            index: "flakes_index",
+            exists_strategy: ExistsStrategy::Ignore,
+            push: PushOptions {
+                initial_backoff: std::time::Duration::from_millis(1),
+                ..PushOptions::default()
+            },
+            provenance: None,
+        };
+        opensearch
+            .push_exports(&config, &options("services.foo")[..1])
+            .await
+            .unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_client_certificate() {
+        let options = ConnectionOptions {
+            auth: Some(Auth::ClientCertificate("/nonexistent/client.pem".into())),
+            ca_certificate: None,
+        };
+        assert!(matches!(
+            OpenSearch::connect("https://localhost:9200", &options),
+            Err(ElasticsearchError::CertificateReadError(..))
+        ));
+    }
+
+    #[test]
+    fn test_unsupported_auth() {
+        let options = ConnectionOptions {
+            auth: Some(Auth::ApiKey {
+                id: "id".to_owned(),
+                key: "key".to_owned(),
+            }),
+            ca_certificate: None,
+        };
+        assert!(matches!(
+            OpenSearch::connect("http://localhost:9200", &options),
+            Err(ElasticsearchError::UnsupportedAuth(_))
+        ));
+    }
+}
@@ -0,0 +1,216 @@
+/// Typed queries against an index written by flake-info.
+///
+/// Every [Query] is translated into an Elasticsearch query on the fields of
+/// the [MAPPING](crate::elastic), looking documents up the same way the search
+/// frontend does. [Backend::search](crate::elastic::Backend::search) runs a
+/// query and returns the matching [Export]s.
+use serde_json::{Value, json};
+
+use crate::data::Export;
+
+/// Document types holding an `option_name`
+const OPTION_TYPES: &[&str] = &["option", "service", "home-manager-option"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    /// The package with the attribute name, e.g. `python3Packages.requests`
+    PackageAttr(String),
+    /// Packages providing the program in their `bin` directory
+    Program(String),
+    /// Options, services and home-manager options below the attribute path,
+    /// e.g. `services.nginx` matches `services.nginx.enable` but not
+    /// `services.nginxQuic.enable`. Matching ignores case.
+    OptionPrefix(String),
+    /// Packages maintained by the maintainer with the given name or GitHub handle
+    Maintainer(String),
+    /// Packages under the license with the given full name, e.g. `MIT License`
+    License(String),
+    /// Modular services running the package with the given attribute name
+    ModularService(String),
+}
+
+impl Query {
+    /// Field hits are sorted by after their score
+    fn sort_field(&self) -> &'static str {
+        match self {
+            Query::OptionPrefix(_) | Query::ModularService(_) => "option_name",
+            _ => "package_attr_name",
+        }
+    }
+
+    /// The `query` of a search request
+    pub fn to_query(&self) -> Value {
+        let (filter, must) = match self {
+            Query::PackageAttr(name) => (
+                json!({"term": {"type": "package"}}),
+                json!({"term": {"package_attr_name": name}}),
+            ),
+            Query::Program(program) => (
+                json!({"term": {"type": "package"}}),
+                json!({"term": {"package_programs": program}}),
+            ),
+            Query::OptionPrefix(prefix) => (
+                json!({"terms": {"type": OPTION_TYPES}}),
+                // the attr_path analyzer indexes every prefix of the name,
+                // split at dots and lowercased
+                json!({"term": {"option_name.attr_path": prefix.to_lowercase()}}),
+            ),
+            Query::Maintainer(maintainer) => (
+                json!({"term": {"type": "package"}}),
+                json!({"bool": {
+                    "should": [
+                        {"term": {"package_maintainers_set": maintainer}},
+                        {"nested": {
+                            "path": "package_maintainers",
+                            "query": {"match": {"package_maintainers.github": maintainer}},
+                        }},
+                    ],
+                    "minimum_should_match": 1,
+                }}),
+            ),
+            Query::License(license) => (
+                json!({"term": {"type": "package"}}),
+                json!({"term": {"package_license_set": license}}),
+            ),
+            Query::ModularService(package) => (
+                json!({"term": {"type": "service"}}),
+                json!({"bool": {
+                    "should": [
+                        {"term": {"service_package": package}},
+                        {"term": {"service_packages": package}},
+                    ],
+                    "minimum_should_match": 1,
+                }}),
+            ),
+        };
+        json!({"bool": {"filter": [filter], "must": [must]}})
+    }
+
+    /// Body of a search request returning at most `size` hits
+    pub fn body(&self, size: usize) -> Value {
+        json!({
+            "size": size,
+            "query": self.to_query(),
+            "sort": ["_score", {self.sort_field(): "asc"}],
+        })
+    }
+}
+
+/// The documents of the hits of a search response
+pub(crate) fn read_hits(response: &Value) -> Result<Vec<Export>, serde_json::Error> {
+    response["hits"]["hits"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|hit| serde_json::from_value(hit["_source"].clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elastic::{
+        Elasticsearch, MAPPING,
+        tests::{mock_server, options},
+    };
+
+    fn queries() -> Vec<Query> {
+        vec![
+            Query::PackageAttr("hello".to_owned()),
+            Query::Program("hello".to_owned()),
+            Query::OptionPrefix("services.nginx".to_owned()),
+            Query::Maintainer("ysndr".to_owned()),
+            Query::License("MIT License".to_owned()),
+            Query::ModularService("nginx".to_owned()),
+        ]
+    }
+
+    /// Fields `value` queries or sorts by
+    fn referenced_fields(value: &Value, fields: &mut Vec<String>) {
+        match value {
+            Value::Object(object) => {
+                for (key, value) in object {
+                    match (key.as_str(), value) {
+                        ("term" | "terms" | "match", Value::Object(query)) => {
+                            fields.extend(query.keys().cloned())
+                        }
+                        ("path", Value::String(path)) => fields.push(path.to_owned()),
+                        ("sort", Value::Array(sort)) => fields.extend(
+                            sort.iter()
+                                .filter_map(Value::as_object)
+                                .flat_map(|field| field.keys().cloned()),
+                        ),
+                        _ => referenced_fields(value, fields),
+                    }
+                }
+            }
+            Value::Array(values) => {
+                for value in values {
+                    referenced_fields(value, fields);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the mapping defines `field`, following properties and sub-fields
+    fn mapped(field: &str) -> bool {
+        let mut mapping = &MAPPING["mappings"];
+        for part in field.split('.') {
+            mapping = match (&mapping["properties"][part], &mapping["fields"][part]) {
+                (Value::Null, Value::Null) => return false,
+                (Value::Null, sub_field) => sub_field,
+                (property, _) => property,
+            };
+        }
+        true
+    }
+
+    #[test]
+    fn test_mapped_fields() {
+        for query in queries() {
+            let mut fields = Vec::new();
+            referenced_fields(&query.body(10), &mut fields);
+            assert!(!fields.is_empty());
+            for field in fields {
+                assert!(mapped(&field), "{:?} uses unmapped field {}", query, field);
+            }
+        }
+        assert!(!mapped("option_name.prefix"));
+    }
+
+    #[test]
+    fn test_option_prefix() {
+        let query = Query::OptionPrefix("services.NGINX".to_owned()).to_query();
+        assert_eq!(
+            query["bool"]["must"][0],
+            json!({"term": {"option_name.attr_path": "services.nginx"}})
+        );
+        assert_eq!(
+            query["bool"]["filter"][0]["terms"]["type"],
+            json!(OPTION_TYPES)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let exports = options("services.nginx");
+        let hits: Vec<Value> = exports
+            .iter()
+            .map(|export| json!({"_id": export.id(), "_source": export}))
+            .collect();
+        let (url, requests) =
+            mock_server(move |_, _| (200, json!({"hits": {"hits": hits.clone()}}))).await;
+
+        let es = Elasticsearch::new(&url).unwrap();
+        let query = Query::OptionPrefix("services.nginx".to_owned());
+        let found = es
+            .search("latest-48-nixos-unstable", &query, 10)
+            .await
+            .unwrap();
+        assert_eq!(found, exports);
+
+        let request: Value = serde_json::from_str(&requests.lock().unwrap()[0]).unwrap();
+        assert_eq!(request, query.body(10));
+    }
+}
@@ -0,0 +1,364 @@
+/// Destinations exports are written to.
+///
+/// A [Sink] is prepared once, receives exports in batches and is finalized
+/// when all exports were written. If anything fails in between it is rolled
+/// back instead, knowing whether the exports or the sink itself failed.
+/// [write_exports] drives a sink through these steps, so the results of
+/// [crate::process_flake] or [crate::nixpkgs_exports] can be fed into any
+/// storage implementing this trait.
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::warn;
+
+use crate::data::{Export, Ident, NdjsonWriter};
+
+#[allow(async_fn_in_trait)]
+pub trait Sink {
+    /// Called once before the first batch, e.g. to create an index
+    async fn prepare(&mut self) -> Result<()>;
+
+    async fn write_batch(&mut self, exports: &[Export]) -> Result<()>;
+
+    /// Called after the last batch, e.g. to switch an alias to the new index
+    async fn finalize(&mut self) -> Result<()>;
+
+    /// Called instead of [Sink::finalize] if producing the exports, preparing,
+    /// writing or finalizing failed, see [Failure]
+    async fn rollback(&mut self, failure: Failure) -> Result<()>;
+}
+
+/// Why a [Sink] is rolled back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Producing the exports failed, e.g. evaluation. What was written is
+    /// incomplete and should be undone as far as possible.
+    Exports,
+    /// The sink failed to prepare, write or finalize. What was written may be
+    /// kept to inspect the failure or to retry.
+    Sink,
+}
+
+/// Writes `exports` to `sink` in batches of at most `batch_size` exports.
+///
+/// If any step fails, or `exports` yields an error, the sink is rolled back
+/// with the matching [Failure] and the first error is returned.
+pub async fn write_exports<S, E>(
+    sink: &mut S,
+    exports: impl IntoIterator<Item = Result<Export, E>>,
+    batch_size: usize,
+) -> Result<()>
+where
+    S: Sink,
+    E: Into<anyhow::Error>,
+{
+    let result = write_batches(sink, exports, batch_size.max(1)).await;
+
+    if let Err((failure, error)) = result {
+        if let Err(rollback_error) = sink.rollback(failure).await {
+            warn!("Rolling back failed: {:?}", rollback_error);
+        }
+        return Err(error);
+    }
+    Ok(())
+}
+
+async fn write_batches<S, E>(
+    sink: &mut S,
+    exports: impl IntoIterator<Item = Result<Export, E>>,
+    batch_size: usize,
+) -> Result<(), (Failure, anyhow::Error)>
+where
+    S: Sink,
+    E: Into<anyhow::Error>,
+{
+    let sink_failure = |error| (Failure::Sink, error);
+    sink.prepare().await.map_err(sink_failure)?;
+
+    let mut batch = Vec::with_capacity(batch_size);
+    for export in exports {
+        batch.push(export.map_err(|error| (Failure::Exports, error.into()))?);
+        if batch.len() == batch_size {
+            sink.write_batch(&batch).await.map_err(sink_failure)?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        sink.write_batch(&batch).await.map_err(sink_failure)?;
+    }
+
+    sink.finalize().await.map_err(sink_failure)
+}
+
+/// Writes a JSON dump (see [Export::to_writer]) when finalized.
+///
+/// Exports are kept in memory until then, so nothing is written if the sink is rolled back.
+pub struct JsonSink<W: Write> {
+    writer: W,
+    ident: Option<Ident>,
+    /// Whether to write a bare array instead, see [Export::to_array_writer]
+    array: bool,
+    exports: Vec<Export>,
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn new(writer: W, ident: Option<Ident>) -> Self {
+        JsonSink {
+            writer,
+            ident,
+            array: false,
+            exports: Vec::new(),
+        }
+    }
+
+    /// Writes the exports as a bare JSON array, without schema version or ident
+    pub fn array(writer: W) -> Self {
+        JsonSink {
+            array: true,
+            ..JsonSink::new(writer, None)
+        }
+    }
+}
+
+impl<W: Write> Sink for JsonSink<W> {
+    async fn prepare(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn write_batch(&mut self, exports: &[Export]) -> Result<()> {
+        self.exports.extend_from_slice(exports);
+        Ok(())
+    }
+
+    async fn finalize(&mut self) -> Result<()> {
+        if self.array {
+            Export::to_array_writer(&mut self.writer, &self.exports)?;
+        } else {
+            Export::to_writer(&mut self.writer, self.ident.as_ref(), &self.exports)?;
+        }
+        writeln!(self.writer)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    async fn rollback(&mut self, _failure: Failure) -> Result<()> {
+        self.exports.clear();
+        Ok(())
+    }
+}
+
+/// Writes exports as newline delimited JSON (see [NdjsonWriter]) as soon as they arrive.
+///
+/// Lines that were already written stay in place if the sink is rolled back.
+pub struct NdjsonSink<W: Write> {
+    state: NdjsonState<W>,
+    ident: Option<Ident>,
+}
+
+enum NdjsonState<W: Write> {
+    Unprepared(W),
+    Writing(NdjsonWriter<W>),
+    Done,
+}
+
+impl<W: Write> NdjsonSink<W> {
+    pub fn new(writer: W, ident: Option<Ident>) -> Self {
+        NdjsonSink {
+            state: NdjsonState::Unprepared(writer),
+            ident,
+        }
+    }
+
+    fn writer(&mut self) -> Result<&mut NdjsonWriter<W>> {
+        match &mut self.state {
+            NdjsonState::Writing(writer) => Ok(writer),
+            _ => anyhow::bail!("NDJSON sink is not prepared"),
+        }
+    }
+}
+
+impl<W: Write> Sink for NdjsonSink<W> {
+    async fn prepare(&mut self) -> Result<()> {
+        self.state = match std::mem::replace(&mut self.state, NdjsonState::Done) {
+            NdjsonState::Unprepared(writer) => {
+                NdjsonState::Writing(NdjsonWriter::new(writer, self.ident.as_ref())?)
+            }
+            _ => anyhow::bail!("NDJSON sink was prepared twice"),
+        };
+        Ok(())
+    }
+
+    async fn write_batch(&mut self, exports: &[Export]) -> Result<()> {
+        let writer = self.writer()?;
+        for export in exports {
+            writer.write(export)?;
+        }
+        Ok(())
+    }
+
+    async fn finalize(&mut self) -> Result<()> {
+        if let NdjsonState::Writing(writer) = std::mem::replace(&mut self.state, NdjsonState::Done)
+        {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+
+    async fn rollback(&mut self, _failure: Failure) -> Result<()> {
+        self.finalize().await
+    }
+}
+
+/// A file written next to its target as `<path>.partial`, for sinks that
+/// should only replace their output once it is complete.
+pub struct PartialFile {
+    path: PathBuf,
+    partial: PathBuf,
+    writer: Option<BufWriter<File>>,
+}
+
+impl PartialFile {
+    pub fn new(path: &Path) -> Self {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        PartialFile {
+            path: path.to_owned(),
+            partial: partial.into(),
+            writer: None,
+        }
+    }
+
+    /// Creates the partial file, truncating any leftover from earlier runs
+    pub fn create(&mut self) -> Result<&mut BufWriter<File>> {
+        let file = File::create(&self.partial)
+            .with_context(|| format!("Couldn't create {}", self.partial.display()))?;
+        Ok(self.writer.insert(BufWriter::new(file)))
+    }
+
+    pub fn writer(&mut self) -> Result<&mut BufWriter<File>> {
+        self.writer
+            .as_mut()
+            .with_context(|| format!("{} was not created", self.partial.display()))
+    }
+
+    /// Flushes the partial file and moves it to the target path
+    pub fn persist(&mut self) -> Result<()> {
+        self.writer()?.flush()?;
+        self.writer = None;
+        fs::rename(&self.partial, &self.path)
+            .with_context(|| format!("Couldn't move output to {}", self.path.display()))
+    }
+
+    /// Removes the partial file, leaving the target untouched
+    pub fn discard(&mut self) -> Result<()> {
+        self.writer = None;
+        match fs::remove_file(&self.partial) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::import::{NixOption, NixpkgsEntry};
+
+    fn option(name: &str) -> Export {
+        let option: NixOption = serde_json::from_value(serde_json::json!({
+            "declarations": [],
+            "name": name,
+            "type": "boolean"
+        }))
+        .unwrap();
+        Export::nixpkgs(NixpkgsEntry::Option(option)).unwrap()
+    }
+
+    /// Records the steps it is driven through
+    #[derive(Default)]
+    struct Recorder {
+        steps: Vec<String>,
+    }
+
+    impl Sink for Recorder {
+        async fn prepare(&mut self) -> Result<()> {
+            self.steps.push("prepare".to_owned());
+            Ok(())
+        }
+
+        async fn write_batch(&mut self, exports: &[Export]) -> Result<()> {
+            self.steps.push(format!("write {}", exports.len()));
+            Ok(())
+        }
+
+        async fn finalize(&mut self) -> Result<()> {
+            self.steps.push("finalize".to_owned());
+            Ok(())
+        }
+
+        async fn rollback(&mut self, failure: Failure) -> Result<()> {
+            self.steps.push(format!("rollback {:?}", failure));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_exports() {
+        let mut sink = Recorder::default();
+        let exports = (0..5).map(|n| Ok::<_, anyhow::Error>(option(&format!("foo.{}", n))));
+        write_exports(&mut sink, exports, 2).await.unwrap();
+        assert_eq!(
+            sink.steps,
+            ["prepare", "write 2", "write 2", "write 1", "finalize"]
+        );
+
+        let mut sink = Recorder::default();
+        let exports = vec![Ok(option("foo")), Err(anyhow::anyhow!("evaluation failed"))];
+        let error = write_exports(&mut sink, exports, 2).await.unwrap_err();
+        assert_eq!(error.to_string(), "evaluation failed");
+        assert_eq!(sink.steps, ["prepare", "rollback Exports"]);
+    }
+
+    #[tokio::test]
+    async fn test_json_sink() {
+        let exports = vec![option("foo"), option("bar")];
+        let mut output = Vec::new();
+        let mut sink = JsonSink::new(&mut output, None);
+        write_exports(
+            &mut sink,
+            exports.iter().cloned().map(Ok::<_, anyhow::Error>),
+            1,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(Export::from_reader(&output[..]).unwrap(), exports);
+    }
+
+    #[test]
+    fn test_partial_file() {
+        let dir = std::env::temp_dir().join(format!("flake-info-sink-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("exports.ndjson");
+
+        let mut file = PartialFile::new(&path);
+        file.create().unwrap().write_all(b"foo\n").unwrap();
+        assert!(!path.exists());
+        file.persist().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "foo\n");
+        assert!(!file.partial.exists());
+
+        let mut file = PartialFile::new(&path);
+        file.create().unwrap().write_all(b"bar\n").unwrap();
+        file.discard().unwrap();
+        assert!(!file.partial.exists());
+        // the previous output is left untouched
+        assert_eq!(fs::read_to_string(&path).unwrap(), "foo\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
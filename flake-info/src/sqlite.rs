@@ -0,0 +1,582 @@
+/// Writes exports into a SQLite database.
+///
+/// The tables follow the documents pushed to Elasticsearch, but list valued
+/// fields are normalized into separate tables. Licenses and maintainers are
+/// stored once and linked to packages through join tables, which correspond
+/// to the `package_license_set` and `package_maintainers_set` fields.
+/// Descriptions of packages and options are indexed with FTS5.
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use ::sqlite::{Connection, State, Statement, Value};
+use anyhow::{Context, Result};
+use log::info;
+use serde_json::Value as Json;
+
+use crate::data::Export;
+use crate::sink::{Failure, Sink};
+
+const SCHEMA: &str = "
+    CREATE TABLE flakes (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        description TEXT,
+        revision TEXT,
+        source TEXT,
+        resolved TEXT
+    );
+
+    CREATE TABLE packages (
+        id INTEGER PRIMARY KEY,
+        flake_id INTEGER REFERENCES flakes(id),
+        attr_name TEXT NOT NULL,
+        attr_set TEXT NOT NULL,
+        pname TEXT NOT NULL,
+        pversion TEXT NOT NULL,
+        system TEXT NOT NULL,
+        default_output TEXT,
+        main_program TEXT,
+        description TEXT,
+        long_description TEXT,
+        position TEXT
+    );
+    CREATE INDEX packages_attr_name ON packages(attr_name);
+
+    CREATE TABLE package_platforms (package_id INTEGER NOT NULL REFERENCES packages(id), platform TEXT NOT NULL);
+    CREATE TABLE package_outputs (package_id INTEGER NOT NULL REFERENCES packages(id), output TEXT NOT NULL);
+    CREATE TABLE package_programs (package_id INTEGER NOT NULL REFERENCES packages(id), program TEXT NOT NULL);
+    CREATE TABLE package_homepages (package_id INTEGER NOT NULL REFERENCES packages(id), url TEXT NOT NULL);
+    CREATE TABLE package_teams (package_id INTEGER NOT NULL REFERENCES packages(id), team TEXT NOT NULL);
+    CREATE TABLE package_modular_services (package_id INTEGER NOT NULL REFERENCES packages(id), service TEXT NOT NULL);
+
+    CREATE TABLE licenses (
+        id INTEGER PRIMARY KEY,
+        full_name TEXT NOT NULL,
+        url TEXT
+    );
+    CREATE TABLE package_licenses (
+        package_id INTEGER NOT NULL REFERENCES packages(id),
+        license_id INTEGER NOT NULL REFERENCES licenses(id)
+    );
+
+    CREATE TABLE maintainers (
+        id INTEGER PRIMARY KEY,
+        name TEXT,
+        github TEXT,
+        email TEXT
+    );
+    CREATE TABLE package_maintainers (
+        package_id INTEGER NOT NULL REFERENCES packages(id),
+        maintainer_id INTEGER NOT NULL REFERENCES maintainers(id)
+    );
+
+    CREATE TABLE apps (
+        id INTEGER PRIMARY KEY,
+        flake_id INTEGER REFERENCES flakes(id),
+        attr_name TEXT NOT NULL,
+        type TEXT,
+        bin TEXT
+    );
+    CREATE TABLE app_platforms (app_id INTEGER NOT NULL REFERENCES apps(id), platform TEXT NOT NULL);
+
+    -- options, modular services and home-manager options
+    CREATE TABLE options (
+        id INTEGER PRIMARY KEY,
+        flake_id INTEGER REFERENCES flakes(id),
+        type TEXT NOT NULL,
+        name TEXT NOT NULL,
+        description TEXT,
+        option_type TEXT,
+        default_value TEXT,
+        example TEXT,
+        source TEXT,
+        flake_module TEXT
+    );
+    CREATE INDEX options_name ON options(name);
+
+    CREATE TABLE services (
+        option_id INTEGER PRIMARY KEY REFERENCES options(id),
+        package TEXT,
+        module TEXT
+    );
+    CREATE TABLE service_packages (option_id INTEGER NOT NULL REFERENCES options(id), package TEXT NOT NULL);
+
+    CREATE VIRTUAL TABLE packages_fts USING fts5(
+        attr_name, pname, description, long_description,
+        content = 'packages', content_rowid = 'id'
+    );
+    CREATE VIRTUAL TABLE options_fts USING fts5(
+        name, description,
+        content = 'options', content_rowid = 'id'
+    );
+";
+
+/// Runs a prepared insert statement with the given values
+fn insert(statement: &mut Statement, values: Vec<Value>) -> Result<()> {
+    statement.reset()?;
+    for (index, value) in values.into_iter().enumerate() {
+        statement.bind((index + 1, value))?;
+    }
+    while statement.next()? != State::Done {}
+    Ok(())
+}
+
+fn text(document: &Json, field: &str) -> Value {
+    match document.get(field) {
+        Some(Json::String(s)) => Value::String(s.to_owned()),
+        Some(Json::Null) | None => Value::Null,
+        Some(other) => Value::String(other.to_string()),
+    }
+}
+
+fn list<'a>(document: &'a Json, field: &str) -> Vec<&'a str> {
+    document
+        .get(field)
+        .and_then(Json::as_array)
+        .map(|list| list.iter().filter_map(Json::as_str).collect())
+        .unwrap_or_default()
+}
+
+/// Inserts `(id, value)` rows for every element of a list field
+fn insert_list(statement: &mut Statement, id: i64, values: Vec<&str>) -> Result<()> {
+    for value in values {
+        insert(
+            statement,
+            vec![Value::Integer(id), Value::String(value.to_owned())],
+        )?;
+    }
+    Ok(())
+}
+
+/// Hands out ids for values that are stored only once
+struct Interner<K> {
+    ids: HashMap<K, i64>,
+}
+
+impl<K: std::hash::Hash + Eq> Interner<K> {
+    fn new() -> Self {
+        Interner {
+            ids: HashMap::new(),
+        }
+    }
+
+    /// Returns the id of `key` and whether it was seen for the first time
+    fn intern(&mut self, key: K) -> (i64, bool) {
+        let next = self.ids.len() as i64 + 1;
+        let mut new = false;
+        let id = *self.ids.entry(key).or_insert_with(|| {
+            new = true;
+            next
+        });
+        (id, new)
+    }
+}
+
+struct Statements<'c> {
+    flake: Statement<'c>,
+    package: Statement<'c>,
+    platform: Statement<'c>,
+    output: Statement<'c>,
+    program: Statement<'c>,
+    homepage: Statement<'c>,
+    team: Statement<'c>,
+    modular_service: Statement<'c>,
+    license: Statement<'c>,
+    package_license: Statement<'c>,
+    maintainer: Statement<'c>,
+    package_maintainer: Statement<'c>,
+    app: Statement<'c>,
+    app_platform: Statement<'c>,
+    option: Statement<'c>,
+    service: Statement<'c>,
+    service_package: Statement<'c>,
+}
+
+impl<'c> Statements<'c> {
+    fn prepare(connection: &'c Connection) -> Result<Self> {
+        Ok(Statements {
+            flake: connection.prepare("INSERT INTO flakes VALUES (?, ?, ?, ?, ?, ?)")?,
+            package: connection
+                .prepare("INSERT INTO packages VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")?,
+            platform: connection.prepare("INSERT INTO package_platforms VALUES (?, ?)")?,
+            output: connection.prepare("INSERT INTO package_outputs VALUES (?, ?)")?,
+            program: connection.prepare("INSERT INTO package_programs VALUES (?, ?)")?,
+            homepage: connection.prepare("INSERT INTO package_homepages VALUES (?, ?)")?,
+            team: connection.prepare("INSERT INTO package_teams VALUES (?, ?)")?,
+            modular_service: connection
+                .prepare("INSERT INTO package_modular_services VALUES (?, ?)")?,
+            license: connection.prepare("INSERT INTO licenses VALUES (?, ?, ?)")?,
+            package_license: connection.prepare("INSERT INTO package_licenses VALUES (?, ?)")?,
+            maintainer: connection.prepare("INSERT INTO maintainers VALUES (?, ?, ?, ?)")?,
+            package_maintainer: connection
+                .prepare("INSERT INTO package_maintainers VALUES (?, ?)")?,
+            app: connection.prepare("INSERT INTO apps VALUES (?, ?, ?, ?, ?)")?,
+            app_platform: connection.prepare("INSERT INTO app_platforms VALUES (?, ?)")?,
+            option: connection
+                .prepare("INSERT INTO options VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")?,
+            service: connection.prepare("INSERT INTO services VALUES (?, ?, ?)")?,
+            service_package: connection.prepare("INSERT INTO service_packages VALUES (?, ?)")?,
+        })
+    }
+}
+
+/// Creates a new database at `path` containing `exports`.
+///
+/// An existing file at `path` is replaced.
+pub fn write_exports(path: &Path, exports: &[Export]) -> Result<()> {
+    if path.exists() {
+        fs::remove_file(path)
+            .with_context(|| format!("Couldn't remove existing database {}", path.display()))?;
+    }
+
+    let connection = ::sqlite::open(path)
+        .with_context(|| format!("Couldn't create database {}", path.display()))?;
+    connection.execute(SCHEMA)?;
+    connection.execute("BEGIN TRANSACTION")?;
+
+    {
+        let mut statements = Statements::prepare(&connection)?;
+        let mut flakes = Interner::new();
+        let mut licenses = Interner::new();
+        let mut maintainers = Interner::new();
+        let (mut packages, mut apps, mut options) = (0, 0, 0);
+
+        for export in exports {
+            let document = serde_json::to_value(export)?;
+
+            let flake_id = match document.get("flake_name") {
+                Some(_) => {
+                    let flake: Vec<Value> = ["flake_name", "flake_description", "revision"]
+                        .iter()
+                        .chain(&["flake_source", "flake_resolved"])
+                        .map(|field| text(&document, field))
+                        .collect();
+                    let (id, new) = flakes.intern(format!("{:?}", flake));
+                    if new {
+                        let mut row = vec![Value::Integer(id)];
+                        row.extend(flake);
+                        insert(&mut statements.flake, row)?;
+                    }
+                    Value::Integer(id)
+                }
+                None => Value::Null,
+            };
+
+            match document["type"].as_str() {
+                Some("package") => {
+                    packages += 1;
+                    let id = packages;
+                    insert(
+                        &mut statements.package,
+                        vec![
+                            Value::Integer(id),
+                            flake_id,
+                            text(&document, "package_attr_name"),
+                            text(&document, "package_attr_set"),
+                            text(&document, "package_pname"),
+                            text(&document, "package_pversion"),
+                            text(&document, "package_system"),
+                            text(&document, "package_default_output"),
+                            text(&document, "package_mainProgram"),
+                            text(&document, "package_description"),
+                            text(&document, "package_longDescription"),
+                            text(&document, "package_position"),
+                        ],
+                    )?;
+
+                    insert_list(
+                        &mut statements.platform,
+                        id,
+                        list(&document, "package_platforms"),
+                    )?;
+                    insert_list(
+                        &mut statements.output,
+                        id,
+                        list(&document, "package_outputs"),
+                    )?;
+                    insert_list(
+                        &mut statements.program,
+                        id,
+                        list(&document, "package_programs"),
+                    )?;
+                    insert_list(
+                        &mut statements.homepage,
+                        id,
+                        list(&document, "package_homepage"),
+                    )?;
+                    insert_list(
+                        &mut statements.team,
+                        id,
+                        list(&document, "package_teams_set"),
+                    )?;
+                    insert_list(
+                        &mut statements.modular_service,
+                        id,
+                        list(&document, "package_modular_services"),
+                    )?;
+
+                    for license in document["package_license"].as_array().into_iter().flatten() {
+                        let key = (text(license, "fullName"), text(license, "url"));
+                        let (license_id, new) = licenses.intern(format!("{:?}", key));
+                        if new {
+                            insert(
+                                &mut statements.license,
+                                vec![Value::Integer(license_id), key.0, key.1],
+                            )?;
+                        }
+                        insert(
+                            &mut statements.package_license,
+                            vec![Value::Integer(id), Value::Integer(license_id)],
+                        )?;
+                    }
+
+                    for maintainer in document["package_maintainers"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                    {
+                        let key = (
+                            text(maintainer, "name"),
+                            text(maintainer, "github"),
+                            text(maintainer, "email"),
+                        );
+                        let (maintainer_id, new) = maintainers.intern(format!("{:?}", key));
+                        if new {
+                            insert(
+                                &mut statements.maintainer,
+                                vec![Value::Integer(maintainer_id), key.0, key.1, key.2],
+                            )?;
+                        }
+                        insert(
+                            &mut statements.package_maintainer,
+                            vec![Value::Integer(id), Value::Integer(maintainer_id)],
+                        )?;
+                    }
+                }
+                Some("app") => {
+                    apps += 1;
+                    let id = apps;
+                    insert(
+                        &mut statements.app,
+                        vec![
+                            Value::Integer(id),
+                            flake_id,
+                            text(&document, "app_attr_name"),
+                            text(&document, "app_type"),
+                            text(&document, "app_bin"),
+                        ],
+                    )?;
+                    insert_list(
+                        &mut statements.app_platform,
+                        id,
+                        list(&document, "app_platforms"),
+                    )?;
+                }
+                Some(kind @ ("option" | "service" | "home-manager-option")) => {
+                    options += 1;
+                    let id = options;
+                    insert(
+                        &mut statements.option,
+                        vec![
+                            Value::Integer(id),
+                            flake_id,
+                            Value::String(kind.to_owned()),
+                            text(&document, "option_name"),
+                            text(&document, "option_description"),
+                            text(&document, "option_type"),
+                            text(&document, "option_default"),
+                            text(&document, "option_example"),
+                            text(&document, "option_source"),
+                            text(&document, "option_flake"),
+                        ],
+                    )?;
+
+                    if kind == "service" {
+                        insert(
+                            &mut statements.service,
+                            vec![
+                                Value::Integer(id),
+                                text(&document, "service_package"),
+                                text(&document, "service_module"),
+                            ],
+                        )?;
+                        insert_list(
+                            &mut statements.service_package,
+                            id,
+                            list(&document, "service_packages"),
+                        )?;
+                    }
+                }
+                other => anyhow::bail!("Unexpected document type {:?}", other),
+            }
+        }
+
+        info!(
+            "Wrote {} packages, {} apps and {} options to {}",
+            packages,
+            apps,
+            options,
+            path.display()
+        );
+    }
+
+    connection.execute(
+        "
+        INSERT INTO packages_fts(packages_fts) VALUES ('rebuild');
+        INSERT INTO options_fts(options_fts) VALUES ('rebuild');
+        COMMIT;
+        ",
+    )?;
+
+    Ok(())
+}
+
+/// A [Sink] creating a new database at a path, see [write_exports].
+///
+/// Exports are kept in memory and written to `<path>.partial` when the sink
+/// is finalized, which then replaces `path`. Nothing changes on rollback.
+pub struct SqliteSink {
+    path: PathBuf,
+    exports: Vec<Export>,
+}
+
+impl SqliteSink {
+    pub fn new(path: &Path) -> Self {
+        SqliteSink {
+            path: path.to_owned(),
+            exports: Vec::new(),
+        }
+    }
+
+    fn partial(&self) -> PathBuf {
+        let mut partial = self.path.as_os_str().to_owned();
+        partial.push(".partial");
+        partial.into()
+    }
+}
+
+impl Sink for SqliteSink {
+    async fn prepare(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn write_batch(&mut self, exports: &[Export]) -> Result<()> {
+        self.exports.extend_from_slice(exports);
+        Ok(())
+    }
+
+    async fn finalize(&mut self) -> Result<()> {
+        let partial = self.partial();
+        write_exports(&partial, &self.exports)?;
+        fs::rename(&partial, &self.path)
+            .with_context(|| format!("Couldn't move database to {}", self.path.display()))
+    }
+
+    async fn rollback(&mut self, _failure: Failure) -> Result<()> {
+        self.exports.clear();
+        match fs::remove_file(self.partial()) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_exports() {
+        let exports: Vec<Export> = serde_json::from_value(serde_json::json!([
+            {
+                "type": "package",
+                "package_attr_name": "hello",
+                "package_attr_set": "No package set",
+                "package_pname": "hello",
+                "package_pversion": "2.12",
+                "package_platforms": ["x86_64-linux", "aarch64-linux"],
+                "package_outputs": ["out"],
+                "package_default_output": "out",
+                "package_programs": ["hello"],
+                "package_mainProgram": "hello",
+                "package_license": [{"fullName": "GNU General Public License v3.0 or later", "url": null}],
+                "package_license_set": ["GNU General Public License v3.0 or later"],
+                "package_license_expression": null,
+                "package_maintainers": [{"name": "Jane", "github": "jane", "email": null}],
+                "package_maintainers_set": ["Jane"],
+                "package_teams": [],
+                "package_teams_set": [],
+                "package_description": "Program that produces a familiar, friendly greeting",
+                "package_longDescription": null,
+                "package_hydra": null,
+                "package_system": "x86_64-linux",
+                "package_homepage": ["https://www.gnu.org/software/hello/"],
+                "package_position": "pkgs/by-name/he/hello/package.nix:34",
+                "package_modular_services": []
+            },
+            {
+                "type": "option",
+                "option_source": "nixos/modules/programs/hello.nix",
+                "option_name": "programs.hello.enable",
+                "option_description": "<rendered-html><p>Whether to greet</p></rendered-html>",
+                "option_type": "boolean",
+                "option_default": "false",
+                "option_example": null,
+                "option_flake": null
+            }
+        ]))
+        .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("flake-info-sqlite-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("exports.sqlite");
+        write_exports(&path, &exports).unwrap();
+
+        let connection = ::sqlite::open(&path).unwrap();
+        let mut statement = connection
+            .prepare(
+                "SELECT packages.attr_name, licenses.full_name FROM packages_fts
+                 JOIN packages ON packages.id = packages_fts.rowid
+                 JOIN package_licenses ON package_licenses.package_id = packages.id
+                 JOIN licenses ON licenses.id = package_licenses.license_id
+                 WHERE packages_fts MATCH 'greeting'",
+            )
+            .unwrap();
+        assert_eq!(statement.next().unwrap(), State::Row);
+        assert_eq!(statement.read::<String, _>(0).unwrap(), "hello");
+        assert_eq!(
+            statement.read::<String, _>(1).unwrap(),
+            "GNU General Public License v3.0 or later"
+        );
+
+        let mut statement = connection
+            .prepare("SELECT type FROM options WHERE name = 'programs.hello.enable'")
+            .unwrap();
+        assert_eq!(statement.next().unwrap(), State::Row);
+        assert_eq!(statement.read::<String, _>(0).unwrap(), "option");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sink_rollback() {
+        let dir =
+            std::env::temp_dir().join(format!("flake-info-sqlite-sink-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("exports.sqlite");
+        fs::write(&path, "previous").unwrap();
+
+        let mut sink = SqliteSink::new(&path);
+        let exports = vec![Err::<Export, _>(anyhow::anyhow!("evaluation failed"))];
+        crate::sink::write_exports(&mut sink, exports, 10)
+            .await
+            .unwrap_err();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "previous");
+        assert!(!sink.partial().exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}